@@ -1,4 +1,8 @@
 fn main() -> anyhow::Result<()> {
+    // The host build has no ESP-IDF to link with.
+    if std::env::var("CARGO_FEATURE_NATIVE").is_err() {
+        return Ok(());
+    }
     embuild::build::CfgArgs::output_propagated("ESP_IDF")?;
     embuild::build::LinkArgs::output_propagated("ESP_IDF")
 }
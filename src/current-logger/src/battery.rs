@@ -0,0 +1,246 @@
+// Fuel gauge of the logger's own LiPo battery, from the voltage on the ADC.
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use anyhow::{anyhow, Result};
+
+// Open circuit voltage to state of charge of a typical single cell LiPo.
+const LIPO_CURVE: &str = "3.30:0,3.50:5,3.60:10,3.70:20,3.75:30,3.79:40,3.83:50,3.87:60,3.92:70,3.97:80,4.06:90,4.15:100";
+
+const FILTER_MS: u64 = 2000;        // Time constant of the voltage average
+const TREND_MS: u64 = 60_000;       // Window of the charging detection
+const CHARGING_SLOPE: f32 = 0.0002; // V/s, rise of the average that means the cell is charging
+const USB_HYSTERESIS: f32 = 0.05;   // V
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerSource {
+    Battery,
+    Charging,
+    Usb,        // No cell or the cell is bypassed, the ADC sees the USB supply
+}
+
+impl PowerSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PowerSource::Battery  => "battery",
+            PowerSource::Charging => "charging",
+            PowerSource::Usb      => "usb",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BatteryState {
+    pub voltage: f32,       // V, averaged and load compensated
+    pub percent: u8,
+    pub source: PowerSource,
+    pub runtime: u16,       // Estimated minutes left on battery, 0 when powered
+}
+
+impl Default for BatteryState {
+    fn default() -> Self {
+        BatteryState::new()
+    }
+}
+
+impl BatteryState {
+    pub const fn new() -> BatteryState {
+        BatteryState { voltage: 0.0, percent: 0, source: PowerSource::Battery, runtime: 0 }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BatteryConfig {
+    pub curve: Vec<(f32, u8)>,  // (V, %) sorted by voltage
+    pub capacity: u32,          // mAh
+    pub load: u32,              // mA drawn by the logger itself
+    pub resistance: u32,        // mOhm, internal resistance of the cell and the wiring
+    pub usb_voltage: f32,       // V, above this the logger runs from USB
+    pub low: u8,                // %, warning on the display
+    pub critical: u8,           // %, logging stops and the logger sleeps until it is charged
+}
+
+impl Default for BatteryConfig {
+    fn default() -> Self {
+        BatteryConfig::new()
+    }
+}
+
+impl BatteryConfig {
+    pub fn new() -> BatteryConfig {
+        BatteryConfig { curve: Self::parse_curve(LIPO_CURVE).unwrap(),
+                        capacity: 500,
+                        load: 80,
+                        resistance: 150,
+                        usb_voltage: 4.55,
+                        low: 15,
+                        critical: 5 }
+    }
+
+    /// Parses a "V:%,V:%,.." list. The points are sorted by voltage.
+    pub fn parse_curve(spec: &str) -> Result<Vec<(f32, u8)>> {
+        let mut curve = Vec::new();
+        for point in spec.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let mut fields = point.split(':');
+            let voltage = fields.next().and_then(|v| v.parse::<f32>().ok());
+            let percent = fields.next().and_then(|p| p.parse::<u8>().ok());
+            match (voltage, percent) {
+                (Some(v), Some(p)) if v.is_finite() && p <= 100 => curve.push((v, p)),
+                _ => return Err(anyhow!("Invalid battery curve point: {}", point)),
+            }
+        }
+        if curve.len() < 2 {
+            return Err(anyhow!("Battery curve needs at least two points: {}", spec));
+        }
+        curve.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        // Two points at one voltage can't be interpolated between.
+        if let Some(pair) = curve.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+            return Err(anyhow!("Battery curve has {}V twice: {}", pair[0].0, spec));
+        }
+        Ok(curve)
+    }
+}
+
+pub struct FuelGauge {
+    config: BatteryConfig,
+    average: Option<f32>,
+    last_time: u64,
+    trend: (u64, f32),      // Start of the charging detection window
+    state: BatteryState,
+}
+
+impl FuelGauge {
+    pub fn new(config: BatteryConfig) -> FuelGauge {
+        FuelGauge { config: config, average: None, last_time: 0, trend: (0, 0.0), state: BatteryState::new() }
+    }
+
+    pub fn state(&self) -> BatteryState {
+        self.state
+    }
+
+    pub fn is_low(&self) -> bool {
+        self.state.source == PowerSource::Battery && self.state.percent <= self.config.low
+    }
+
+    pub fn is_critical(&self) -> bool {
+        self.state.source == PowerSource::Battery && self.state.percent <= self.config.critical
+    }
+
+    /// After a critical shutdown the logger starts again only above the low level, or on USB power.
+    pub fn can_start(&self) -> bool {
+        self.state.source != PowerSource::Battery || self.state.percent > self.config.low
+    }
+
+    /// Takes a raw battery voltage read at `now` ms and returns the updated state.
+    pub fn update(&mut self, voltage: f32, now: u64) -> BatteryState {
+        let average = match self.average {
+            None => {
+                self.trend = (now, voltage);
+                voltage
+            },
+            Some(avg) => {
+                let k = ((now - self.last_time) as f32 / FILTER_MS as f32).min(1.0);
+                avg + (voltage - avg) * k
+            },
+        };
+        self.average = Some(average);
+        self.last_time = now;
+
+        let previous = self.state.source;
+        let usb_voltage = if previous == PowerSource::Usb { self.config.usb_voltage - USB_HYSTERESIS }
+                          else { self.config.usb_voltage };
+        let source = if average > usb_voltage {
+            PowerSource::Usb
+        }
+        else if now - self.trend.0 >= TREND_MS {
+            let slope = (average - self.trend.1) / ((now - self.trend.0) as f32 / 1000.0);
+            self.trend = (now, average);
+            // Near full the charger holds the voltage, so charging lasts until it falls.
+            if slope > CHARGING_SLOPE || (previous == PowerSource::Charging && slope >= 0.0) {
+                PowerSource::Charging
+            }
+            else {
+                PowerSource::Battery
+            }
+        }
+        else if previous == PowerSource::Usb {
+            PowerSource::Battery
+        }
+        else {
+            previous
+        };
+
+        // The logger's own current lowers the terminal voltage below the open circuit voltage.
+        let compensated = if source == PowerSource::Battery {
+            average + self.config.load as f32 * self.config.resistance as f32 / 1_000_000.0
+        }
+        else {
+            average
+        };
+        let percent = self.percent(compensated);
+        let runtime = if source == PowerSource::Battery && self.config.load > 0 {
+            (self.config.capacity * percent as u32 / 100 * 60 / self.config.load).min(u16::MAX as u32) as u16
+        }
+        else {
+            0
+        };
+        self.state = BatteryState { voltage: compensated, percent: percent, source: source, runtime: runtime };
+        self.state
+    }
+
+    // Linear interpolation on the curve.
+    fn percent(&self, voltage: f32) -> u8 {
+        let curve = &self.config.curve;
+        if voltage <= curve[0].0 {
+            return curve[0].1;
+        }
+        for w in curve.windows(2) {
+            let ((v0, p0), (v1, p1)) = (w[0], w[1]);
+            if voltage <= v1 {
+                let p = p0 as f32 + (p1 as f32 - p0 as f32) * (voltage - v0) / (v1 - v0);
+                return p.round() as u8;
+            }
+        }
+        curve[curve.len() - 1].1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curve_is_sorted() {
+        let curve = BatteryConfig::parse_curve("4.2:100, 3.3:0 ,3.8:50").unwrap();
+        assert_eq!(curve, vec![(3.3, 0), (3.8, 50), (4.2, 100)]);
+    }
+
+    #[test]
+    fn invalid_curves_are_refused() {
+        for spec in ["NaN:50,4.2:100", "3.3:0,inf:100", "3.3:0,-inf:100", "3.3:0,3.3:10,4.2:100", "3.3:0,4.2:101",
+                     "3.3:0", "3.3,4.2:100", ""] {
+            assert!(BatteryConfig::parse_curve(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn percent_is_interpolated() {
+        let mut config = BatteryConfig::new();
+        config.curve = BatteryConfig::parse_curve("3.0:0,4.0:100").unwrap();
+        config.load = 0;
+        for (voltage, percent) in [(2.5, 0), (3.0, 0), (3.25, 25), (3.5, 50), (4.0, 100), (4.3, 100)] {
+            let mut gauge = FuelGauge::new(config.clone());
+            assert_eq!(gauge.update(voltage, 0).percent, percent, "{}V", voltage);
+        }
+    }
+
+    #[test]
+    fn usb_power_is_detected() {
+        let mut gauge = FuelGauge::new(BatteryConfig::new());
+        assert_eq!(gauge.update(4.8, 0).source, PowerSource::Usb);
+        assert!(gauge.is_critical() == false);
+        let mut gauge = FuelGauge::new(BatteryConfig::new());
+        let state = gauge.update(3.3, 0);
+        assert_eq!(state.source, PowerSource::Battery);
+        assert!(gauge.is_critical());
+    }
+}
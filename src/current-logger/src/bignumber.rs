@@ -0,0 +1,268 @@
+// Large seven-segment style readout in the upper part of the display.
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use embedded_graphics::{
+    mono_font::{ascii::FONT_10X20, ascii::FONT_6X12, MonoTextStyle},
+    image::Image,
+    pixelcolor::{Rgb565},
+    text::{Text},
+    geometry::Point,
+    prelude::*,
+};
+use tinybmp::Bmp;
+
+const DIGIT_WIDTH: i32 = 20;
+const DOT_WIDTH: i32 = 8;
+const UNIT_X: i32 = 68;        // Digits are drawn in 0..UNIT_X.
+const MAX_DIGITS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quantity {
+    Voltage,    // V
+    Current,    // A
+    Power,      // W
+    Energy,     // Wh
+    Charge,     // Ah
+}
+
+impl Quantity {
+    pub fn from_name(name: &str) -> Option<Quantity> {
+        match name {
+            "voltage" => Some(Quantity::Voltage),
+            "current" => Some(Quantity::Current),
+            "power"   => Some(Quantity::Power),
+            "energy"  => Some(Quantity::Energy),
+            "charge"  => Some(Quantity::Charge),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Quantity::Voltage => "voltage",
+            Quantity::Current => "current",
+            Quantity::Power   => "power",
+            Quantity::Energy  => "energy",
+            Quantity::Charge  => "charge",
+        }
+    }
+
+    // Order of the readouts selected by the button.
+    pub fn next(&self) -> Quantity {
+        match self {
+            Quantity::Voltage => Quantity::Current,
+            Quantity::Current => Quantity::Power,
+            Quantity::Power   => Quantity::Energy,
+            Quantity::Energy  => Quantity::Charge,
+            Quantity::Charge  => Quantity::Voltage,
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Quantity::Voltage => "V",
+            Quantity::Current => "A",
+            Quantity::Power   => "W",
+            Quantity::Energy  => "Wh",
+            Quantity::Charge  => "Ah",
+        }
+    }
+
+    // Unit prefixes from the largest scale to the smallest.
+    fn prefixes(&self) -> &'static [(&'static str, f32)] {
+        match self {
+            Quantity::Voltage => &[("", 1.0), ("m", 0.001)],
+            Quantity::Current => &[("", 1.0), ("m", 0.001), ("u", 0.000_001)],
+            Quantity::Power   => &[("k", 1000.0), ("", 1.0), ("m", 0.001)],
+            Quantity::Energy  => &[("k", 1000.0), ("", 1.0), ("m", 0.001)],
+            Quantity::Charge  => &[("", 1.0), ("m", 0.001), ("u", 0.000_001)],
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Readout {
+    // `digits` holds only digits and at most one '.', e.g. "5.12".
+    Number { negative: bool, digits: String, prefix: &'static str },
+    // The value does not fit even with the largest prefix.
+    Overflow { negative: bool },
+    // NaN or infinite, i.e. not a reading at all.
+    Invalid,
+}
+
+/// Chooses the unit prefix and decimal places so that `value` fills the digits available.
+pub fn format_readout(value: f32, quantity: Quantity) -> Readout {
+    if !value.is_finite() {
+        return Readout::Invalid;
+    }
+    let negative = value < 0.0;
+    let slots = if negative { MAX_DIGITS - 1 } else { MAX_DIGITS };
+    let mag = value.abs();
+    let prefixes = quantity.prefixes();
+    // Largest prefix that keeps at least one integer digit, the base unit for zero.
+    let mut idx = prefixes.iter().position(|p| mag / p.1 >= 1.0)
+        .unwrap_or(if mag == 0.0 { prefixes.iter().position(|p| p.1 == 1.0).unwrap_or(0) }
+                   else { prefixes.len() - 1 });
+    loop {
+        let scaled = mag / prefixes[idx].1;
+        let int_digits = if scaled < 1.0 { 1 } else { scaled.log10().floor() as usize + 1 };
+        if int_digits <= slots {
+            let mut decimals = slots - int_digits;
+            let mut digits = format!("{:.*}", decimals, scaled);
+            // Rounding can carry into one more digit, like 9.996 -> 10.00.
+            if digits.len() - (decimals > 0) as usize > slots && decimals > 0 {
+                decimals -= 1;
+                digits = format!("{:.*}", decimals, scaled);
+            }
+            if digits.len() - (decimals > 0) as usize <= slots {
+                let is_zero = digits.chars().all(|c| c == '0' || c == '.');
+                return Readout::Number { negative: negative && !is_zero, digits: digits, prefix: prefixes[idx].0 };
+            }
+        }
+        if idx == 0 {
+            return Readout::Overflow { negative: negative };
+        }
+        idx -= 1;
+    }
+}
+
+pub struct BigNumber {
+    digits: [Bmp<'static, Rgb565>; 10],
+    dot: Bmp<'static, Rgb565>,
+    minus: Bmp<'static, Rgb565>,
+    vv: Bmp<'static, Rgb565>,
+    aa: Bmp<'static, Rgb565>,
+    ww: Bmp<'static, Rgb565>,
+    wh: Bmp<'static, Rgb565>,
+    ah: Bmp<'static, Rgb565>,
+}
+
+impl Default for BigNumber {
+    fn default() -> Self {
+        BigNumber::new()
+    }
+}
+
+impl BigNumber {
+    pub fn new() -> BigNumber {
+        BigNumber {
+            digits: [Bmp::from_slice(include_bytes!("./img/n0.bmp")).unwrap(),
+                     Bmp::from_slice(include_bytes!("./img/n1.bmp")).unwrap(),
+                     Bmp::from_slice(include_bytes!("./img/n2.bmp")).unwrap(),
+                     Bmp::from_slice(include_bytes!("./img/n3.bmp")).unwrap(),
+                     Bmp::from_slice(include_bytes!("./img/n4.bmp")).unwrap(),
+                     Bmp::from_slice(include_bytes!("./img/n5.bmp")).unwrap(),
+                     Bmp::from_slice(include_bytes!("./img/n6.bmp")).unwrap(),
+                     Bmp::from_slice(include_bytes!("./img/n7.bmp")).unwrap(),
+                     Bmp::from_slice(include_bytes!("./img/n8.bmp")).unwrap(),
+                     Bmp::from_slice(include_bytes!("./img/n9.bmp")).unwrap()],
+            dot: Bmp::from_slice(include_bytes!("./img/dot.bmp")).unwrap(),
+            minus: Bmp::from_slice(include_bytes!("./img/minus.bmp")).unwrap(),
+            vv: Bmp::from_slice(include_bytes!("./img/v.bmp")).unwrap(),
+            aa: Bmp::from_slice(include_bytes!("./img/a.bmp")).unwrap(),
+            ww: Bmp::from_slice(include_bytes!("./img/w.bmp")).unwrap(),
+            wh: Bmp::from_slice(include_bytes!("./img/wh.bmp")).unwrap(),
+            ah: Bmp::from_slice(include_bytes!("./img/ah.bmp")).unwrap(),
+        }
+    }
+
+    pub fn draw<D>(&self, display: &mut D, value: f32, quantity: Quantity) -> Result<(), D::Error>
+        where D: DrawTarget<Color = Rgb565>
+    {
+        let unit_style = MonoTextStyle::new(&FONT_6X12, Rgb565::WHITE);
+        // The unit glyph is opaque, so it goes under the prefix.
+        let unit = match quantity {
+            Quantity::Voltage => &self.vv,
+            Quantity::Current => &self.aa,
+            Quantity::Power   => &self.ww,
+            Quantity::Energy  => &self.wh,
+            Quantity::Charge  => &self.ah,
+        };
+        Image::new(unit, Point::new(UNIT_X, 0)).draw(display)?;
+        let mut pos_x = 0;
+        match format_readout(value, quantity) {
+            Readout::Number { negative, digits, prefix } => {
+                if negative {
+                    Image::new(&self.minus, Point::new(pos_x, 0)).draw(display)?;
+                    pos_x += DIGIT_WIDTH;
+                }
+                for c in digits.chars() {
+                    if c == '.' {
+                        Image::new(&self.dot, Point::new(pos_x, 0)).draw(display)?;
+                        pos_x += DOT_WIDTH;
+                    }
+                    else if let Some(n) = c.to_digit(10) {
+                        Image::new(&self.digits[n as usize], Point::new(pos_x, 0)).draw(display)?;
+                        pos_x += DIGIT_WIDTH;
+                    }
+                }
+                if prefix.is_empty() == false {
+                    Text::new(prefix, Point::new(UNIT_X + 1, 18), unit_style).draw(display)?;
+                }
+            },
+            Readout::Overflow { negative } => {
+                let style = MonoTextStyle::new(&FONT_10X20, Rgb565::RED);
+                Text::new(if negative { "-OL" } else { "OL" }, Point::new(1, 30), style).draw(display)?;
+            },
+            Readout::Invalid => {
+                let style = MonoTextStyle::new(&FONT_10X20, Rgb565::RED);
+                Text::new("Err", Point::new(1, 30), style).draw(display)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn number(negative: bool, digits: &str, prefix: &'static str) -> Readout {
+        Readout::Number { negative: negative, digits: digits.to_string(), prefix: prefix }
+    }
+
+    #[test]
+    fn prefix_and_decimals() {
+        assert_eq!(format_readout(3.3, Quantity::Voltage), number(false, "3.30", ""));
+        assert_eq!(format_readout(0.0123, Quantity::Current), number(false, "12.3", "m"));
+        assert_eq!(format_readout(0.000_005, Quantity::Charge), number(false, "5.00", "u"));
+        assert_eq!(format_readout(1500.0, Quantity::Power), number(false, "1.50", "k"));
+    }
+
+    #[test]
+    fn rounding_carries_into_the_next_prefix() {
+        assert_eq!(format_readout(0.99996, Quantity::Current), number(false, "1.00", ""));
+        assert_eq!(format_readout(9.996, Quantity::Voltage), number(false, "10.0", ""));
+        assert_eq!(format_readout(0.0009999, Quantity::Voltage), number(false, "1.00", "m"));
+    }
+
+    #[test]
+    fn zero() {
+        assert_eq!(format_readout(0.0, Quantity::Current), number(false, "0.00", ""));
+        assert_eq!(format_readout(-0.0, Quantity::Energy), number(false, "0.00", ""));
+        // Too small to show, no minus in front of the zeros.
+        assert_eq!(format_readout(-1e-12, Quantity::Current), number(false, "0.0", "u"));
+    }
+
+    #[test]
+    fn negative() {
+        assert_eq!(format_readout(-0.0123, Quantity::Current), number(true, "12", "m"));
+        assert_eq!(format_readout(-1.5, Quantity::Power), number(true, "1.5", ""));
+        assert_eq!(format_readout(-0.0004, Quantity::Voltage), number(true, "0.4", "m"));
+    }
+
+    #[test]
+    fn overflow() {
+        assert_eq!(format_readout(1000.0, Quantity::Voltage), Readout::Overflow { negative: false });
+        assert_eq!(format_readout(-100.0, Quantity::Current), Readout::Overflow { negative: true });
+        assert_eq!(format_readout(999.0, Quantity::Voltage), number(false, "999", ""));
+        assert_eq!(format_readout(2_000_000.0, Quantity::Power), Readout::Overflow { negative: false });
+    }
+
+    #[test]
+    fn not_a_reading() {
+        assert_eq!(format_readout(f32::NAN, Quantity::Current), Readout::Invalid);
+        assert_eq!(format_readout(f32::INFINITY, Quantity::Power), Readout::Invalid);
+        assert_eq!(format_readout(f32::NEG_INFINITY, Quantity::Voltage), Readout::Invalid);
+    }
+}
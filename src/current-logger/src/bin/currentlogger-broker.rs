@@ -0,0 +1,44 @@
+// A small MQTT broker standing in for Mosquitto, to try the MQTT uplink on a host.
+//
+// currentlogger-broker [--port <port>]
+//
+// Every message published is printed as "topic payload". Lines typed as "topic payload" are published,
+// e.g. "currentlogger/currentch1/cmd interval=100". QoS 1 is acknowledged and delivered as QoS 0,
+// retained messages and the last will are kept, and a client silent for 1.5 keep-alive periods is dropped.
+
+use log::*;
+use std::io::BufRead;
+use std::thread;
+use anyhow::{anyhow, bail, Result};
+
+use currentlogger::mqttbroker::MqttBroker;
+use currentlogger::mqttclient::Publish;
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let mut port = 1883;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => { port = args.next().ok_or(anyhow!("Missing value for --port"))?.parse()?; },
+            _ => bail!("Unknown argument: {}", arg),
+        }
+    }
+    let mut broker = MqttBroker::bind(("0.0.0.0", port))?;
+    broker.set_observer(Box::new(|message: &Publish| {
+        println!("{}{} {}", message.topic, if message.retain { " (retained)" } else { "" },
+                 String::from_utf8_lossy(&message.payload));
+    }));
+    broker.start()?;
+    info!("Listening on port {}", port);
+
+    for line in std::io::stdin().lock().lines().map_while(|line| line.ok()) {
+        if let Some((topic, payload)) = line.trim().split_once(' ') {
+            broker.publish(&Publish::new(topic, payload.as_bytes(), 0, false));
+        }
+    }
+    // Without stdin, e.g. in the background, the broker keeps running.
+    loop {
+        thread::park();
+    }
+}
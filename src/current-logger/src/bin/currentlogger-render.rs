@@ -0,0 +1,22 @@
+// Renders each screen state of the display into BMP files on a Linux host.
+//
+// currentlogger-render [output directory]
+
+use log::*;
+use std::{fs, path::Path};
+use anyhow::Result;
+
+use currentlogger::screens;
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let outdir = std::env::args().nth(1).unwrap_or("screens".to_string());
+    fs::create_dir_all(&outdir)?;
+
+    for (name, dp) in screens::states() {
+        let path = Path::new(&outdir).join(format!("{}.bmp", name));
+        screens::render(&dp).save_bmp(&path)?;
+        info!("{}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,210 @@
+// Runs the logger core on a Linux host with a synthetic INA228.
+//
+// currentlogger-sim --server <address:port> [--waveform <spec>] [--interval <ms>] [--duration <s>] [--battery <V>]
+//                   [--trigger <A>] [--discover <address:port>]
+//                   [--path <path>] [--header "Name: value"]... [--auth bearer:<token>|token:<token>|basic:<user>:<password>]
+//                   [--format json|influx] [--org <org>] [--bucket <bucket>] [--measurement <name>] [--fields <field=name;..>]
+//                   [--mqtt <address:port>] [--topic <prefix>] [--udp <address:port>]
+//                   [--tcp <address:port>] [--live]
+//
+// --discover acts as a collector found by mDNS, --server is then the fallback.
+// --mqtt publishes to a broker instead of the HTTP uploads, e.g. to currentlogger-broker.
+// --udp streams datagrams to a collector instead, --tcp streams frames on one connection.
+// --live prints the messages of the live feed the device sends to a browser.
+//
+// Waveform spec:
+//   constant:<V>:<A>
+//   square:<V>:<low A>:<high A>:<period ms>:<burst ms>
+//   csv:<file>              lines of time(ms),voltage(V),current(A)
+
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use log::*;
+use anyhow::{anyhow, bail, Result};
+
+use currentlogger::hal::{Clock, SystemClock};
+use currentlogger::ina228::{Ina228, INA228_ADDR};
+use currentlogger::sim::{SimIna228, Waveform};
+use currentlogger::transfer::Transfer;
+use currentlogger::mqtt::{MqttUplink, MqttTopics};
+use currentlogger::udpstream::UdpUplink;
+use currentlogger::tcpstream::TcpUplink;
+use currentlogger::hal::Uplink;
+use currentlogger::settings::Settings;
+use currentlogger::displayctl::DisplayPanel;
+use currentlogger::pushswitch::{PushEvent, ButtonRole};
+use currentlogger::hosthal::{ScriptedButtons, FixedBattery, MemoryLeds, HostPower, HostNetwork, MemoryStore,
+                             FixedDiscovery, PrintFeed};
+use currentlogger::livefeed::LiveBroadcast;
+use currentlogger::displayctl::WifiStatus;
+use currentlogger::logger::{Logger, Hardware};
+
+// Selectable intervals in the order of the INT button.
+const INTERVALS: [u32; 6] = [5, 10, 50, 100, 500, 1000];
+
+struct Args {
+    server: String,
+    waveform: String,
+    interval: u32,
+    duration: u64,
+    battery: f32,
+    trigger: f32,
+    discover: Option<String>,
+    path: String,
+    headers: String,
+    auth: String,
+    format: String,
+    org: String,
+    bucket: String,
+    measurement: String,
+    fields: String,
+    mqtt: Option<String>,
+    topic: String,
+    udp: Option<String>,
+    tcp: Option<String>,
+    live: bool,
+}
+
+fn parse_args() -> Result<Args> {
+    let mut args = Args { server: "127.0.0.1:3001".to_string(),
+                          waveform: "constant:5.0:0.1".to_string(),
+                          interval: 100,
+                          duration: 0,
+                          battery: 4.0,
+                          trigger: 0.0,
+                          discover: None,
+                          path: "/".to_string(),
+                          headers: "".to_string(),
+                          auth: "".to_string(),
+                          format: "json".to_string(),
+                          org: "".to_string(),
+                          bucket: "".to_string(),
+                          measurement: "".to_string(),
+                          fields: "".to_string(),
+                          mqtt: None,
+                          topic: "currentlogger/currentch1".to_string(),
+                          udp: None,
+                          tcp: None,
+                          live: false };
+    let mut it = std::env::args().skip(1);
+    while let Some(arg) = it.next() {
+        let mut value = || it.next().ok_or(anyhow!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--server"   => { args.server = value()?; },
+            "--waveform" => { args.waveform = value()?; },
+            "--interval" => { args.interval = value()?.parse()?; },
+            "--duration" => { args.duration = value()?.parse()?; },
+            "--battery"  => { args.battery = value()?.parse()?; },
+            "--trigger"  => { args.trigger = value()?.parse()?; },
+            "--discover" => { args.discover = Some(value()?); },
+            "--path"     => { args.path = value()?; },
+            "--header"   => { args.headers = format!("{};{}", args.headers, value()?); },
+            "--auth"     => { args.auth = value()?; },
+            "--format"   => { args.format = value()?; },
+            "--org"      => { args.org = value()?; },
+            "--bucket"   => { args.bucket = value()?; },
+            "--measurement" => { args.measurement = value()?; },
+            "--fields"   => { args.fields = value()?; },
+            "--mqtt"     => { args.mqtt = Some(value()?); },
+            "--topic"    => { args.topic = value()?; },
+            "--udp"      => { args.udp = Some(value()?); },
+            "--tcp"      => { args.tcp = Some(value()?); },
+            "--live"     => { args.live = true; },
+            _ => bail!("Unknown argument: {}", arg),
+        }
+    }
+    Ok(args)
+}
+
+fn main() -> Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let args = parse_args()?;
+    let steps = INTERVALS.iter().position(|&i| i == args.interval)
+        .ok_or(anyhow!("Interval must be one of {:?}", INTERVALS))?;
+    info!("server={} waveform={} interval={}ms", args.server, args.waveform, args.interval);
+
+    let mut ina228 = Ina228::new(
+        SimIna228::new(Box::new(SystemClock::new()), Waveform::parse(&args.waveform)?, 0.010),
+        INA228_ADDR);
+    ina228.init(0.010)?;
+
+    // The same settings as on the device build the uploads.
+    let mut settings = Settings::new();
+    settings.path = args.path.clone();
+    settings.headers = args.headers.clone();
+    settings.auth = args.auth.clone();
+    settings.format = args.format.clone();
+    settings.org = args.org.clone();
+    settings.bucket = args.bucket.clone();
+    settings.measurement = args.measurement.clone();
+    settings.fields = args.fields.clone();
+    let uplink: Box<dyn Uplink> = match (&args.mqtt, &args.udp, &args.tcp) {
+        (Some(broker), _, _) => {
+            let mut mqtt = MqttUplink::new(broker.clone());
+            mqtt.set_topics(MqttTopics::new(&args.topic));
+            mqtt.start()?;
+            Box::new(mqtt)
+        },
+        (None, Some(collector), _) => {
+            let mut udp = UdpUplink::new(collector.clone());
+            udp.start()?;
+            Box::new(udp)
+        },
+        (None, None, Some(collector)) => {
+            let mut tcp = TcpUplink::new(collector.clone());
+            tcp.start()?;
+            Box::new(tcp)
+        },
+        (None, None, None) => {
+            let mut txd = Transfer::new(args.server.clone());
+            txd.set_endpoint(&settings.upload_path(), settings.upload_headers()?);
+            let (format, point) = settings.upload_format()?;
+            txd.set_format(format, point);
+            if args.discover.is_some() {
+                txd.set_discovery(Box::new(FixedDiscovery { server: args.discover.clone() }));
+            }
+            txd.start()?;
+            Box::new(txd)
+        },
+    };
+
+    // Select the interval, then start logging, through the same button events as the device.
+    // With a trigger the current starts logging instead.
+    let buttons = ScriptedButtons::new();
+    for _ in 0..steps {
+        buttons.push(PushEvent::ShortPress(ButtonRole::Interval));
+    }
+    if args.trigger == 0.0 {
+        buttons.push(PushEvent::ShortPress(ButtonRole::StartStop));
+    }
+
+    let clock = SystemClock::new();
+    let mut logger = Logger::new(Hardware {
+        sensor: Box::new(ina228),
+        clock: Box::new(SystemClock::new()),
+        display: Box::new(DisplayPanel::new()),
+        buttons: Box::new(buttons),
+        battery: Box::new(FixedBattery { voltage: args.battery }),
+        uplink: uplink,
+        leds: Box::<MemoryLeds>::default(),
+        power: Box::<HostPower>::default(),
+        network: Box::new(HostNetwork::new(WifiStatus::Connected)),
+        store: Box::<MemoryStore>::default(),
+    });
+    logger.set_trigger(args.trigger);
+    if args.live {
+        let live = LiveBroadcast::new();
+        live.add_client(Box::new(PrintFeed));
+        live.start();
+        logger.set_live_feed(Box::new(live));
+    }
+    if args.duration == 0 {
+        return logger.run();
+    }
+    while clock.now_ms() < args.duration * 1000 && logger.is_shutdown() == false {
+        clock.sleep_ms(1);
+        logger.step()?;
+    }
+    info!("{} records left in the buffer", logger.get_records().get_size());
+    Ok(())
+}
@@ -1,25 +1,111 @@
+#![allow(clippy::bool_comparison)]
+
 use log::*;
 
+use crate::battery::BatteryState;
+use crate::hal::WALL_CLOCK_SET_MS;
+
+#[derive(Debug, Clone, Copy)]
 pub struct CurrentLog {
     pub voltage: f32,
     pub current: f32,
     pub power: f32,
     pub clock: u32,
-    pub battery: f32,
+    pub time: u64,      // ms since the Unix epoch, or of the monotonic clock while the wall clock is not set
+    pub battery: BatteryState,
 }
 
 impl CurrentLog {
-    pub fn default() -> Self {
-        CurrentLog { voltage: 0.0, current: 0.0, power: 0.0, clock: 0, battery: 0.0 }
+    pub const fn default() -> Self {
+        CurrentLog { voltage: 0.0, current: 0.0, power: 0.0, clock: 0, time: 0, battery: BatteryState::new() }
+    }
+
+    /// True when `time` is on the wall clock.
+    pub fn has_time(&self) -> bool {
+        self.time >= WALL_CLOCK_SET_MS
     }
 }
 
 
+/// Summary of one logging session.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionStats {
+    pub samples: u32,
+    pub duration: u64,      // ms
+    pub current_min: f32,
+    pub current_max: f32,
+    pub voltage_min: f32,
+    pub voltage_max: f32,
+    pub power_max: f32,
+    start: u64,
+    current_sum: f64,
+    voltage_sum: f64,
+    power_sum: f64,
+}
+
+impl SessionStats {
+    /// A session starting at `now` ms.
+    pub fn new(now: u64) -> SessionStats {
+        SessionStats { samples: 0, duration: 0, current_min: 0.0, current_max: 0.0, voltage_min: 0.0,
+                       voltage_max: 0.0, power_max: 0.0, start: now, current_sum: 0.0, voltage_sum: 0.0,
+                       power_sum: 0.0 }
+    }
+
+    pub fn add(&mut self, data: &CurrentLog)
+    {
+        if self.samples == 0 {
+            self.current_min = data.current;
+            self.current_max = data.current;
+            self.voltage_min = data.voltage;
+            self.voltage_max = data.voltage;
+            self.power_max = data.power;
+        }
+        self.samples += 1;
+        self.current_min = self.current_min.min(data.current);
+        self.current_max = self.current_max.max(data.current);
+        self.voltage_min = self.voltage_min.min(data.voltage);
+        self.voltage_max = self.voltage_max.max(data.voltage);
+        self.power_max = self.power_max.max(data.power);
+        self.current_sum += data.current as f64;
+        self.voltage_sum += data.voltage as f64;
+        self.power_sum += data.power as f64;
+    }
+
+    /// Ends the session at `now` ms.
+    pub fn finish(&mut self, now: u64)
+    {
+        self.duration = now.saturating_sub(self.start);
+    }
+
+    pub fn current_avg(&self) -> f32 {
+        if self.samples == 0 { 0.0 } else { (self.current_sum / self.samples as f64) as f32 }
+    }
+
+    pub fn voltage_avg(&self) -> f32 {
+        if self.samples == 0 { 0.0 } else { (self.voltage_sum / self.samples as f64) as f32 }
+    }
+
+    pub fn power_avg(&self) -> f32 {
+        if self.samples == 0 { 0.0 } else { (self.power_sum / self.samples as f64) as f32 }
+    }
+
+    /// Wh over the duration, from the average power.
+    pub fn energy(&self) -> f32 {
+        self.power_avg() * self.duration as f32 / 3_600_000.0
+    }
+}
+
 pub struct CurrentRecord {
     rec: Vec<CurrentLog>,
 }
 
 #[allow(dead_code)]
+impl Default for CurrentRecord {
+    fn default() -> Self {
+        CurrentRecord::new()
+    }
+}
+
 impl CurrentRecord {
     pub fn new() -> CurrentRecord {
         CurrentRecord { rec: Vec::new() }
@@ -32,9 +118,9 @@ impl CurrentRecord {
 
     pub fn dump(&self)
     {
-        info!("time,voltage,current,power,battery");
+        info!("time,voltage,current,power,battery,battery_level");
         for it in &self.rec {
-           info!("{},{},{},{},{}", it.clock, it.voltage, it.current, it.power, it.battery);
+           info!("{},{},{},{},{},{}", it.clock, it.voltage, it.current, it.power, it.battery.voltage, it.battery.percent);
         } 
     }
 
@@ -43,6 +129,20 @@ impl CurrentRecord {
         self.rec.clear()
     }
 
+    /// Moves the records taken before the wall clock was set onto it, `now` being `unix_now` on the monotonic clock.
+    pub fn set_wall_clock(&mut self, now: u64, unix_now: u64)
+    {
+        for it in self.rec.iter_mut().filter(|it| it.has_time() == false) {
+            it.time = unix_now - now.saturating_sub(it.time);
+        }
+    }
+
+    /// Keeps the first `size` records.
+    pub fn truncate(&mut self, size: usize)
+    {
+        self.rec.truncate(size)
+    }
+
     pub fn get_size(&self) -> usize {
         self.rec.len()    
     }
@@ -61,3 +161,69 @@ impl CurrentRecord {
 
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(count: u32) -> CurrentRecord {
+        let mut rec = CurrentRecord::new();
+        for clock in 0..count {
+            let mut data = CurrentLog::default();
+            data.clock = clock;
+            data.time = 1000 + clock as u64;
+            rec.record(data);
+        }
+        rec
+    }
+
+    #[test]
+    fn remove_drains_the_front() {
+        let mut rec = records(10);
+        rec.remove_data(4);
+        assert_eq!(rec.get_size(), 6);
+        assert_eq!(rec.get_all_data()[0].clock, 4);
+        rec.remove_data(0);
+        assert_eq!(rec.get_size(), 6);
+        // More than there are empties it.
+        rec.remove_data(100);
+        assert_eq!(rec.get_size(), 0);
+    }
+
+    #[test]
+    fn truncate_keeps_the_front() {
+        let mut rec = records(10);
+        rec.truncate(3);
+        assert_eq!(rec.get_all_data().iter().map(|r| r.clock).collect::<Vec<_>>(), vec![0, 1, 2]);
+        rec.truncate(5);
+        assert_eq!(rec.get_size(), 3);
+    }
+
+    #[test]
+    fn wall_clock_moves_the_records() {
+        let mut rec = records(3);
+        let unix_now = WALL_CLOCK_SET_MS + 50_000;
+        rec.set_wall_clock(1002, unix_now);
+        let times: Vec<u64> = rec.get_all_data().iter().map(|r| r.time).collect();
+        assert_eq!(times, vec![unix_now - 2, unix_now - 1, unix_now]);
+        assert!(rec.get_all_data().iter().all(|r| r.has_time()));
+    }
+
+    #[test]
+    fn session_stats() {
+        let mut stats = SessionStats::new(1000);
+        for (current, voltage) in [(0.1, 5.0), (0.3, 4.0)] {
+            let mut data = CurrentLog::default();
+            data.current = current;
+            data.voltage = voltage;
+            data.power = current * voltage;
+            stats.add(&data);
+        }
+        stats.finish(3600 * 1000 + 1000);
+        assert_eq!(stats.samples, 2);
+        assert_eq!(stats.current_min, 0.1);
+        assert_eq!(stats.current_max, 0.3);
+        assert!((stats.current_avg() - 0.2).abs() < 1e-6);
+        assert!((stats.energy() - 0.85).abs() < 1e-4);
+    }
+}
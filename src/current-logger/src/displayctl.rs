@@ -1,6 +1,13 @@
+#![allow(clippy::bool_comparison)]
+
+use std::{sync::Arc, sync::Mutex};
+#[cfg(feature = "native")]
 use log::*;
-use std::{thread, time::Duration, sync::Arc, sync::Mutex};
+#[cfg(feature = "native")]
+use std::{thread, time::Duration};
+#[cfg(feature = "native")]
 use esp_idf_hal::{gpio::*, spi, delay::FreeRtos};
+#[cfg(feature = "native")]
 use ssd1331::{DisplayRotation, Ssd1331};
 use embedded_graphics::{
     mono_font::{ascii::FONT_10X20, ascii::FONT_5X8, ascii::FONT_6X12, MonoTextStyle},
@@ -15,52 +22,406 @@ use embedded_graphics::{
 };
 use tinybmp::Bmp;
 
+use crate::hal::DisplaySink;
+use crate::bignumber::{BigNumber, Quantity};
+use crate::battery::{BatteryState, PowerSource};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum LoggingStatus {
     Start,
     Stop,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum WifiStatus {
+    Disconnected,   // No known AP found yet
+    Scanning,
+    Connecting,
     Connected,
-    Disconnected,
+    Lost,           // The connection dropped, reconnecting after a wait
 }
 
+#[cfg(feature = "native")]
 type SPI<'d> = esp_idf_hal::spi::SpiDeviceDriver<'static, spi::SpiDriver<'static>>;
+#[cfg(feature = "native")]
 type DC<'d> = esp_idf_hal::gpio::PinDriver<'static, Gpio10, esp_idf_hal::gpio::Output>;
+#[cfg(feature = "native")]
 type RST<'d> = esp_idf_hal::gpio::PinDriver<'static, Gpio1, esp_idf_hal::gpio::Output>;
 
+// The display thread draws one frame every FRAME_MS.
+pub const FRAME_MS: u64 = 100;
+
+// Offsets cycled by the pixel shift. The layout fills the panel, so a shift hides one edge line.
+const SHIFT: [(i32, i32); 4] = [(0, 0), (1, 0), (1, 1), (0, 1)];
+
+/// Brightness and power saving of the panel. Times are in seconds, 0 disables it.
+#[derive(Debug, Clone, Copy)]
+pub struct DisplaySettings {
+    pub brightness: u8,         // 0..255
+    pub dim_brightness: u8,     // 0..255, after dim_after without a button press
+    pub dim_after: u64,
+    pub off_after: u64,         // Screen off while logging, a button press wakes it up
+    pub pixel_shift: u64,       // Period of moving the whole layout by one pixel
+}
+
+impl Default for DisplaySettings {
+    fn default() -> Self {
+        DisplaySettings::new()
+    }
+}
+
+impl DisplaySettings {
+    pub fn new() -> DisplaySettings {
+        DisplaySettings { brightness: 255, dim_brightness: 64, dim_after: 60, off_after: 0, pixel_shift: 0 }
+    }
+}
+
+// SSD1331 commands. The segment current of a color is its contrast times the master current,
+// so the panel draws less current at a lower contrast while the colors stay as drawn.
+#[cfg(feature = "native")]
+const CMD_CONTRAST_A: u8 = 0x81;
+#[cfg(feature = "native")]
+const CMD_CONTRAST_B: u8 = 0x82;
+#[cfg(feature = "native")]
+const CMD_CONTRAST_C: u8 = 0x83;
+#[cfg(feature = "native")]
+const CMD_MASTER_CURRENT: u8 = 0x87;     // 0..15, 16ths of the full current
+#[cfg(feature = "native")]
+const CMD_DISPLAY_OFF: u8 = 0xAE;        // Sleep mode, the panel is not driven
+#[cfg(feature = "native")]
+const CMD_DISPLAY_ON: u8 = 0xAF;
+
+// The SPI device and D/C pin, shared by the display driver and the commands it has no call for.
+// Both are only used from the display thread.
+#[cfg(feature = "native")]
+struct Shared<T>(Arc<Mutex<T>>);
+
+#[cfg(feature = "native")]
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Shared(self.0.clone())
+    }
+}
+
+#[cfg(feature = "native")]
+impl<T: embedded_hal_0_2::blocking::spi::Write<u8>> embedded_hal_0_2::blocking::spi::Write<u8> for Shared<T> {
+    type Error = T::Error;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.0.lock().unwrap().write(words)
+    }
+}
+
+#[cfg(feature = "native")]
+impl<T: embedded_hal_0_2::digital::v2::OutputPin> embedded_hal_0_2::digital::v2::OutputPin for Shared<T> {
+    type Error = T::Error;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.0.lock().unwrap().set_low()
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.0.lock().unwrap().set_high()
+    }
+}
+
+// Sends command bytes to the panel, D/C low selects the command register.
+#[cfg(feature = "native")]
+fn send_commands(spi: &mut Shared<SPI>, dc: &mut Shared<DC>, commands: &[u8])
+{
+    use embedded_hal_0_2::{blocking::spi::Write, digital::v2::OutputPin};
+    let _ = dc.set_low();
+    if let Err(e) = spi.write(commands) {
+        info!("Display command {:02X?}: {:?}", commands, e);
+    }
+}
+
 struct DisplayText {
     voltage: f32,
     current: f32,
     power: f32,
+    energy: f32,        // Wh
+    charge: f32,        // Ah
+    readout: Quantity,  // Value shown in the big digits
     interval: u32,
-    message: String,
-    battery: f32,
+    message: Option<String>,   // Error message shown instead of the readout
+    battery: BatteryState,
+    battery_low: bool,
     status: LoggingStatus,
     wifi: WifiStatus,
     buffer_water_mark: u32,
+    activity: u32,      // Counts button presses, to restart the dim and off timers
+    asleep: bool,       // Dimmed or off, set by the display thread. A button press only wakes it up then.
+    blank: bool,
+    settings: DisplaySettings,
+}
+
+// Screen layout of the 96x64 panel and the state kept between frames.
+pub struct Screen {
+    wifi: Bmp<'static, Rgb565>,
+    bat: [Bmp<'static, Rgb565>; 6],     // 0, 20, 40, 60, 80, 100%
+    usbpwr: Bmp<'static, Rgb565>,
+    number: BigNumber,
+    loopcount: usize,
+    battery_level: u32,     // Battery icon shown, 0..100 in steps of 20
+    activity: u32,
+    idle_frames: u64,
+    frames: u64,
+    off: bool,
+    brightness: u8,     // Contrast the panel should have
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Screen::new()
+    }
+}
+
+impl Screen {
+    pub fn new() -> Screen {
+        Screen {
+            wifi: Bmp::from_slice(include_bytes!("./img/wifirev.bmp")).unwrap(),
+            bat: [Bmp::from_slice(include_bytes!("./img/battery-0.bmp")).unwrap(),
+                  Bmp::from_slice(include_bytes!("./img/battery-20.bmp")).unwrap(),
+                  Bmp::from_slice(include_bytes!("./img/battery-40.bmp")).unwrap(),
+                  Bmp::from_slice(include_bytes!("./img/battery-60.bmp")).unwrap(),
+                  Bmp::from_slice(include_bytes!("./img/battery-80.bmp")).unwrap(),
+                  Bmp::from_slice(include_bytes!("./img/battery-100.bmp")).unwrap()],
+            usbpwr: Bmp::from_slice(include_bytes!("./img/usb-power.bmp")).unwrap(),
+            number: BigNumber::new(),
+            loopcount: 0,
+            battery_level: 0,
+            activity: 0,
+            idle_frames: 0,
+            frames: 0,
+            off: false,
+            brightness: 255,
+        }
+    }
+
+    /// True while the screen is blanked, nothing changes on the panel then.
+    pub fn is_off(&self) -> bool {
+        self.off
+    }
+
+    /// Contrast of the panel, 0..255. The colors are drawn as they are.
+    pub fn brightness(&self) -> u8 {
+        self.brightness
+    }
+
+    fn draw<D>(&mut self, display: &mut D, lck: &mut DisplayText) -> Result<(), D::Error>
+        where D: DrawTarget<Color = Rgb565>
+    {
+        let settings = &lck.settings;
+        if lck.activity != self.activity {
+            self.activity = lck.activity;
+            self.idle_frames = 0;
+        }
+        else {
+            self.idle_frames += 1;
+        }
+        self.frames += 1;
+        let idle_ms = self.idle_frames * FRAME_MS;
+
+        self.off = lck.blank || (lck.status == LoggingStatus::Start && settings.off_after > 0
+            && idle_ms >= settings.off_after * 1000);
+        self.brightness = if settings.dim_after > 0 && idle_ms >= settings.dim_after * 1000 {
+            settings.dim_brightness
+        }
+        else {
+            settings.brightness
+        };
+        lck.asleep = self.off || self.brightness < settings.brightness;
+        if self.off {
+            return display.clear(Rgb565::BLACK);
+        }
+        let shift = if settings.pixel_shift > 0 {
+            SHIFT[((self.frames * FRAME_MS / (settings.pixel_shift * 1000)) % SHIFT.len() as u64) as usize]
+        }
+        else {
+            SHIFT[0]
+        };
+        self.draw_layout(&mut display.translated(Point::new(shift.0, shift.1)), lck)
+    }
+
+    fn draw_layout<D>(&mut self, display: &mut D, lck: &DisplayText) -> Result<(), D::Error>
+        where D: DrawTarget<Color = Rgb565>
+    {
+        let style = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
+        let middle_style_white = MonoTextStyle::new(&FONT_6X12, Rgb565::WHITE);
+        let middle_style_red = MonoTextStyle::new(&FONT_6X12, Rgb565::RED);
+        let small_style_white = MonoTextStyle::new(&FONT_5X8, Rgb565::WHITE);
+        let fill = PrimitiveStyle::with_fill(Rgb565::YELLOW);
+        // Battery BMP
+        let bat_x = 81;
+        let bat_y = 33;
+
+        display.clear(Rgb565::BLACK)?;
+        match &lck.message {
+            None => {
+                let value = match lck.readout {
+                    Quantity::Voltage => lck.voltage,
+                    Quantity::Current => lck.current,
+                    Quantity::Power   => lck.power,
+                    Quantity::Energy  => lck.energy,
+                    Quantity::Charge  => lck.charge,
+                };
+                self.number.draw(display, value, lck.readout)?;
+            },
+            Some(message) => {
+                Text::new(message, Point::new(1, 20), style).draw(display)?;
+            },
+        }
+
+        match lck.status {
+            LoggingStatus::Start => {
+                if self.loopcount <= 5 {
+                    Circle::new(Point::new(1, 53), 8)
+                        .into_styled(fill)
+                        .draw(display)?;
+                }
+            },
+            LoggingStatus::Stop => {
+            },
+        }
+
+        let cur_pos = 50;
+        // The small line shows the voltage in place of the value already in the big digits.
+        if lck.readout == Quantity::Current {
+            Text::new(&format!("{:.2}V", lck.voltage), Point::new(10, cur_pos), middle_style_white).draw(display)?;
+        }
+        else if lck.current.abs() < 0.001 {
+            Text::new(&format!("{:.0}uA", lck.current * 1_000_000.0), Point::new(10, cur_pos), middle_style_white).draw(display)?;
+        }
+        else if lck.current.abs() < 1.0 {
+            Text::new(&format!("{:.0}mA", lck.current * 1000.0), Point::new(10, cur_pos), middle_style_white).draw(display)?;
+        }
+        else if lck.current > 32.0 {
+            Text::new("N/A", Point::new(10, cur_pos), middle_style_red).draw(display)?;
+        }
+        else {
+            Text::new(&format!("{:.1}A", lck.current), Point::new(10, cur_pos), middle_style_red).draw(display)?;
+        }
+
+        if lck.readout == Quantity::Power {
+            Text::new(&format!("{:.2}V", lck.voltage), Point::new(48, cur_pos), middle_style_white).draw(display)?;
+        }
+        else if lck.power < 1.0 {
+            Text::new(&format!("{:.0}mW", lck.power * 1000.0), Point::new(48, cur_pos), middle_style_white).draw(display)?;
+        }
+        else {
+            Text::new(&format!("{:.1}W", lck.power), Point::new(48, cur_pos), middle_style_red).draw(display)?;
+        }
+        if lck.interval > 1000 {
+            Text::new(&format!("Int.{}s", lck.interval / 1000), Point::new(10, 60), middle_style_white).draw(display)?;
+        }
+        else {
+            Text::new(&format!("Int.{}ms", lck.interval), Point::new(10, 60), middle_style_white).draw(display)?;
+        }
+
+        // Water mark of buffer
+        let bar_len = (lck.buffer_water_mark * 95 / 100) as i32;
+        Line::new(Point::new(0,63), Point::new(bar_len, 63)).into_styled(PrimitiveStyle::with_stroke(Rgb565::RED, 1)).draw(display)?;
+        Triangle::new(Point::new(bar_len-2,61), Point::new(bar_len,63), Point::new(bar_len-2,63)).into_styled(PrimitiveStyle::with_stroke(Rgb565::RED, 1)).draw(display)?;
+
+        match lck.wifi {
+            WifiStatus::Disconnected => {
+            },
+            WifiStatus::Scanning | WifiStatus::Connecting => {
+                if self.loopcount < 5 {
+                    Image::new(&self.wifi, Point::new(76,1)).draw(display)?;
+                }
+            },
+            WifiStatus::Connected => {
+                Image::new(&self.wifi, Point::new(76,1)).draw(display)?;
+            },
+            WifiStatus::Lost => {
+                Image::new(&self.wifi, Point::new(76,1)).draw(display)?;
+                Line::new(Point::new(76,1), Point::new(95,16)).into_styled(PrimitiveStyle::with_stroke(Rgb565::RED, 2)).draw(display)?;
+            },
+        }
+
+        let battery = &lck.battery;
+        // A low battery blinks its level in red.
+        if !lck.battery_low {
+            Text::new(&format!("{}%", battery.percent), Point::new(76, 60), small_style_white).draw(display)?;
+        }
+        else if self.loopcount < 5 {
+            let small_style_red = MonoTextStyle::new(&FONT_5X8, Rgb565::RED);
+            Text::new(&format!("{}%", battery.percent), Point::new(76, 60), small_style_red).draw(display)?;
+        }
+        // Icon steps of 20%, moved only when the level is 4% past the middle between two steps.
+        let percent = battery.percent as u32;
+        if percent >= self.battery_level + 14 {
+            self.battery_level = ((percent + 6) / 20 * 20).min(100);
+        }
+        else if percent + 14 <= self.battery_level {
+            self.battery_level = (percent + 13) / 20 * 20;
+        }
+        let level = (self.battery_level / 20) as usize;
+        match battery.source {
+            PowerSource::Battery => {
+                Image::new(&self.bat[level], Point::new(bat_x, bat_y)).draw(display)?;
+            },
+            PowerSource::Charging => {
+                // Fills up from the present level once a second.
+                let step = (self.frames / 10) as usize % (self.bat.len() - level);
+                Image::new(&self.bat[level + step], Point::new(bat_x, bat_y)).draw(display)?;
+            },
+            PowerSource::Usb => {
+                Image::new(&self.usbpwr, Point::new(bat_x, bat_y)).draw(display)?;
+            },
+        }
+
+        self.loopcount += 1;
+        if self.loopcount == 10 {
+            self.loopcount = 0;
+        }
+        Ok(())
+    }
 }
 
 pub struct DisplayPanel {
     txt: Arc<Mutex<DisplayText>>
 }
 
+impl Default for DisplayPanel {
+    fn default() -> Self {
+        DisplayPanel::new()
+    }
+}
+
 impl DisplayPanel {
 
     pub fn new() -> DisplayPanel {
         DisplayPanel { txt: Arc::new(Mutex::new(
             DisplayText {voltage: 0.0,
-                         message: "".to_string(),
+                         message: None,
                          current: 0.0,
                          power: 0.0,
+                         energy: 0.0,
+                         charge: 0.0,
+                         readout: Quantity::Voltage,
                          interval: 0,
-                         battery: 0.0,
+                         battery: BatteryState::new(),
+                         battery_low: false,
                          status: LoggingStatus::Stop,
                          wifi: WifiStatus::Disconnected,
                          buffer_water_mark: 0,
+                         activity: 0,
+                         asleep: false,
+                         blank: false,
+                         settings: DisplaySettings::new(),
                      })) }
     }
 
+    pub fn set_settings(&mut self, settings: DisplaySettings)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.settings = settings;
+    }
+
+    #[cfg(feature = "native")]
     pub fn start(&mut self,
         spi : SPI, dc: DC, mut rst : RST)
     {
@@ -68,344 +429,176 @@ impl DisplayPanel {
         let _th = thread::spawn(move || {
             info!("Start Display Thread.");
             let mut delay = FreeRtos;
-            let mut display = Ssd1331::new(spi, dc, DisplayRotation::Rotate180);
+            let mut spi = Shared(Arc::new(Mutex::new(spi)));
+            let mut dc = Shared(Arc::new(Mutex::new(dc)));
+            let mut display = Ssd1331::new(spi.clone(), dc.clone(), DisplayRotation::Rotate180);
             let _ = display.reset(&mut rst, &mut delay);
             let _ = display.init();
             display.clear();
-            let style = MonoTextStyle::new(&FONT_10X20, Rgb565::WHITE);
-            let middle_style_white = MonoTextStyle::new(&FONT_6X12, Rgb565::WHITE);
-            let middle_style_red = MonoTextStyle::new(&FONT_6X12, Rgb565::RED);
-            let small_style_white = MonoTextStyle::new(&FONT_5X8, Rgb565::WHITE);
-            let wifibmp = Bmp::from_slice(include_bytes!("./img/wifirev.bmp")).unwrap();
-            let wifi_img: Image<Bmp<Rgb565>> = Image::new(&wifibmp, Point::new(76,1));
-            let fill = PrimitiveStyle::with_fill(Rgb565::YELLOW);
-
-            // Battery BMP
-            let bat_x = 81;
-            let bat_y = 33;
-            let bat0 = Bmp::from_slice(include_bytes!("./img/battery-0.bmp")).unwrap();
-            let bat0_img: Image<Bmp<Rgb565>> = Image::new(&bat0, Point::new(bat_x, bat_y));
-            let bat20 = Bmp::from_slice(include_bytes!("./img/battery-20.bmp")).unwrap();
-            let bat20_img: Image<Bmp<Rgb565>> = Image::new(&bat20, Point::new(bat_x, bat_y));
-            let bat40 = Bmp::from_slice(include_bytes!("./img/battery-40.bmp")).unwrap();
-            let bat40_img: Image<Bmp<Rgb565>> = Image::new(&bat40, Point::new(bat_x, bat_y));
-            let bat60 = Bmp::from_slice(include_bytes!("./img/battery-60.bmp")).unwrap();
-            let bat60_img: Image<Bmp<Rgb565>> = Image::new(&bat60, Point::new(bat_x, bat_y));
-            let bat80 = Bmp::from_slice(include_bytes!("./img/battery-80.bmp")).unwrap();
-            let bat80_img: Image<Bmp<Rgb565>> = Image::new(&bat80, Point::new(bat_x, bat_y));
-            let bat100 = Bmp::from_slice(include_bytes!("./img/battery-100.bmp")).unwrap();
-            let bat100_img: Image<Bmp<Rgb565>> = Image::new(&bat100, Point::new(bat_x, bat_y));
-            let usbpwr = Bmp::from_slice(include_bytes!("./img/usb-power.bmp")).unwrap();
-            let usbpwr_img: Image<Bmp<Rgb565>> = Image::new(&usbpwr, Point::new(bat_x, bat_y));
-
-            // Number BMP
-            let n0 = Bmp::from_slice(include_bytes!("./img/n0.bmp")).unwrap();
-            let n0_img: Image<Bmp<Rgb565>> = Image::new(&n0, Point::zero());
-            let n1 = Bmp::from_slice(include_bytes!("./img/n1.bmp")).unwrap();
-            let n1_img: Image<Bmp<Rgb565>> = Image::new(&n1, Point::zero());
-            let n2 = Bmp::from_slice(include_bytes!("./img/n2.bmp")).unwrap();
-            let n2_img: Image<Bmp<Rgb565>> = Image::new(&n2, Point::zero());
-            let n3 = Bmp::from_slice(include_bytes!("./img/n3.bmp")).unwrap();
-            let n3_img: Image<Bmp<Rgb565>> = Image::new(&n3, Point::zero());
-            let n4 = Bmp::from_slice(include_bytes!("./img/n4.bmp")).unwrap();
-            let n4_img: Image<Bmp<Rgb565>> = Image::new(&n4, Point::zero());
-            let n5 = Bmp::from_slice(include_bytes!("./img/n5.bmp")).unwrap();
-            let n5_img: Image<Bmp<Rgb565>> = Image::new(&n5, Point::zero());
-            let n6 = Bmp::from_slice(include_bytes!("./img/n6.bmp")).unwrap();
-            let n6_img: Image<Bmp<Rgb565>> = Image::new(&n6, Point::zero());
-            let n7 = Bmp::from_slice(include_bytes!("./img/n7.bmp")).unwrap();
-            let n7_img: Image<Bmp<Rgb565>> = Image::new(&n7, Point::zero());
-            let n8 = Bmp::from_slice(include_bytes!("./img/n8.bmp")).unwrap();
-            let n8_img: Image<Bmp<Rgb565>> = Image::new(&n8, Point::zero());
-            let n9 = Bmp::from_slice(include_bytes!("./img/n9.bmp")).unwrap();
-            let n9_img: Image<Bmp<Rgb565>> = Image::new(&n9, Point::zero());
-            let vv = Bmp::from_slice(include_bytes!("./img/v.bmp")).unwrap();
-            let vv_img: Image<Bmp<Rgb565>> = Image::new(&vv, Point::new(68, 0));
-            let dot = Bmp::from_slice(include_bytes!("./img/dot.bmp")).unwrap();
-            let dot_img: Image<Bmp<Rgb565>> = Image::new(&dot, Point::zero());
-            let minus = Bmp::from_slice(include_bytes!("./img/minus.bmp")).unwrap();
-            let minus_img: Image<Bmp<Rgb565>> = Image::new(&minus, Point::zero());
-            let mut digit_img = n0_img.translate(Point::new(0,0));
-
-            let mut loopcount = 0;
-            let mut battery_level = 0;
-            let mut battery_queue : [f32;10] = [0.0;10];
+            let mut screen = Screen::new();
+            let mut contrast: Option<u8> = None;
             loop {
-                let lck = txt.lock().unwrap();
-                display.clear();
-                let mut temp = lck.voltage;
-                if temp != -999.0 {
-                    dot_img.draw(&mut display).unwrap();                
-                    vv_img.draw(&mut display).unwrap();
-                    let mut digit_10 = 10.0;
-                    let mut first_digit = true;
-                    let mut pos_x = 0;
-                    for digit in 0..=3 {
-                        if pos_x >= 68 {
-                            continue;
-                        }
-                        let num = (temp / digit_10) as i32;
-                        if temp < 0.0 && digit == 0 {
-                            digit_img = minus_img.translate(Point::new(pos_x, 0));
-                            digit_img.draw(&mut display).unwrap();
-                            pos_x += 20;
-                        }
-                        match num {
-                            0 => {
-                                if !first_digit || digit > 0 {
-                                    digit_img = n0_img.translate(Point::new(pos_x, 0));
-                                    pos_x += 20;
-                                }
-                            },
-                            1 | -1 => {
-                                digit_img = n1_img.translate(Point::new(pos_x, 0));
-                                first_digit = false;
-                                pos_x += 20;
-                            },
-                            2 | -2 => {
-                                digit_img = n2_img.translate(Point::new(pos_x, 0));
-                                first_digit = false;
-                                pos_x += 20;
-                            },
-                            3 | -3 => {
-                                digit_img = n3_img.translate(Point::new(pos_x, 0));
-                                first_digit = false;
-                                pos_x += 20;
-                            },
-                            4 | -4 => {
-                                digit_img = n4_img.translate(Point::new(pos_x, 0));
-                                first_digit = false;
-                                pos_x += 20;
-                            },
-                            5 | -5 => {
-                                digit_img = n5_img.translate(Point::new(pos_x, 0));
-                                first_digit = false;
-                                pos_x += 20;
-                            },
-                            6 | -6 => {
-                                digit_img = n6_img.translate(Point::new(pos_x, 0));
-                                first_digit = false;
-                                pos_x += 20;
-                            },
-                            7 | -7 => {
-                                digit_img = n7_img.translate(Point::new(pos_x, 0));
-                                first_digit = false;
-                                pos_x += 20;
-                            },
-                            8 | -8 => {
-                                digit_img = n8_img.translate(Point::new(pos_x, 0));
-                                first_digit = false;
-                                pos_x += 20;
-                            },
-                            9 | -9 => {
-                                digit_img = n9_img.translate(Point::new(pos_x, 0));
-                                first_digit = false;
-                                pos_x += 20;
-                            },
-                            _ => {}
-                        }
-                        digit_img.draw(&mut display).unwrap();
-                        if digit == 1 {
-                            digit_img = dot_img.translate(Point::new(pos_x, 0));
-                            digit_img.draw(&mut display).unwrap();
-                            pos_x += 8;
-                        }
-                        temp = temp - digit_10 * (num as f32);
-                        digit_10 /= 10.0;
-                    }
-                }
-                else {
-                    Text::new(&format!("{}", lck.message), Point::new(1, 20), style).draw(&mut display).unwrap();
-                }
-
-                match lck.status {
-                    LoggingStatus::Start => {
-                        match loopcount {
-                            0..=5 => {
-                                Circle::new(Point::new(1, 53), 8)
-                                    .into_styled(fill)
-                                    .draw(&mut display).unwrap();
-                            },
-                            _ => {},
-                        }
-                    },
-                    LoggingStatus::Stop => {
-                    },
-                }
-                let cur_pos = 50;
-                if lck.current.abs() < 0.001 {
-                    Text::new(&format!("{:.0}uA", lck.current * 1000_000.0), Point::new(10, cur_pos), middle_style_white).draw(&mut display).unwrap();
-                }
-                else if lck.current.abs() < 1.0 {
-                    Text::new(&format!("{:.0}mA", lck.current * 1000.0), Point::new(10, cur_pos), middle_style_white).draw(&mut display).unwrap();
-                }
-                else if lck.current > 32.0 {
-                    Text::new("N/A", Point::new(10, cur_pos), middle_style_red).draw(&mut display).unwrap();
-                }
-                else {
-                    Text::new(&format!("{:.1}A", lck.current), Point::new(10, cur_pos), middle_style_red).draw(&mut display).unwrap();
-                }
-
-                if lck.power < 1.0 {
-                    Text::new(&format!("{:.0}mW", lck.power * 1000.0), Point::new(48, cur_pos), middle_style_white).draw(&mut display).unwrap();
-                }
-                else {
-                    Text::new(&format!("{:.1}W", lck.power), Point::new(48, cur_pos), middle_style_red).draw(&mut display).unwrap();
-                }
-                Text::new(&format!("Int.{}ms", lck.interval), Point::new(10, 60), middle_style_white).draw(&mut display).unwrap();
-
-                // Water mark of buffer
-                let bar_len = (lck.buffer_water_mark * 95 / 100) as i32;
-                Line::new(Point::new(0,63), Point::new(bar_len, 63)).into_styled(PrimitiveStyle::with_stroke(Rgb565::RED, 1)).draw(&mut display).unwrap();
-                Triangle::new(Point::new(bar_len-2,61), Point::new(bar_len,63), Point::new(bar_len-2,63)).into_styled(PrimitiveStyle::with_stroke(Rgb565::RED, 1)).draw(&mut display).unwrap();
-
-                match lck.wifi {
-                    WifiStatus::Disconnected => {
-                    },
-                    WifiStatus::Connected => {
-                        wifi_img.draw(&mut display).unwrap();
-                    },
-                }
-
-                battery_queue[loopcount] = lck.battery;
-                let mut battery_average = 0.0;
-                for i in 0..10 {
-                    battery_average += battery_queue[i];
-                }
-                battery_average = battery_average / 10.0;
-                Text::new(&format!("{:.1}V", battery_average), Point::new(76, 60), small_style_white).draw(&mut display).unwrap();
-                //                info!("battery_average: {}v", battery_average);
-                match battery_level {
-                    0 => {
-                        if battery_average > 3.75 {
-                            battery_level = 20;
-                        }
-                    },
-                    20 => {
-                        if battery_average > 3.85 {
-                            battery_level = 40;
-                        }
-                        else if battery_average < 3.7 {
-                            battery_level = 0;
-                        }
-                    },
-                    40 => {
-                        if battery_average > 3.95 {
-                            battery_level = 60;
-                        }
-                        else if battery_average < 3.8 {
-                            battery_level = 20;
-                        }
-                    },
-                    60 => {
-                        if battery_average > 4.05 {
-                            battery_level = 80;
-                        }
-                        else if battery_average < 3.9 {
-                            battery_level = 40;
-                        }
-                    },
-                    80 => {
-                        if battery_average > 4.15 {
-                            battery_level = 100;
-                        }
-                        else if battery_average < 4.0 {
-                            battery_level = 60;
-                        }
-                    }
-                    100 => {
-                        if battery_average > 4.55 {
-                            battery_level = 200;
-                        }
-                        else if battery_average < 4.1 {
-                            battery_level = 80;
-                        }
-                    },
-                    200 => {
-                        if battery_average < 4.5 {
-                            battery_level = 100;
-                        }
-                    },
-                    _ => {
-                        battery_level = 0;
-                    }
+                let mut lck = txt.lock().unwrap();
+                let was_off = screen.is_off();
+                screen.draw(&mut display, &mut lck).unwrap();
+                drop(lck);
+                let brightness = screen.brightness();
+                if contrast != Some(brightness) {
+                    send_commands(&mut spi, &mut dc, &[CMD_MASTER_CURRENT, 0x0F, CMD_CONTRAST_A, brightness,
+                                                       CMD_CONTRAST_B, brightness, CMD_CONTRAST_C, brightness]);
+                    contrast = Some(brightness);
                 }
-                match battery_level {
-                    0 => {
-                        bat0_img.draw(&mut display).unwrap();
-                    },
-                    20 => {
-                        bat20_img.draw(&mut display).unwrap();
-                    },
-                    40 => {
-                        bat40_img.draw(&mut display).unwrap();
-                    },
-                    60 => {
-                        bat60_img.draw(&mut display).unwrap();
-                    },
-                    80 => {
-                        bat80_img.draw(&mut display).unwrap();
-                    },
-                    100 => {
-                        bat100_img.draw(&mut display).unwrap();
-                    },
-                    200 => {
-                        usbpwr_img.draw(&mut display).unwrap();
-                    },
-                    _ => {}
+                // A blank screen is sent once and the panel sleeps, then the SPI stays idle until it wakes up.
+                if !(was_off && screen.is_off()) {
+                    display.flush().unwrap();
                 }
-
-                loopcount += 1;
-                if loopcount == 10 {
-                    loopcount = 0;
+                if screen.is_off() != was_off {
+                    send_commands(&mut spi, &mut dc, &[if screen.is_off() { CMD_DISPLAY_OFF } else { CMD_DISPLAY_ON }]);
                 }
-                display.flush().unwrap();
-                drop(lck);
-                thread::sleep(Duration::from_millis(100));
+                thread::sleep(Duration::from_millis(FRAME_MS));
             }
         });
     }
 
-    pub fn set_voltage(&mut self, vol: f32, cur: f32, power: f32)
+    /// Draws the current values on any Rgb565 target, like the display thread does.
+    #[allow(dead_code)]
+    pub fn render<D>(&self, screen: &mut Screen, target: &mut D) -> Result<(), D::Error>
+        where D: DrawTarget<Color = Rgb565>
+    {
+        let mut lck = self.txt.lock().unwrap();
+        screen.draw(target, &mut lck)
+    }
+}
+
+impl DisplaySink for DisplayPanel {
+    fn set_voltage(&mut self, vol: f32, cur: f32, power: f32)
     {
         let mut lck = self.txt.lock().unwrap();
         lck.voltage = vol;
         lck.current = cur;
         lck.power = power;
+        lck.message = None;
+    }
+
+    fn set_accumulated(&mut self, energy: f32, charge: f32)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.energy = energy;
+        lck.charge = charge;
+    }
+
+    fn set_readout(&mut self, readout: Quantity)
+    {
+        let mut lck = self.txt.lock().unwrap();
+        lck.readout = readout;
     }
 
-    pub fn set_interval(&mut self, interval : u32)
+    fn set_interval(&mut self, interval : u32)
     {
         let mut lck = self.txt.lock().unwrap();
         lck.interval = interval;
     }
 
-    pub fn set_current_status(&mut self, status: LoggingStatus)
+    fn set_current_status(&mut self, status: LoggingStatus)
     {
         let mut lck= self.txt.lock().unwrap();
         lck.status = status;
     }
 
-    pub fn set_wifi_status(&mut self, status: WifiStatus)
+    fn set_wifi_status(&mut self, status: WifiStatus)
     {
         let mut lck= self.txt.lock().unwrap();
         lck.wifi = status;
     }
 
-    pub fn set_err_message(&mut self, msg: String)
+    fn set_err_message(&mut self, msg: String)
     {
         let mut lck = self.txt.lock().unwrap();
-        lck.message = msg;
-        lck.voltage = -999.0;
+        lck.message = Some(msg);
     }
 
-    pub fn set_battery(&mut self, bat: f32){
+    fn set_battery(&mut self, bat: BatteryState){
         let mut lck = self.txt.lock().unwrap();
         lck.battery = bat;
     }
 
-    pub fn set_buffer_watermark(&mut self, wm: u32){
+    fn set_battery_low(&mut self, low: bool){
+        let mut lck = self.txt.lock().unwrap();
+        lck.battery_low = low;
+    }
+
+    fn set_buffer_watermark(&mut self, wm: u32){
         let mut lck = self.txt.lock().unwrap();
         lck.buffer_water_mark = wm;
     }
+
+    fn wake(&mut self) -> bool {
+        let mut lck = self.txt.lock().unwrap();
+        lck.activity = lck.activity.wrapping_add(1);
+        // Cleared here already, a second press before the next frame is not taken as a wake up.
+        let asleep = lck.asleep;
+        lck.asleep = false;
+        asleep
+    }
+
+    fn blank(&mut self){
+        let mut lck = self.txt.lock().unwrap();
+        lck.blank = true;
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::hosthal::Framebuffer;
+
+    // Draws frames for `ms` ms like the display thread.
+    fn frames(dp: &DisplayPanel, screen: &mut Screen, ms: u64) -> Framebuffer {
+        let mut fb = Framebuffer::new();
+        for _ in 0..ms / FRAME_MS {
+            dp.render(screen, &mut fb).unwrap();
+        }
+        fb
+    }
+
+    #[test]
+    fn dims_by_contrast_and_wakes_up() {
+        let mut dp = DisplayPanel::new();
+        let mut settings = DisplaySettings::new();
+        settings.dim_after = 2;
+        dp.set_settings(settings);
+        let mut screen = Screen::new();
+        let bright = frames(&dp, &mut screen, 1000);
+        assert_eq!(screen.brightness(), 255);
+        assert!(dp.wake() == false);
+
+        let dimmed = frames(&dp, &mut screen, 3000);
+        assert_eq!(screen.brightness(), 64);
+        // The panel dims, the pixels stay as drawn.
+        assert!(bright.to_bmp() == dimmed.to_bmp());
+        assert!(dp.wake());
+        assert!(dp.wake() == false);
+        frames(&dp, &mut screen, 100);
+        assert_eq!(screen.brightness(), 255);
+    }
+
+    #[test]
+    fn turns_off_while_logging() {
+        let mut dp = DisplayPanel::new();
+        let mut settings = DisplaySettings::new();
+        settings.dim_after = 0;
+        settings.off_after = 1;
+        dp.set_settings(settings);
+        let mut screen = Screen::new();
+        frames(&dp, &mut screen, 2000);
+        // Stays on while not logging.
+        assert!(screen.is_off() == false);
+        assert!(dp.wake() == false);
+        dp.set_current_status(LoggingStatus::Start);
+        frames(&dp, &mut screen, 2000);
+        assert!(screen.is_off());
+        assert!(dp.wake());
+        frames(&dp, &mut screen, 100);
+        assert!(screen.is_off() == false);
+    }
 }
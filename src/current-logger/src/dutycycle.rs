@@ -0,0 +1,127 @@
+// Logging with deep sleep between samples, for intervals of a minute or longer.
+// The state lives in RTC memory, which keeps its contents through deep sleep.
+#![allow(clippy::redundant_field_names)]
+
+use log::*;
+use anyhow::Result;
+
+use crate::hal::Sensor;
+use crate::currentlogs::{CurrentLog, CurrentRecord};
+use crate::battery::BatteryState;
+
+pub const DUTY_SAMPLES: usize = 128;   // 4KB of the 8KB RTC memory
+pub const MIN_DUTY_INTERVAL: u32 = 60_000;  // ms, shorter ones would join the WiFi again and again
+
+pub struct DutyCycle {
+    active: bool,
+    start: u64,         // ms of the wall clock when logging started
+    interval: u64,      // ms
+    count: usize,
+    lost: u32,          // Samples dropped because the buffer was full
+    samples: [CurrentLog; DUTY_SAMPLES],
+}
+
+impl Default for DutyCycle {
+    fn default() -> Self {
+        DutyCycle::new()
+    }
+}
+
+impl DutyCycle {
+    pub const fn new() -> DutyCycle {
+        DutyCycle { active: false, start: 0, interval: 0, count: 0, lost: 0,
+                    samples: [CurrentLog::default(); DUTY_SAMPLES] }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    pub fn start(&mut self, now: u64, interval: u64) {
+        info!("Duty cycled logging every {}ms", interval);
+        self.active = true;
+        self.start = now;
+        self.interval = interval;
+        self.count = 0;
+        self.lost = 0;
+    }
+
+    pub fn stop(&mut self) {
+        info!("Duty cycled logging stopped, {} samples lost", self.lost);
+        self.active = false;
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.count == DUTY_SAMPLES
+    }
+
+    /// Reads one sample at `now` ms of the wall clock. The clock may be set back by SNTP, then the offset is 0. When the buffer is full the oldest sample is dropped.
+    pub fn sample(&mut self, sensor: &mut dyn Sensor, battery: BatteryState, now: u64) -> Result<()> {
+        let m = sensor.read()?;
+        let data = CurrentLog { voltage: m.voltage, current: m.current, power: m.power,
+                                clock: now.saturating_sub(self.start) as u32, time: now, battery: battery };
+        if self.is_full() {
+            self.samples.copy_within(1.., 0);
+            self.count -= 1;
+            self.lost += 1;
+        }
+        self.samples[self.count] = data;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Moves the samples into a record for uploading.
+    pub fn take(&mut self) -> CurrentRecord {
+        let mut rec = CurrentRecord::new();
+        for data in &self.samples[..self.count] {
+            rec.record(*data);
+        }
+        self.count = 0;
+        rec
+    }
+
+    /// Puts back what the upload did not send.
+    pub fn restore(&mut self, rec: &CurrentRecord) {
+        for data in rec.get_all_data().iter().rev().take(DUTY_SAMPLES - self.count).rev() {
+            self.samples[self.count] = *data;
+            self.count += 1;
+        }
+    }
+
+    /// Time to sleep from `now` until the next sample, keeping samples on the interval grid.
+    pub fn next_sleep(&self, now: u64) -> u64 {
+        self.interval - now.saturating_sub(self.start) % self.interval
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::hal::Measurement;
+    use crate::hosthal::ConstantSensor;
+
+    #[test]
+    fn clock_set_back() {
+        let mut duty = DutyCycle::new();
+        let mut sensor = ConstantSensor { value: Measurement { voltage: 3.3, current: 0.01, ..Default::default() } };
+        duty.start(1_000_000, 60_000);
+        duty.sample(&mut sensor, BatteryState::new(), 1_030_000).unwrap();
+        assert_eq!(duty.next_sleep(1_030_000), 30_000);
+
+        // SNTP moved the wall clock before the start.
+        duty.sample(&mut sensor, BatteryState::new(), 900_000).unwrap();
+        assert_eq!(duty.next_sleep(900_000), 60_000);
+        let rec = duty.take();
+        let clocks: Vec<u32> = rec.get_all_data().iter().map(|d| d.clock).collect();
+        assert_eq!(clocks, vec![30_000, 0]);
+        assert_eq!(rec.get_all_data()[1].time, 900_000);
+    }
+}
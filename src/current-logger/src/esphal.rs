@@ -0,0 +1,243 @@
+// ESP-IDF implementations of the hardware abstraction.
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use std::ffi::CString;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::{bail, Result};
+use esp_idf_hal::gpio::*;
+use esp_idf_hal::i2c::I2cDriver;
+use esp_idf_hal::delay::BLOCK;
+use esp_idf_hal::adc::{AdcDriver, AdcChannelDriver, Atten11dB, ADC1};
+
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::http::server::ws::EspHttpWsDetachedSender;
+use embedded_svc::ws::{FrameType, Sender};
+
+use esp_idf_sys::{esp_tls_t, esp_tls_cfg_t, esp_tls_init, esp_tls_conn_new_sync, esp_tls_conn_destroy,
+                  esp_tls_get_ssl_context, esp_crt_bundle_attach, mbedtls_ssl_context, mbedtls_ssl_get_peer_cert,
+                  lwip_setsockopt, timeval, SOL_SOCKET, SO_RCVTIMEO};
+
+use crate::hal::{I2cBus, BatteryAdc, StatusLeds, PowerControl, KeyValueStore, Connection, Connector, FeedSender};
+use crate::tlspin::{parse_pin, matches_pin};
+
+pub struct EspI2c {
+    drv: I2cDriver<'static>,
+}
+
+impl EspI2c {
+    pub fn new(drv: I2cDriver<'static>) -> Self {
+        EspI2c { drv: drv }
+    }
+}
+
+impl I2cBus for EspI2c {
+    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<()> {
+        self.drv.write(addr, bytes, BLOCK)?;
+        Ok(())
+    }
+
+    fn read(&mut self, addr: u8, buf: &mut [u8]) -> Result<()> {
+        self.drv.read(addr, buf, BLOCK)?;
+        Ok(())
+    }
+}
+
+// Battery voltage is divided by 2 before ADC GPIO0.
+pub struct EspBatteryAdc {
+    adc: AdcDriver<'static, ADC1>,
+    pin: AdcChannelDriver<'static, Gpio0, Atten11dB<ADC1>>,
+}
+
+impl EspBatteryAdc {
+    pub fn new(adc: AdcDriver<'static, ADC1>, pin: AdcChannelDriver<'static, Gpio0, Atten11dB<ADC1>>) -> Self {
+        EspBatteryAdc { adc: adc, pin: pin }
+    }
+}
+
+impl BatteryAdc for EspBatteryAdc {
+    fn read_voltage(&mut self) -> Result<f32> {
+        Ok(self.adc.read(&mut self.pin)? as f32 * 2.0 / 1000.0)
+    }
+}
+
+pub struct EspLeds {
+    interval: PinDriver<'static, Gpio6, InputOutput>,
+    startstop: PinDriver<'static, Gpio7, InputOutput>,
+}
+
+impl EspLeds {
+    pub fn new(interval: PinDriver<'static, Gpio6, InputOutput>,
+               startstop: PinDriver<'static, Gpio7, InputOutput>) -> Self {
+        EspLeds { interval: interval, startstop: startstop }
+    }
+}
+
+impl StatusLeds for EspLeds {
+    fn set_logging(&mut self, on: bool) -> Result<()> {
+        if on { self.startstop.set_high()?; } else { self.startstop.set_low()?; }
+        Ok(())
+    }
+
+    fn set_measuring(&mut self, on: bool) -> Result<()> {
+        if on { self.interval.set_high()?; } else { self.interval.set_low()?; }
+        Ok(())
+    }
+}
+
+pub struct EspPower;
+
+impl PowerControl for EspPower {
+    fn deep_sleep(&mut self, ms: u64) {
+        unsafe {
+            esp_idf_sys::esp_deep_sleep(ms * 1000);
+        }
+    }
+}
+
+const NVS_VALUE_MAX: usize = 4096;     // A CA certificate in PEM fits
+
+pub struct EspStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl EspStore {
+    pub fn new(partition: EspDefaultNvsPartition, namespace: &str) -> Result<Self> {
+        Ok(EspStore { nvs: EspNvs::new(partition, namespace, true)? })
+    }
+}
+
+impl KeyValueStore for EspStore {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        let mut buf = vec![0u8; NVS_VALUE_MAX];
+        match self.nvs.get_raw(key, &mut buf)? {
+            Some(value) => Ok(Some(String::from_utf8_lossy(value).to_string())),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.nvs.set_raw(key, value.as_bytes())?;
+        Ok(())
+    }
+}
+
+const MBEDTLS_ERR_SSL_PEER_CLOSE_NOTIFY: isize = -0x7880;
+const MBEDTLS_ERR_SSL_WANT_READ: isize = -0x6900;       // The socket timed out
+
+/// TLS by esp-tls. The server is verified by the CA given in PEM, or by the certificate bundle
+/// of ESP-IDF. With a pin the SHA-256 of the verified server certificate must match as well.
+pub struct EspTlsConnector {
+    ca: Option<CString>,
+    pin: Option<[u8; 32]>,
+}
+
+impl EspTlsConnector {
+    pub fn new(ca_pem: &str, pin: &str) -> Result<Self> {
+        let ca = if ca_pem.trim().is_empty() { None } else { Some(CString::new(ca_pem.trim())?) };
+        Ok(EspTlsConnector { ca: ca, pin: parse_pin(pin)? })
+    }
+}
+
+impl Connector for EspTlsConnector {
+    fn connect(&mut self, host: &str, addr: SocketAddr, timeout: Duration) -> Result<Box<dyn Connection>> {
+        let mut cfg: esp_tls_cfg_t = unsafe { std::mem::zeroed() };
+        cfg.timeout_ms = timeout.as_millis() as _;
+        match &self.ca {
+            Some(ca) => {
+                cfg.__bindgen_anon_1.cacert_pem_buf = ca.as_ptr() as *const u8;
+                cfg.__bindgen_anon_2.cacert_pem_bytes = ca.as_bytes_with_nul().len() as _;
+            },
+            None => { cfg.crt_bundle_attach = Some(esp_crt_bundle_attach); },
+        }
+        let name = CString::new(host)?;
+        let tls = unsafe { esp_tls_init() };
+        if tls.is_null() {
+            bail!("TLS out of memory");
+        }
+        // The connection owns `tls` from here and destroys it when dropped.
+        let mut conn = EspTlsConnection { tls: tls };
+        let ret = unsafe { esp_tls_conn_new_sync(name.as_ptr(), host.len() as _, addr.port() as _, &cfg, tls) };
+        if ret != 1 {
+            bail!("TLS connection to {} failed", host);
+        }
+        if let Some(pin) = &self.pin {
+            let ssl = unsafe { esp_tls_get_ssl_context(tls) } as *const mbedtls_ssl_context;
+            let cert = unsafe { mbedtls_ssl_get_peer_cert(ssl) };
+            if cert.is_null() {
+                bail!("No certificate from {}", host);
+            }
+            let der = unsafe { std::slice::from_raw_parts((*cert).raw.p, (*cert).raw.len as usize) };
+            if matches_pin(der, pin) == false {
+                bail!("Certificate of {} does not match the pin", host);
+            }
+        }
+        conn.set_read_timeout(timeout)?;
+        Ok(Box::new(conn))
+    }
+}
+
+struct EspTlsConnection {
+    tls: *mut esp_tls_t,
+}
+
+// esp-tls connections may move between threads, they are used by one at a time.
+unsafe impl Send for EspTlsConnection {}
+
+impl Connection for EspTlsConnection {
+    fn set_read_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        let tv = timeval { tv_sec: timeout.as_secs() as _, tv_usec: timeout.subsec_micros() as _ };
+        let ret = unsafe { lwip_setsockopt((*self.tls).sockfd, SOL_SOCKET as _, SO_RCVTIMEO as _,
+                                           &tv as *const timeval as *const _, std::mem::size_of::<timeval>() as _) };
+        if ret != 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, "Could not set the read timeout"));
+        }
+        Ok(())
+    }
+}
+
+impl io::Read for EspTlsConnection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = unsafe { (*self.tls).read.unwrap() };
+        let n = unsafe { read(self.tls, buf.as_mut_ptr() as *mut _, buf.len()) } as isize;
+        match n {
+            n if n >= 0 => Ok(n as usize),
+            MBEDTLS_ERR_SSL_PEER_CLOSE_NOTIFY => Ok(0),
+            MBEDTLS_ERR_SSL_WANT_READ => Err(io::Error::new(io::ErrorKind::WouldBlock, "TLS read timed out")),
+            n => Err(io::Error::new(io::ErrorKind::Other, format!("TLS read error {}", n))),
+        }
+    }
+}
+
+impl io::Write for EspTlsConnection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let write = unsafe { (*self.tls).write.unwrap() };
+        let n = unsafe { write(self.tls, buf.as_ptr() as *const _, buf.len()) } as isize;
+        if n < 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("TLS write error {}", n)));
+        }
+        Ok(n as usize)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for EspTlsConnection {
+    fn drop(&mut self) {
+        unsafe { esp_tls_conn_destroy(self.tls); }
+    }
+}
+
+// A WebSocket of the httpd, the frames are queued to the server task.
+impl FeedSender for EspHttpWsDetachedSender {
+    fn send_text(&mut self, text: &str) -> Result<()> {
+        if self.is_closed() {
+            bail!("WebSocket closed");
+        }
+        self.send(FrameType::Text(false), text.as_bytes())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,207 @@
+// Hardware abstraction used by the measurement core.
+// The ESP-IDF implementations live in esphal.rs and the host ones in hosthal.rs.
+
+use std::{thread, time::Duration, time::Instant, time::SystemTime, time::UNIX_EPOCH};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::io::{Read, Write};
+use anyhow::Result;
+
+use crate::currentlogs::{CurrentLog, SessionStats};
+use crate::displayctl::{LoggingStatus, WifiStatus};
+use crate::pushswitch::PushEvent;
+use crate::bignumber::Quantity;
+use crate::battery::BatteryState;
+use crate::livefeed::LiveStatus;
+
+/// Raw register access to a device on the I2C bus.
+pub trait I2cBus: Send {
+    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<()>;
+    fn read(&mut self, addr: u8, buf: &mut [u8]) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Measurement {
+    pub voltage: f32,   // V
+    pub current: f32,   // A
+    pub power: f32,     // W
+    pub energy: f32,    // Wh accumulated since the last reset
+    pub charge: f32,    // Ah accumulated since the last reset
+}
+
+/// Voltage/current/power sensor.
+pub trait Sensor {
+    fn read(&mut self) -> Result<Measurement>;
+    fn reset_accumulators(&mut self) -> Result<()>;
+}
+
+/// Wall clock times before this (2020-09-13) mean the time was never set.
+pub const WALL_CLOCK_SET_MS: u64 = 1_600_000_000_000;
+
+/// Monotonic millisecond clock.
+pub trait Clock {
+    fn now_ms(&self) -> u64;
+    fn sleep_ms(&self, ms: u64);
+    /// ms since the Unix epoch, None until the wall clock is set, e.g. by SNTP.
+    fn unix_ms(&self) -> Option<u64>;
+}
+
+/// Clock of the running system, used on both the ESP32 and the host.
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock { start: Instant::now() }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    fn sleep_ms(&self, ms: u64) {
+        thread::sleep(Duration::from_millis(ms));
+    }
+
+    fn unix_ms(&self) -> Option<u64> {
+        let ms = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_millis() as u64;
+        if ms < WALL_CLOCK_SET_MS { None } else { Some(ms) }
+    }
+}
+
+/// Receiver of the values shown on the display.
+pub trait DisplaySink {
+    fn set_voltage(&mut self, vol: f32, cur: f32, power: f32);
+    fn set_accumulated(&mut self, energy: f32, charge: f32);
+    fn set_readout(&mut self, readout: Quantity);
+    fn set_interval(&mut self, interval: u32);
+    fn set_current_status(&mut self, status: LoggingStatus);
+    fn set_wifi_status(&mut self, status: WifiStatus);
+    fn set_err_message(&mut self, msg: String);
+    fn set_battery(&mut self, bat: BatteryState);
+    fn set_battery_low(&mut self, low: bool);
+    fn set_buffer_watermark(&mut self, wm: u32);
+    /// Restarts the dim and screen-off timers after a button press.
+    /// True when the screen was dimmed or off, the press only woke it up then.
+    fn wake(&mut self) -> bool;
+    /// Turns the screen off for good, before the device goes to deep sleep.
+    fn blank(&mut self);
+}
+
+/// Source of push button events.
+pub trait Buttons {
+    fn get_event(&mut self) -> Option<PushEvent>;
+}
+
+/// Battery voltage measurement.
+pub trait BatteryAdc {
+    fn read_voltage(&mut self) -> Result<f32>;   // V at the cell, before averaging
+}
+
+/// Remote control of the logger through the uplink.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RemoteCommand {
+    Start,
+    Stop,
+    Interval(u32),      // ms
+}
+
+impl RemoteCommand {
+    /// Parses "start", "stop" or "interval=<ms>".
+    pub fn parse(command: &str) -> Option<RemoteCommand> {
+        match command.trim().split_once('=') {
+            Some(("interval", ms)) => ms.trim().parse().ok().map(RemoteCommand::Interval),
+            Some(_) => None,
+            None if command.trim() == "start" => Some(RemoteCommand::Start),
+            None if command.trim() == "stop" => Some(RemoteCommand::Stop),
+            None => None,
+        }
+    }
+}
+
+/// Destination of the logged records.
+/// Only the records are required, the session, statistics, alerts and commands are for uplinks that carry them.
+pub trait Uplink {
+    /// Starts sending up to one chunk from the front of `data` and returns how many records it holds,
+    /// 0 while a transfer is pending. The records stay in the buffer until they are acknowledged.
+    fn set_transfer_data(&mut self, data: &[CurrentLog]) -> usize;
+    /// Records the server accepted since the last call, to be removed from the front of the buffer.
+    fn take_acknowledged(&mut self) -> usize;
+    /// Queues an event like "shutdown", returns false while the previous transfer is pending.
+    fn send_event(&mut self, event: &str, battery: &BatteryState) -> bool;
+    /// True when nothing is waiting to be sent.
+    fn is_idle(&self) -> bool;
+    /// Logging started or stopped, `reason` is e.g. "button", "trigger" or "remote".
+    fn session(&mut self, _logging: bool, _interval: u32, _reason: &str) {}
+    /// Summary of the session that just stopped.
+    fn statistics(&mut self, _stats: &SessionStats) {}
+    /// A condition worth telling at once, like "battery_low" or "sensor_error".
+    fn alert(&mut self, _alert: &str, _detail: &str) {}
+    /// A command received from the server.
+    fn take_command(&mut self) -> Option<RemoteCommand> {
+        None
+    }
+}
+
+/// Receives every record and the state shown on the display, for a live view.
+pub trait LiveFeed {
+    fn record(&mut self, data: &CurrentLog);
+    fn status(&mut self, status: &LiveStatus);
+}
+
+/// A client of the live feed, e.g. a WebSocket.
+pub trait FeedSender: Send {
+    /// Fails when the client is gone.
+    fn send_text(&mut self, text: &str) -> Result<()>;
+}
+
+/// Start/stop and measurement indicator LEDs.
+pub trait StatusLeds {
+    fn set_logging(&mut self, on: bool) -> Result<()>;
+    fn set_measuring(&mut self, on: bool) -> Result<()>;
+}
+
+/// Sleep of the whole device.
+pub trait PowerControl {
+    /// Sleeps for `ms` and restarts. On the host it returns, and the logger stops.
+    fn deep_sleep(&mut self, ms: u64);
+}
+
+/// Network connection, kept up by a background task.
+pub trait Network {
+    fn status(&self) -> WifiStatus;
+    fn rssi(&self) -> Option<i8>;           // dBm
+    fn ip(&self) -> Option<Ipv4Addr>;
+    /// True when no known AP has been found for a while, the portal can be offered then.
+    fn wants_provisioning(&self) -> bool;
+}
+
+/// Finds a collector on the LAN, as host:port.
+pub trait Discovery: Send {
+    fn find(&mut self) -> Option<String>;
+}
+
+/// Byte stream to a server, plain TCP or TLS.
+pub trait Connection: Read + Write + Send {
+    /// A read that times out fails with WouldBlock or TimedOut.
+    fn set_read_timeout(&mut self, timeout: Duration) -> std::io::Result<()>;
+}
+
+/// Opens connections to `addr`; `host` is the name without the port, for TLS.
+pub trait Connector: Send {
+    fn connect(&mut self, host: &str, addr: SocketAddr, timeout: Duration) -> Result<Box<dyn Connection>>;
+}
+
+/// Persistent string settings.
+pub trait KeyValueStore {
+    fn get(&self, key: &str) -> Result<Option<String>>;
+    fn set(&mut self, key: &str, value: &str) -> Result<()>;
+}
@@ -0,0 +1,451 @@
+// Host implementations of the hardware abstraction, for running the core on a PC.
+#![allow(clippy::redundant_field_names)]
+
+use log::*;
+use std::{sync::Arc, sync::Mutex};
+use std::collections::{VecDeque, HashMap};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::{fs, path::Path, convert::Infallible};
+use std::net::Ipv4Addr;
+use anyhow::Result;
+use embedded_graphics::{pixelcolor::Rgb565, prelude::*};
+
+use crate::hal::{Measurement, Sensor, Clock, DisplaySink, Buttons, BatteryAdc, Uplink, StatusLeds, PowerControl, Network,
+                KeyValueStore, Discovery, FeedSender};
+use crate::currentlogs::CurrentLog;
+use crate::displayctl::{LoggingStatus, WifiStatus};
+use crate::pushswitch::PushEvent;
+use crate::bignumber::Quantity;
+use crate::battery::BatteryState;
+
+/// Clock that only moves when it is advanced or slept on.
+#[derive(Clone)]
+pub struct ManualClock {
+    now: Arc<AtomicU64>,
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        ManualClock::new()
+    }
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        ManualClock { now: Arc::new(AtomicU64::new(0)) }
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.now.fetch_add(ms, Ordering::Relaxed);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.now.load(Ordering::Relaxed)
+    }
+
+    fn sleep_ms(&self, ms: u64) {
+        self.advance(ms);
+    }
+
+    fn unix_ms(&self) -> Option<u64> {
+        None
+    }
+}
+
+/// Sensor returning a fixed measurement.
+pub struct ConstantSensor {
+    pub value: Measurement,
+}
+
+impl Sensor for ConstantSensor {
+    fn read(&mut self) -> Result<Measurement> {
+        Ok(self.value)
+    }
+
+    fn reset_accumulators(&mut self) -> Result<()> {
+        self.value.energy = 0.0;
+        self.value.charge = 0.0;
+        Ok(())
+    }
+}
+
+/// Battery returning a fixed voltage.
+pub struct FixedBattery {
+    pub voltage: f32,
+}
+
+impl BatteryAdc for FixedBattery {
+    fn read_voltage(&mut self) -> Result<f32> {
+        Ok(self.voltage)
+    }
+}
+
+/// Button events fed from a queue.
+#[derive(Clone)]
+pub struct ScriptedButtons {
+    events: Arc<Mutex<VecDeque<PushEvent>>>,
+}
+
+impl Default for ScriptedButtons {
+    fn default() -> Self {
+        ScriptedButtons::new()
+    }
+}
+
+impl ScriptedButtons {
+    pub fn new() -> Self {
+        ScriptedButtons { events: Arc::new(Mutex::new(VecDeque::new())) }
+    }
+
+    pub fn push(&self, ev: PushEvent) {
+        self.events.lock().unwrap().push_back(ev);
+    }
+}
+
+impl Buttons for ScriptedButtons {
+    fn get_event(&mut self) -> Option<PushEvent> {
+        self.events.lock().unwrap().pop_front()
+    }
+}
+
+/// Last values sent to the display.
+#[derive(Debug, Clone)]
+pub struct DisplayState {
+    pub voltage: f32,
+    pub current: f32,
+    pub power: f32,
+    pub energy: f32,
+    pub charge: f32,
+    pub readout: Quantity,
+    pub interval: u32,
+    pub message: Option<String>,
+    pub battery: BatteryState,
+    pub battery_low: bool,
+    pub status: LoggingStatus,
+    pub wifi: WifiStatus,
+    pub buffer_water_mark: u32,
+    pub activity: u32,
+    pub asleep: bool,       // Set by the caller, as the display thread does after the dim time
+    pub blank: bool,
+}
+
+#[derive(Clone)]
+pub struct MemoryDisplay {
+    pub state: Arc<Mutex<DisplayState>>,
+}
+
+impl Default for MemoryDisplay {
+    fn default() -> Self {
+        MemoryDisplay::new()
+    }
+}
+
+impl MemoryDisplay {
+    pub fn new() -> Self {
+        MemoryDisplay { state: Arc::new(Mutex::new(DisplayState {
+            voltage: 0.0, current: 0.0, power: 0.0, energy: 0.0, charge: 0.0,
+            readout: Quantity::Voltage, interval: 0, message: None,
+            battery: BatteryState::new(), battery_low: false, status: LoggingStatus::Stop, wifi: WifiStatus::Disconnected,
+            buffer_water_mark: 0, activity: 0, asleep: false, blank: false })) }
+    }
+}
+
+impl DisplaySink for MemoryDisplay {
+    fn set_voltage(&mut self, vol: f32, cur: f32, power: f32) {
+        let mut lck = self.state.lock().unwrap();
+        lck.voltage = vol;
+        lck.current = cur;
+        lck.power = power;
+        lck.message = None;
+    }
+
+    fn set_accumulated(&mut self, energy: f32, charge: f32) {
+        let mut lck = self.state.lock().unwrap();
+        lck.energy = energy;
+        lck.charge = charge;
+    }
+
+    fn set_readout(&mut self, readout: Quantity) {
+        self.state.lock().unwrap().readout = readout;
+    }
+
+    fn set_interval(&mut self, interval: u32) {
+        self.state.lock().unwrap().interval = interval;
+    }
+
+    fn set_current_status(&mut self, status: LoggingStatus) {
+        self.state.lock().unwrap().status = status;
+    }
+
+    fn set_wifi_status(&mut self, status: WifiStatus) {
+        self.state.lock().unwrap().wifi = status;
+    }
+
+    fn set_err_message(&mut self, msg: String) {
+        self.state.lock().unwrap().message = Some(msg);
+    }
+
+    fn set_battery(&mut self, bat: BatteryState) {
+        self.state.lock().unwrap().battery = bat;
+    }
+
+    fn set_battery_low(&mut self, low: bool) {
+        self.state.lock().unwrap().battery_low = low;
+    }
+
+    fn set_buffer_watermark(&mut self, wm: u32) {
+        self.state.lock().unwrap().buffer_water_mark = wm;
+    }
+
+    fn wake(&mut self) -> bool {
+        let mut lck = self.state.lock().unwrap();
+        lck.activity = lck.activity.wrapping_add(1);
+        let asleep = lck.asleep;
+        lck.asleep = false;
+        asleep
+    }
+
+    fn blank(&mut self) {
+        self.state.lock().unwrap().blank = true;
+    }
+}
+
+/// Uplink that keeps every accepted record, in chunks like Transfer.
+#[derive(Clone)]
+pub struct MemoryUplink {
+    pub sent: Arc<Mutex<Vec<CurrentLog>>>,
+    pub events: Arc<Mutex<Vec<String>>>,
+    pub chunk: usize,
+    pub hold: Arc<AtomicBool>,      // The chunk is not acknowledged while set, like a slow server
+    acked: usize,
+}
+
+impl MemoryUplink {
+    pub fn new(chunk: usize) -> Self {
+        MemoryUplink { sent: Arc::new(Mutex::new(Vec::new())), events: Arc::new(Mutex::new(Vec::new())), chunk: chunk,
+                       hold: Arc::new(AtomicBool::new(false)), acked: 0 }
+    }
+}
+
+impl Uplink for MemoryUplink {
+    fn set_transfer_data(&mut self, data: &[CurrentLog]) -> usize {
+        if self.acked > 0 {
+            return 0;
+        }
+        let count = data.len().min(self.chunk);
+        self.sent.lock().unwrap().extend_from_slice(&data[..count]);
+        self.acked = count;
+        count
+    }
+
+    fn take_acknowledged(&mut self) -> usize {
+        if self.hold.load(Ordering::Relaxed) {
+            return 0;
+        }
+        let acked = self.acked;
+        self.acked = 0;
+        acked
+    }
+
+    fn send_event(&mut self, event: &str, _battery: &BatteryState) -> bool {
+        self.events.lock().unwrap().push(event.to_string());
+        true
+    }
+
+    fn is_idle(&self) -> bool {
+        true
+    }
+}
+
+/// Deep sleep that only records the request.
+#[derive(Clone, Default)]
+pub struct HostPower {
+    pub slept: Arc<AtomicU64>,     // ms of the last deep sleep request
+}
+
+impl PowerControl for HostPower {
+    fn deep_sleep(&mut self, ms: u64) {
+        info!("Deep sleep for {}ms", ms);
+        self.slept.store(ms, Ordering::Relaxed);
+    }
+}
+
+/// Network whose state is set by the caller, connected to the loopback by default.
+#[derive(Clone)]
+pub struct HostNetwork {
+    pub status: Arc<Mutex<WifiStatus>>,
+}
+
+impl HostNetwork {
+    pub fn new(status: WifiStatus) -> Self {
+        HostNetwork { status: Arc::new(Mutex::new(status)) }
+    }
+
+    pub fn set_status(&self, status: WifiStatus) {
+        *self.status.lock().unwrap() = status;
+    }
+}
+
+impl Network for HostNetwork {
+    fn status(&self) -> WifiStatus {
+        *self.status.lock().unwrap()
+    }
+
+    fn rssi(&self) -> Option<i8> {
+        None
+    }
+
+    fn ip(&self) -> Option<Ipv4Addr> {
+        if self.status() == WifiStatus::Connected { Some(Ipv4Addr::LOCALHOST) } else { None }
+    }
+
+    fn wants_provisioning(&self) -> bool {
+        false
+    }
+}
+
+/// Collector "found" at a fixed address, as mDNS would report it.
+pub struct FixedDiscovery {
+    pub server: Option<String>,
+}
+
+impl Discovery for FixedDiscovery {
+    fn find(&mut self) -> Option<String> {
+        self.server.clone()
+    }
+}
+
+/// Live feed client printing the messages a browser would receive.
+pub struct PrintFeed;
+
+impl FeedSender for PrintFeed {
+    fn send_text(&mut self, text: &str) -> Result<()> {
+        println!("live: {}", text);
+        Ok(())
+    }
+}
+
+/// Settings kept in memory.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    pub values: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl KeyValueStore for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.values.lock().unwrap().get(key).cloned())
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.values.lock().unwrap().insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+}
+
+/// LED state kept in memory.
+#[derive(Clone, Default)]
+pub struct MemoryLeds {
+    pub logging: Arc<AtomicBool>,
+    pub measuring: Arc<AtomicBool>,
+}
+
+impl StatusLeds for MemoryLeds {
+    fn set_logging(&mut self, on: bool) -> Result<()> {
+        self.logging.store(on, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn set_measuring(&mut self, on: bool) -> Result<()> {
+        self.measuring.store(on, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+pub const FRAME_WIDTH: u32 = 96;
+pub const FRAME_HEIGHT: u32 = 64;
+
+/// 96x64 Rgb565 frame in memory, with the same size as the SSD1331.
+pub struct Framebuffer {
+    pixels: Vec<Rgb565>,
+}
+
+impl Default for Framebuffer {
+    fn default() -> Self {
+        Framebuffer::new()
+    }
+}
+
+impl Framebuffer {
+    pub fn new() -> Self {
+        Framebuffer { pixels: vec![Rgb565::BLACK; (FRAME_WIDTH * FRAME_HEIGHT) as usize] }
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> Rgb565 {
+        self.pixels[(y * FRAME_WIDTH + x) as usize]
+    }
+
+    /// Writes the frame as a 24-bit BMP file.
+    pub fn save_bmp(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_bmp())?;
+        Ok(())
+    }
+
+    /// The frame as a 24-bit BMP.
+    pub fn to_bmp(&self) -> Vec<u8> {
+        let row_size = (FRAME_WIDTH * 3 + 3) & !3;
+        let image_size = row_size * FRAME_HEIGHT;
+        let mut bmp: Vec<u8> = Vec::with_capacity((54 + image_size) as usize);
+        // BITMAPFILEHEADER
+        bmp.extend_from_slice(b"BM");
+        bmp.extend_from_slice(&(54 + image_size).to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        bmp.extend_from_slice(&54u32.to_le_bytes());
+        // BITMAPINFOHEADER
+        bmp.extend_from_slice(&40u32.to_le_bytes());
+        bmp.extend_from_slice(&(FRAME_WIDTH as i32).to_le_bytes());
+        bmp.extend_from_slice(&(FRAME_HEIGHT as i32).to_le_bytes());
+        bmp.extend_from_slice(&1u16.to_le_bytes());
+        bmp.extend_from_slice(&24u16.to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        bmp.extend_from_slice(&image_size.to_le_bytes());
+        bmp.extend_from_slice(&2835i32.to_le_bytes());
+        bmp.extend_from_slice(&2835i32.to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        bmp.extend_from_slice(&0u32.to_le_bytes());
+        // Rows are stored bottom-up in BGR order.
+        for y in (0..FRAME_HEIGHT).rev() {
+            for x in 0..FRAME_WIDTH {
+                let c = self.pixel(x, y);
+                bmp.push((c.b() << 3) | (c.b() >> 2));
+                bmp.push((c.g() << 2) | (c.g() >> 4));
+                bmp.push((c.r() << 3) | (c.r() >> 2));
+            }
+            bmp.resize(bmp.len() + (row_size - FRAME_WIDTH * 3) as usize, 0);
+        }
+        bmp
+    }
+}
+
+impl OriginDimensions for Framebuffer {
+    fn size(&self) -> Size {
+        Size::new(FRAME_WIDTH, FRAME_HEIGHT)
+    }
+}
+
+impl DrawTarget for Framebuffer {
+    type Color = Rgb565;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+        where I: IntoIterator<Item = Pixel<Self::Color>>
+    {
+        for Pixel(point, color) in pixels {
+            if point.x >= 0 && point.y >= 0 && (point.x as u32) < FRAME_WIDTH && (point.y as u32) < FRAME_HEIGHT {
+                self.pixels[(point.y as u32 * FRAME_WIDTH + point.x as u32) as usize] = color;
+            }
+        }
+        Ok(())
+    }
+}
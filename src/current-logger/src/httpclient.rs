@@ -0,0 +1,450 @@
+// Minimal HTTP/1.1 client for the uploads: one request at a time on a kept-alive connection.
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use log::*;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+use anyhow::{anyhow, bail, Result};
+
+use crate::hal::{Connection, Connector};
+
+const MAX_HEADER_LINE: usize = 1024;
+const MAX_BODY: usize = 4096;       // Longer replies are read and dropped
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        self.status >= 200 && self.status < 300
+    }
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&mut self, timeout: Duration) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, Some(timeout))
+    }
+}
+
+/// Plain TCP.
+pub struct TcpConnector;
+
+impl Connector for TcpConnector {
+    fn connect(&mut self, _host: &str, addr: SocketAddr, timeout: Duration) -> Result<Box<dyn Connection>> {
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(Box::new(stream))
+    }
+}
+
+pub struct HttpClient {
+    host: String,           // Host header, host:port as configured
+    addr: SocketAddr,
+    tls: bool,
+    timeout: Duration,
+    stream: Option<BufReader<Box<dyn Connection>>>,
+}
+
+impl HttpClient {
+    pub fn new(host: &str, addr: SocketAddr, tls: bool, timeout: Duration) -> HttpClient {
+        HttpClient { host: host.to_string(), addr: addr, tls: tls, timeout: timeout, stream: None }
+    }
+
+    /// True when the connection has to be opened with a TLS connector.
+    pub fn is_tls(&self) -> bool {
+        self.tls
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Sends a POST with the extra `headers` and reads the whole response. A kept-alive connection
+    /// that the server closed in the meantime is replaced once before giving up.
+    pub fn post(&mut self, connector: &mut dyn Connector, path: &str, headers: &[(String, String)],
+                content_type: &str, body: &[u8]) -> Result<Response> {
+        let mut head = format!("POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nAccept: */*\r\n\
+                                User-Agent: current-logger\r\nContent-Length: {}\r\n",
+                               path, self.host, content_type, body.len());
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        let mut head = head.into_bytes();
+        head.extend_from_slice(body);
+        let reused = self.stream.is_some();
+        match self.exchange(connector, &head) {
+            Ok(response) => Ok(response),
+            Err(e) if reused => {
+                info!("Reconnecting after {}", e);
+                self.exchange(connector, &head)
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    fn exchange(&mut self, connector: &mut dyn Connector, request: &[u8]) -> Result<Response> {
+        if self.stream.is_none() {
+            let name = self.host.rsplit_once(':').map(|(name, _)| name).unwrap_or(&self.host);
+            self.stream = Some(BufReader::new(connector.connect(name, self.addr, self.timeout)?));
+        }
+        let reader = self.stream.as_mut().unwrap();
+        let result = reader.get_mut().write_all(request).map_err(|e| anyhow!(e))
+            .and_then(|_| read_response(reader));
+        match result {
+            Ok((response, keep_alive)) => {
+                if keep_alive == false {
+                    self.stream = None;
+                }
+                Ok(response)
+            },
+            Err(e) => {
+                self.stream = None;
+                Err(e)
+            },
+        }
+    }
+}
+
+// Reads the status line, the headers and the body. Returns whether the connection stays open.
+fn read_response<R: BufRead>(reader: &mut R) -> Result<(Response, bool)> {
+    let status_line = read_line(reader)?;
+    if status_line.is_empty() {
+        bail!("Connection closed by the server");
+    }
+    let mut parts = status_line.splitn(3, ' ');
+    let version = parts.next().unwrap_or("");
+    let status = parts.next().and_then(|s| s.parse::<u16>().ok())
+        .ok_or(anyhow!("Invalid status line: {}", status_line))?;
+    if version.starts_with("HTTP/1.") == false {
+        bail!("Invalid status line: {}", status_line);
+    }
+    let mut keep_alive = version != "HTTP/1.0";
+    let mut length: Option<usize> = None;
+    let mut chunked = false;
+    loop {
+        let line = read_line(reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim().to_ascii_lowercase()),
+            None => bail!("Invalid header: {}", line),
+        };
+        match name.as_str() {
+            "content-length"    => { length = Some(value.parse().map_err(|_| anyhow!("Invalid header: {}", line))?); },
+            "transfer-encoding" => { chunked = value.contains("chunked"); },
+            "connection"        => { keep_alive = value != "close" && (keep_alive || value == "keep-alive"); },
+            _ => {},
+        }
+    }
+    let mut body = Vec::new();
+    if chunked {
+        loop {
+            let size_line = read_line(reader)?;
+            let size = usize::from_str_radix(size_line.split(';').next().unwrap_or("").trim(), 16)
+                .map_err(|_| anyhow!("Invalid chunk size: {}", size_line))?;
+            if size == 0 {
+                // Trailers up to the empty line
+                while read_line(reader)?.is_empty() == false {}
+                break;
+            }
+            read_body(reader, size, &mut body)?;
+            read_line(reader)?;
+        }
+    }
+    else if let Some(length) = length {
+        read_body(reader, length, &mut body)?;
+    }
+    else if status >= 200 && status != 204 && status != 304 {
+        // The body ends with the connection.
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest)?;
+        rest.truncate(MAX_BODY);
+        body = rest;
+        keep_alive = false;
+    }
+    Ok((Response { status: status, body: body }, keep_alive))
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String> {
+    let mut line = Vec::new();
+    reader.by_ref().take(MAX_HEADER_LINE as u64).read_until(b'\n', &mut line)?;
+    if line.len() == MAX_HEADER_LINE && line.last() != Some(&b'\n') {
+        bail!("Header line too long");
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end_matches(['\r', '\n']).to_string())
+}
+
+// Keeps up to MAX_BODY bytes of the body.
+fn read_body<R: BufRead>(reader: &mut R, length: usize, body: &mut Vec<u8>) -> Result<()> {
+    let mut buf = [0u8; 256];
+    let mut left = length;
+    while left > 0 {
+        let n = reader.read(&mut buf[..left.min(256)])?;
+        if n == 0 {
+            bail!("Connection closed in the body");
+        }
+        if body.len() < MAX_BODY {
+            let keep = n.min(MAX_BODY - body.len());
+            body.extend_from_slice(&buf[..keep]);
+        }
+        left -= n;
+    }
+    Ok(())
+}
+
+/// Parses "Name: value;Name: value". Values may not contain ';'.
+pub fn parse_headers(list: &str) -> Result<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+    for entry in list.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+        let (name, value) = entry.split_once(':').ok_or(anyhow!("Invalid header: {}", entry))?;
+        let name = name.trim();
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(anyhow!("Invalid header: {}", entry));
+        }
+        headers.push((name.to_string(), value.trim().to_string()));
+    }
+    Ok(headers)
+}
+
+/// Parses "bearer:<token>", "token:<token>" (InfluxDB) or "basic:<user>:<password>" into an Authorization
+/// header, empty is none.
+pub fn parse_auth(auth: &str) -> Result<Option<(String, String)>> {
+    let auth = auth.trim();
+    if auth.is_empty() {
+        return Ok(None);
+    }
+    let (scheme, credentials) = auth.split_once(':').ok_or(anyhow!("Invalid authentication: {}", scheme_of(auth)))?;
+    let value = match scheme {
+        "bearer" => format!("Bearer {}", credentials),
+        "token"  => format!("Token {}", credentials),
+        "basic" if credentials.contains(':') => format!("Basic {}", base64(credentials.as_bytes())),
+        _ => return Err(anyhow!("Invalid authentication: {}", scheme_of(auth))),
+    };
+    Ok(Some(("Authorization".to_string(), value)))
+}
+
+// Only the scheme goes into messages, never the secret.
+fn scheme_of(auth: &str) -> &str {
+    auth.split(':').next().unwrap_or("")
+}
+
+fn base64(data: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            }
+            else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    fn response(text: &str) -> Result<(Response, bool)> {
+        read_response(&mut Cursor::new(text.as_bytes()))
+    }
+
+    // A connection that replies with the canned bytes and keeps what is written.
+    struct Canned {
+        reply: Cursor<Vec<u8>>,
+        sent: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Read for Canned {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.reply.read(buf)
+        }
+    }
+
+    impl Write for Canned {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.sent.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Canned {
+        fn set_read_timeout(&mut self, _timeout: Duration) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Every connect takes the next reply.
+    struct CannedConnector {
+        replies: Vec<&'static str>,
+        connects: usize,
+        sent: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl CannedConnector {
+        fn new(replies: Vec<&'static str>) -> CannedConnector {
+            CannedConnector { replies: replies, connects: 0, sent: Arc::new(Mutex::new(Vec::new())) }
+        }
+    }
+
+    impl Connector for CannedConnector {
+        fn connect(&mut self, _host: &str, _addr: SocketAddr, _timeout: Duration) -> Result<Box<dyn Connection>> {
+            let reply = self.replies.get(self.connects).ok_or(anyhow!("Connection refused"))?;
+            self.connects += 1;
+            Ok(Box::new(Canned { reply: Cursor::new(reply.as_bytes().to_vec()), sent: self.sent.clone() }))
+        }
+    }
+
+    fn client() -> HttpClient {
+        HttpClient::new("collector:3001", "127.0.0.1:3001".parse().unwrap(), false, Duration::from_secs(1))
+    }
+
+    #[test]
+    fn content_length() {
+        let mut reader = Cursor::new(b"HTTP/1.1 201 Created\r\nContent-Length: 5\r\n\r\nhelloHTTP/1.1".to_vec());
+        let (r, keep_alive) = read_response(&mut reader).unwrap();
+        assert_eq!(r.status, 201);
+        assert!(r.is_success());
+        assert_eq!(r.body, b"hello");
+        assert!(keep_alive);
+        // The next response is left in the reader.
+        assert_eq!(read_line(&mut reader).unwrap(), "HTTP/1.1");
+    }
+
+    #[test]
+    fn long_body_is_cut() {
+        let text = format!("HTTP/1.1 200 OK\r\nContent-Length: 5000\r\n\r\n{}next", "x".repeat(5000));
+        let mut reader = Cursor::new(text.into_bytes());
+        let (r, _) = read_response(&mut reader).unwrap();
+        assert_eq!(r.body.len(), MAX_BODY);
+        assert_eq!(read_line(&mut reader).unwrap(), "next");
+        assert!(response("HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort").is_err());
+    }
+
+    #[test]
+    fn chunked_with_trailers() {
+        let mut reader = Cursor::new(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                                       5;name=value\r\nhello\r\n6\r\n world\r\n0\r\n\
+                                       X-Checksum: 1234\r\nX-Other: 5\r\n\r\nnext".to_vec());
+        let (r, keep_alive) = read_response(&mut reader).unwrap();
+        assert_eq!(r.body, b"hello world");
+        assert!(keep_alive);
+        assert_eq!(read_line(&mut reader).unwrap(), "next");
+        assert!(response("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nxyz\r\n").is_err());
+    }
+
+    #[test]
+    fn read_to_close() {
+        let (r, keep_alive) = response("HTTP/1.1 200 OK\r\n\r\nuntil the end").unwrap();
+        assert_eq!(r.body, b"until the end");
+        assert!(keep_alive == false);
+        // No body without a length.
+        let (r, keep_alive) = response("HTTP/1.1 204 No Content\r\n\r\n").unwrap();
+        assert!(r.body.is_empty());
+        assert!(keep_alive);
+    }
+
+    #[test]
+    fn connection_header() {
+        let keep_alive = |text: &str| response(text).unwrap().1;
+        assert!(keep_alive("HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n") == false);
+        assert!(keep_alive("HTTP/1.1 200 OK\r\nconnection: Keep-Alive\r\nContent-Length: 0\r\n\r\n"));
+        assert!(keep_alive("HTTP/1.0 200 OK\r\nContent-Length: 0\r\n\r\n") == false);
+        assert!(keep_alive("HTTP/1.0 200 OK\r\nConnection: keep-alive\r\nContent-Length: 0\r\n\r\n"));
+    }
+
+    #[test]
+    fn invalid_responses() {
+        let long = format!("HTTP/1.1 200 OK\r\nX-Long: {}\r\n\r\n", "a".repeat(MAX_HEADER_LINE));
+        assert_eq!(response(&long).unwrap_err().to_string(), "Header line too long");
+        assert_eq!(response("").unwrap_err().to_string(), "Connection closed by the server");
+        assert!(response("SMTP 220 ready\r\n\r\n").is_err());
+        assert!(response("HTTP/1.1 OK\r\n\r\n").is_err());
+        assert!(response("HTTP/1.1 200 OK\r\nNo colon\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn error_status() {
+        let (r, keep_alive) = response("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 4\r\n\r\nbusy").unwrap();
+        assert_eq!(r.status, 503);
+        assert!(r.is_success() == false);
+        assert_eq!(r.body, b"busy");
+        assert!(keep_alive);
+    }
+
+    #[test]
+    fn post_keeps_the_connection() {
+        let mut connector = CannedConnector::new(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\nHTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 204 No Content\r\n\r\n"]);
+        let mut http = client();
+        let headers = vec![("Authorization".to_string(), "Token abc".to_string())];
+        for _ in 0..3 {
+            assert!(http.post(&mut connector, "/upload", &headers, "application/json", b"[ ]").unwrap().is_success());
+        }
+        // The second reply closed the first connection.
+        assert_eq!(connector.connects, 2);
+        let sent = String::from_utf8(connector.sent.lock().unwrap().clone()).unwrap();
+        assert!(sent.starts_with("POST /upload HTTP/1.1\r\nHost: collector:3001\r\nContent-Type: application/json\r\n"));
+        assert!(sent.contains("Content-Length: 3\r\nAuthorization: Token abc\r\n\r\n[ ]POST"));
+    }
+
+    #[test]
+    fn post_reconnects_once() {
+        // The server closed the kept-alive connection after the first reply.
+        let mut connector = CannedConnector::new(vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"]);
+        let mut http = client();
+        http.post(&mut connector, "/", &[], "text/plain", b"1").unwrap();
+        http.post(&mut connector, "/", &[], "text/plain", b"2").unwrap();
+        assert_eq!(connector.connects, 2);
+        // A new connection is not retried.
+        assert!(http.post(&mut connector, "/", &[], "text/plain", b"3").is_err());
+        assert!(http.post(&mut connector, "/", &[], "text/plain", b"4").is_err());
+    }
+
+    #[test]
+    fn base64_vectors() {
+        // RFC 4648
+        for (data, encoded) in [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="),
+                                ("fooba", "Zm9vYmE="), ("foobar", "Zm9vYmFy")] {
+            assert_eq!(base64(data.as_bytes()), encoded);
+        }
+        assert_eq!(parse_auth("basic:user:pass").unwrap(),
+                   Some(("Authorization".to_string(), "Basic dXNlcjpwYXNz".to_string())));
+    }
+
+    #[test]
+    fn auth_and_headers() {
+        assert_eq!(parse_auth("").unwrap(), None);
+        assert_eq!(parse_auth("token:abc").unwrap().unwrap().1, "Token abc");
+        assert_eq!(parse_auth("bearer:xyz").unwrap().unwrap().1, "Bearer xyz");
+        // The secret stays out of the message.
+        assert_eq!(parse_auth("digest:secret").unwrap_err().to_string(), "Invalid authentication: digest");
+        assert!(parse_auth("basic:nopassword").is_err());
+        assert_eq!(parse_headers("X-A: 1; X-B:two ;").unwrap(),
+                   vec![("X-A".to_string(), "1".to_string()), ("X-B".to_string(), "two".to_string())]);
+        assert!(parse_headers("Bad Name: 1").is_err());
+    }
+}
@@ -20,4 +20,8 @@ convert c.bmp -type grayscale -colors 2 c.bmp
 convert dot.bmp -type grayscale -colors 2 dot.bmp
 convert minus.bmp -type grayscale -colors 2 minus.bmp
 convert usb-power.bmp -type palette -colors 8 usb-power.bmp
-convert v.bmp -type palette -colors 8 v.bmp
\ No newline at end of file
+convert v.bmp -type palette -colors 8 v.bmp
+convert a.bmp -type palette -colors 8 a.bmp
+convert w.bmp -type palette -colors 8 w.bmp
+convert wh.bmp -type palette -colors 8 wh.bmp
+convert ah.bmp -type palette -colors 8 ah.bmp
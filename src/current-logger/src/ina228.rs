@@ -0,0 +1,117 @@
+#![allow(clippy::redundant_field_names)]
+
+use log::*;
+use anyhow::Result;
+
+use crate::hal::{I2cBus, Measurement, Sensor};
+
+pub const INA228_ADDR: u8 = 0x40;
+
+pub const REG_CONFIG: u8    = 0x00;
+pub const REG_SHUNT_CAL: u8 = 0x02;
+pub const REG_VBUS: u8      = 0x05;
+pub const REG_CURRENT: u8   = 0x07;
+pub const REG_POWER: u8     = 0x08;
+pub const REG_ENERGY: u8    = 0x09;
+pub const REG_CHARGE: u8    = 0x0A;
+
+pub const CONFIG_RSTACC: u16 = 0x4000;  // Reset ENERGY and CHARGE
+
+pub const VBUS_LSB: f32 = 193.3125 / 1_000_000.0;  // V
+pub const MAX_CURRENT: f32 = 16.384;              // A
+
+pub struct Ina228<B: I2cBus> {
+    bus: B,
+    addr: u8,
+    current_lsb: f32,
+}
+
+impl<B: I2cBus> Ina228<B> {
+    pub fn new(bus: B, addr: u8) -> Self {
+        Ina228 { bus: bus, addr: addr, current_lsb: MAX_CURRENT / 524_288.0 }
+    }
+
+    // SHUNT_CAL
+    pub fn init(&mut self, shunt_resistor: f32) -> Result<()>
+    {
+        let shunt_cal_val = 13107.2 * self.current_lsb * 1_000_000.0 * shunt_resistor;
+        let shunt_cal = shunt_cal_val as u32;
+        info!("current_lsb={:?} shunt_cal_val={:?}", self.current_lsb, shunt_cal_val);
+        self.bus.write(self.addr, &[REG_SHUNT_CAL, (shunt_cal >> 8) as u8, (shunt_cal & 0xFF) as u8])
+    }
+
+    pub fn reset_accumulators(&mut self) -> Result<()>
+    {
+        self.bus.write(self.addr, &[REG_CONFIG, (CONFIG_RSTACC >> 8) as u8, (CONFIG_RSTACC & 0xFF) as u8])
+    }
+
+    fn read_reg40(&mut self, reg: u8) -> Result<u64>
+    {
+        let mut buf = [0u8; 5];
+        self.bus.write(self.addr, &[reg; 1])?;
+        self.bus.read(self.addr, &mut buf)?;
+        Ok(buf.iter().fold(0u64, |v, b| v << 8 | *b as u64))
+    }
+
+    fn read_reg24(&mut self, reg: u8) -> Result<u32>
+    {
+        let mut buf = [0u8; 3];
+        self.bus.write(self.addr, &[reg; 1])?;
+        self.bus.read(self.addr, &mut buf)?;
+        Ok((buf[0] as u32) << 16 | (buf[1] as u32) << 8 | (buf[2] as u32))
+    }
+
+    pub fn read_voltage(&mut self) -> Result<f32>
+    {
+        let vbus_reg = self.read_reg24(REG_VBUS)? >> 4;
+        Ok(vbus_reg as f32 * VBUS_LSB)   // V
+    }
+
+    pub fn read_current(&mut self) -> Result<f32>
+    {
+        let raw = self.read_reg24(REG_CURRENT)?;
+        let current_reg = if raw & 0x800000 == 0x800000 {
+            -((0x100000 - (raw >> 4)) as f32)
+        }
+        else {
+            (raw >> 4) as f32
+        };
+        Ok(self.current_lsb * current_reg)  // A
+    }
+
+    pub fn read_power(&mut self) -> Result<f32>
+    {
+        let power_reg = self.read_reg24(REG_POWER)? as f32;
+        Ok(3.2 * self.current_lsb * power_reg)  // W
+    }
+
+    pub fn read_energy(&mut self) -> Result<f32>
+    {
+        let energy_reg = self.read_reg40(REG_ENERGY)? as f32;
+        Ok(16.0 * 3.2 * self.current_lsb * energy_reg / 3600.0)  // Wh
+    }
+
+    pub fn read_charge(&mut self) -> Result<f32>
+    {
+        let raw = self.read_reg40(REG_CHARGE)?;
+        // 40-bit two's complement
+        let charge_reg = ((raw << 24) as i64 >> 24) as f32;
+        Ok(self.current_lsb * charge_reg / 3600.0)  // Ah
+    }
+}
+
+impl<B: I2cBus> Sensor for Ina228<B> {
+    fn read(&mut self) -> Result<Measurement> {
+        Ok(Measurement {
+            voltage: self.read_voltage()?,
+            current: self.read_current()?,
+            power: self.read_power()?,
+            energy: self.read_energy()?,
+            charge: self.read_charge()?,
+        })
+    }
+
+    fn reset_accumulators(&mut self) -> Result<()> {
+        Ina228::reset_accumulators(self)
+    }
+}
@@ -0,0 +1,43 @@
+// Measurement core of the current logger.
+// The firmware is src/main.rs. With the std feature the core also builds on a Linux host.
+
+pub mod hal;
+pub mod ina228;
+pub mod currentlogs;
+pub mod displayctl;
+pub mod bignumber;
+pub mod battery;
+pub mod pushswitch;
+pub mod httpclient;
+pub mod lineprotocol;
+pub mod mqttclient;
+pub mod mqtt;
+pub mod udpstream;
+pub mod tcpstream;
+pub mod livefeed;
+pub mod tlspin;
+pub mod transfer;
+pub mod logger;
+pub mod dutycycle;
+pub mod wifilist;
+pub mod netconfig;
+pub mod settings;
+pub mod provision;
+#[cfg(feature = "native")]
+pub mod esphal;
+#[cfg(feature = "native")]
+pub mod wifi;
+#[cfg(feature = "native")]
+pub mod portal;
+#[cfg(feature = "native")]
+pub mod mdns;
+#[cfg(feature = "native")]
+pub mod webfeed;
+#[cfg(feature = "std")]
+pub mod hosthal;
+#[cfg(feature = "std")]
+pub mod sim;
+#[cfg(feature = "std")]
+pub mod screens;
+#[cfg(feature = "std")]
+pub mod mqttbroker;
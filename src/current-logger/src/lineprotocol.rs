@@ -0,0 +1,186 @@
+// InfluxDB v2 line protocol, written straight to /api/v2/write without the agent.
+#![allow(clippy::bool_comparison)]
+
+use anyhow::{anyhow, Result};
+
+use crate::currentlogs::CurrentLog;
+use crate::battery::BatteryState;
+
+pub const DEFAULT_MEASUREMENT: &str = "currentlogger";
+pub const DEFAULT_TAG_KEY: &str = "tag";
+pub const CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+// Fields of a record as the agent names them.
+const FIELDS: [&str; 7] = ["current", "voltage", "power", "bat", "bat_level", "bat_runtime", "bat_source"];
+
+/// Names of the points. The agent's names are the defaults, so the dashboards keep working.
+#[derive(Debug, Clone)]
+pub struct PointFormat {
+    pub measurement: String,
+    pub tag_key: String,        // The device name is its value
+    fields: Vec<String>,        // Written names in the order of FIELDS, empty leaves the field out
+}
+
+impl Default for PointFormat {
+    fn default() -> Self {
+        PointFormat::new()
+    }
+}
+
+impl PointFormat {
+    pub fn new() -> PointFormat {
+        PointFormat { measurement: DEFAULT_MEASUREMENT.to_string(),
+                      tag_key: DEFAULT_TAG_KEY.to_string(),
+                      fields: FIELDS.iter().map(|f| f.to_string()).collect() }
+    }
+
+    /// Renames the fields by "field=name;..", e.g. "current=I;bat_source=". Empty names are left out.
+    pub fn parse(measurement: &str, tag_key: &str, fields: &str) -> Result<PointFormat> {
+        let mut format = PointFormat::new();
+        if measurement.is_empty() == false {
+            format.measurement = measurement.to_string();
+        }
+        if tag_key.is_empty() == false {
+            format.tag_key = tag_key.to_string();
+        }
+        for entry in fields.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let (field, name) = entry.split_once('=').ok_or(anyhow!("Invalid field name: {}", entry))?;
+            let index = FIELDS.iter().position(|f| *f == field.trim()).ok_or(anyhow!("Unknown field: {}", field))?;
+            format.fields[index] = name.trim().to_string();
+        }
+        if format.fields.iter().all(|name| name.is_empty()) {
+            return Err(anyhow!("No fields left to write"));
+        }
+        Ok(format)
+    }
+
+    /// Formats the records up to `max` or up to the first one without a wall clock time, with ms timestamps.
+    /// Returns the lines and the record count.
+    pub fn format_records(&self, data: &[CurrentLog], tag: &str, max: usize) -> (String, usize) {
+        let mut body = String::new();
+        let mut count = 0;
+        for it in data.iter().take(max) {
+            if it.has_time() == false {
+                break;
+            }
+            let values = [format!("{:.5}", it.current),
+                          format!("{:.5}", it.voltage),
+                          format!("{:.5}", it.power),
+                          format!("{:.2}", it.battery.voltage),
+                          format!("{}i", it.battery.percent),
+                          format!("{}i", it.battery.runtime),
+                          string_value(it.battery.source.name())];
+            body.push_str(&format!("{} {} {}\n", self.series(tag), self.field_set(&values), it.time));
+            count += 1;
+        }
+        (body, count)
+    }
+
+    /// Formats an event without a timestamp, InfluxDB stamps it with its own time.
+    pub fn format_event(&self, event: &str, battery: &BatteryState, tag: &str) -> String {
+        let mut fields = vec![format!("event={}", string_value(event))];
+        for (index, value) in [(3, format!("{:.2}", battery.voltage)), (4, format!("{}i", battery.percent))] {
+            if self.fields[index].is_empty() == false {
+                fields.push(format!("{}={}", escape_key(&self.fields[index]), value));
+            }
+        }
+        format!("{} {}\n", self.series(tag), fields.join(","))
+    }
+
+    fn series(&self, tag: &str) -> String {
+        let measurement = self.measurement.replace(',', "\\,").replace(' ', "\\ ");
+        format!("{},{}={}", measurement, escape_key(&self.tag_key), escape_key(tag))
+    }
+
+    fn field_set(&self, values: &[String]) -> String {
+        self.fields.iter().zip(values)
+            .filter(|(name, _)| !name.is_empty())
+            .map(|(name, value)| format!("{}={}", escape_key(name), value))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Path of the v2 write API for `org` and `bucket` with ms timestamps.
+pub fn write_path(org: &str, bucket: &str) -> String {
+    format!("/api/v2/write?org={}&bucket={}&precision=ms", url_encode(org), url_encode(bucket))
+}
+
+// Tag keys, tag values and field keys escape commas, equal signs and spaces.
+fn escape_key(s: &str) -> String {
+    s.replace('\\', "\\\\").replace(',', "\\,").replace('=', "\\=").replace(' ', "\\ ")
+}
+
+fn string_value(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn url_encode(s: &str) -> String {
+    s.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+        _ => format!("%{:02X}", b),
+    }).collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::WALL_CLOCK_SET_MS;
+
+    fn record(time: u64) -> CurrentLog {
+        let mut data = CurrentLog::default();
+        data.voltage = 5.0;
+        data.current = 0.001;
+        data.power = 0.005;
+        data.time = time;
+        data
+    }
+
+    #[test]
+    fn default_names() {
+        let (body, count) = PointFormat::new().format_records(&[record(WALL_CLOCK_SET_MS)], "ch1", 64);
+        assert_eq!(count, 1);
+        assert_eq!(body, format!("currentlogger,tag=ch1 current=0.00100,voltage=5.00000,power=0.00500,bat=0.00,\
+                                  bat_level=0i,bat_runtime=0i,bat_source=\"battery\" {}\n", WALL_CLOCK_SET_MS));
+    }
+
+    #[test]
+    fn names_are_escaped() {
+        let point = PointFormat::parse("power meter,lab", "dev id", "current=I (A);bat_source=;bat=v,bat").unwrap();
+        let (body, _) = point.format_records(&[record(WALL_CLOCK_SET_MS + 1)], "bench=1", 64);
+        assert_eq!(body, format!("power\\ meter\\,lab,dev\\ id=bench\\=1 I\\ (A)=0.00100,voltage=5.00000,power=0.00500,\
+                                  v\\,bat=0.00,bat_level=0i,bat_runtime=0i {}\n", WALL_CLOCK_SET_MS + 1));
+        // The equal sign needs no escape in the measurement.
+        assert!(PointFormat::parse("a=b", "", "").unwrap().series("x").starts_with("a=b,tag=x"));
+        assert_eq!(escape_key("back\\slash"), "back\\\\slash");
+    }
+
+    #[test]
+    fn event_is_a_string_field() {
+        let line = PointFormat::new().format_event("start \"bench\"", &BatteryState::new(), "ch 1");
+        assert_eq!(line, "currentlogger,tag=ch\\ 1 event=\"start \\\"bench\\\"\",bat=0.00,bat_level=0i\n");
+        let point = PointFormat::parse("", "", "bat=;bat_level=").unwrap();
+        assert_eq!(point.format_event("stop", &BatteryState::new(), "ch1"), "currentlogger,tag=ch1 event=\"stop\"\n");
+    }
+
+    #[test]
+    fn records_without_time_wait() {
+        let data = [record(WALL_CLOCK_SET_MS), record(WALL_CLOCK_SET_MS + 1), record(10), record(WALL_CLOCK_SET_MS + 3)];
+        assert_eq!(PointFormat::new().format_records(&data, "ch1", 64).1, 2);
+        assert_eq!(PointFormat::new().format_records(&data, "ch1", 1).1, 1);
+        assert_eq!(PointFormat::new().format_records(&data[2..], "ch1", 64), ("".to_string(), 0));
+    }
+
+    #[test]
+    fn invalid_fields() {
+        assert!(PointFormat::parse("", "", "amps=I").is_err());
+        assert!(PointFormat::parse("", "", "current").is_err());
+        assert!(PointFormat::parse("", "", "current=;voltage=;power=;bat=;bat_level=;bat_runtime=;bat_source=").is_err());
+    }
+
+    #[test]
+    fn path_is_encoded() {
+        assert_eq!(write_path("my org", "lab/bench"), "/api/v2/write?org=my%20org&bucket=lab%2Fbench&precision=ms");
+    }
+}
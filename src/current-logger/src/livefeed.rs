@@ -0,0 +1,273 @@
+// Live feed for a browser: every record and what the display shows, sent as JSON to the connected clients.
+//
+// One message every SEND_MS, when there is something new:
+//   { "status": { "interval": 5, "logging": true, "watermark": 3, "wifi": "connected",
+//                 "voltage": 5.0, "current": 0.1, "power": 0.5,
+//                 "battery": { "voltage": 4.01, "percent": 85, "source": "battery", "runtime": 318, "low": false } },
+//     "records": [ { "clock": 120, "time": 1700000000000, "voltage": 5.0, "current": 0.1, "power": 0.5 }, .. ] }
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use log::*;
+use std::collections::VecDeque;
+use std::mem;
+use std::{thread, time::Duration, sync::Arc, sync::Mutex};
+
+use crate::currentlogs::CurrentLog;
+use crate::battery::BatteryState;
+use crate::displayctl::WifiStatus;
+use crate::hal::{LiveFeed, FeedSender};
+
+const SEND_MS: u64 = 100;
+const MAX_RECORDS: usize = 512;     // Waiting for the next message, older ones are dropped
+const MAX_CLIENTS: usize = 4;
+
+/// What the display shows.
+#[derive(Debug, Clone, Copy)]
+pub struct LiveStatus {
+    pub interval: u32,      // ms
+    pub logging: bool,
+    pub watermark: u32,     // % of the buffer in use
+    pub wifi: WifiStatus,
+    pub voltage: f32,
+    pub current: f32,
+    pub power: f32,
+    pub battery: BatteryState,
+    pub battery_low: bool,
+}
+
+struct LiveData {
+    clients: Vec<Box<dyn FeedSender>>,
+    records: VecDeque<CurrentLog>,
+    status: Option<LiveStatus>,     // Taken when it is sent
+    sending: bool,                  // The clients are out of `clients` while a message is sent
+}
+
+/// Sends the feed to the clients from its own thread. Clones share the clients.
+#[derive(Clone)]
+pub struct LiveBroadcast {
+    data: Arc<Mutex<LiveData>>,
+}
+
+impl Default for LiveBroadcast {
+    fn default() -> Self {
+        LiveBroadcast::new()
+    }
+}
+
+impl LiveBroadcast {
+    pub fn new() -> Self {
+        LiveBroadcast { data: Arc::new(Mutex::new(
+            LiveData { clients: Vec::new(), records: VecDeque::new(), status: None, sending: false })) }
+    }
+
+    /// Adds a client, the oldest one is closed when there are too many.
+    pub fn add_client(&self, client: Box<dyn FeedSender>)
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.clients.len() >= MAX_CLIENTS {
+            info!("Too many live feed clients, dropped the oldest");
+            lck.clients.remove(0);
+        }
+        lck.clients.push(client);
+        info!("Live feed clients {}", lck.clients.len());
+    }
+
+    pub fn start(&self)
+    {
+        let data = self.data.clone();
+        let _th = thread::spawn(move || {
+            info!("Start live feed thread.");
+            loop {
+                thread::sleep(Duration::from_millis(SEND_MS));
+                let mut lck = data.lock().unwrap();
+                if lck.clients.is_empty() || (lck.status.is_none() && lck.records.is_empty()) {
+                    continue;
+                }
+                let status = lck.status.take();
+                let records = mem::take(&mut lck.records);
+                let mut clients = mem::take(&mut lck.clients);
+                lck.sending = true;
+                drop(lck);
+                // Sent without the lock, a slow client must not hold up the logger.
+                // A client whose connection is gone is dropped.
+                let message = Self::message(status, records.into_iter());
+                clients.retain_mut(|client| match client.send_text(&message) {
+                    Ok(()) => true,
+                    Err(e) => { info!("Live feed client closed: {}", e); false },
+                });
+                let mut lck = data.lock().unwrap();
+                // The clients added meanwhile are the newest.
+                clients.append(&mut lck.clients);
+                let excess = clients.len().saturating_sub(MAX_CLIENTS);
+                if excess > 0 {
+                    info!("Too many live feed clients, dropped the oldest");
+                    clients.drain(..excess);
+                }
+                lck.clients = clients;
+                lck.sending = false;
+            }
+        });
+    }
+
+    fn message(status: Option<LiveStatus>, records: impl Iterator<Item = CurrentLog>) -> String
+    {
+        let mut message = "{ ".to_string();
+        if let Some(s) = status {
+            message.push_str(&format!("\"status\": {{ \"interval\": {}, \"logging\": {}, \"watermark\": {}, \"wifi\": \"{}\", \
+                                       \"voltage\": {:.5}, \"current\": {:.5}, \"power\": {:.5}, \
+                                       \"battery\": {{ \"voltage\": {:.2}, \"percent\": {}, \"source\": \"{}\", \"runtime\": {}, \"low\": {} }} }}, ",
+                                      s.interval, s.logging, s.watermark, format!("{:?}", s.wifi).to_lowercase(),
+                                      s.voltage, s.current, s.power,
+                                      s.battery.voltage, s.battery.percent, s.battery.source.name(), s.battery.runtime, s.battery_low));
+        }
+        let records: Vec<String> = records.map(|it| format!("{{ \"clock\": {}, \"time\": {}, \"voltage\": {:.5}, \"current\": {:.5}, \"power\": {:.5} }}",
+                                                             it.clock, it.time, it.voltage, it.current, it.power)).collect();
+        message.push_str(&format!("\"records\": [{}] }}", records.join(", ")));
+        message
+    }
+}
+
+impl LiveFeed for LiveBroadcast {
+    fn record(&mut self, data: &CurrentLog)
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.clients.is_empty() && lck.sending == false {
+            return;
+        }
+        if lck.records.len() >= MAX_RECORDS {
+            lck.records.pop_front();
+        }
+        lck.records.push_back(*data);
+    }
+
+    fn status(&mut self, status: &LiveStatus)
+    {
+        self.data.lock().unwrap().status = Some(*status);
+    }
+}
+
+/// The page of the live view. It connects to /ws and shows the status and a chart of the current.
+pub const LIVE_PAGE: &str = "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+<title>Current Logger</title><style>body{font-family:sans-serif}td{padding:2px 12px}canvas{border:1px solid #888;width:100%}</style>\
+</head><body><h2>Current Logger</h2>\
+<table><tr><td>Voltage</td><td id=\"voltage\">-</td><td>Interval</td><td id=\"interval\">-</td></tr>\
+<tr><td>Current</td><td id=\"current\">-</td><td>Logging</td><td id=\"logging\">-</td></tr>\
+<tr><td>Power</td><td id=\"power\">-</td><td>Buffer</td><td id=\"watermark\">-</td></tr>\
+<tr><td>Battery</td><td id=\"battery\">-</td><td>WiFi</td><td id=\"wifi\">-</td></tr></table>\
+<p id=\"conn\">Connecting..</p><canvas id=\"chart\" width=\"600\" height=\"200\"></canvas>\
+<script>\
+const N=600,pts=[],c=document.getElementById('chart').getContext('2d');\
+const set=(id,v)=>document.getElementById(id).textContent=v;\
+function draw(){c.clearRect(0,0,600,200);if(pts.length<2)return;\
+let lo=Math.min(...pts),hi=Math.max(...pts);if(hi-lo<1e-6){hi+=5e-7;lo-=5e-7}\
+c.beginPath();pts.forEach((v,i)=>{let y=195-(v-lo)/(hi-lo)*190;i?c.lineTo(i,y):c.moveTo(i,y)});c.stroke();\
+c.fillText((hi*1000).toFixed(3)+'mA',2,10);c.fillText((lo*1000).toFixed(3)+'mA',2,198)}\
+function connect(){const ws=new WebSocket('ws://'+location.host+'/ws');\
+ws.onopen=()=>set('conn','Connected');\
+ws.onclose=()=>{set('conn','Disconnected, retrying..');setTimeout(connect,2000)};\
+ws.onmessage=e=>{const m=JSON.parse(e.data),s=m.status;\
+if(s){set('voltage',s.voltage.toFixed(4)+' V');set('current',(s.current*1000).toFixed(3)+' mA');\
+set('power',(s.power*1000).toFixed(3)+' mW');set('interval',s.interval+' ms');set('logging',s.logging?'logging':'stopped');\
+set('watermark',s.watermark+' %');set('wifi',s.wifi);\
+set('battery',s.battery.voltage.toFixed(2)+' V '+s.battery.percent+' % '+s.battery.source+(s.battery.low?' LOW':''))}\
+for(const r of m.records){pts.push(r.current);if(pts.length>N)pts.shift()}if(m.records.length)draw()}}\
+connect();</script></body></html>";
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use anyhow::{bail, Result};
+
+    // Keeps the messages, taking `delay` for each, or fails from the first one.
+    struct TestClient {
+        sent: Arc<Mutex<Vec<String>>>,
+        delay: Duration,
+        closed: bool,
+    }
+
+    impl FeedSender for TestClient {
+        fn send_text(&mut self, text: &str) -> Result<()> {
+            thread::sleep(self.delay);
+            self.sent.lock().unwrap().push(text.to_string());
+            if self.closed {
+                bail!("closed");
+            }
+            Ok(())
+        }
+    }
+
+    fn client(delay: u64, closed: bool) -> (Box<dyn FeedSender>, Arc<Mutex<Vec<String>>>) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        (Box::new(TestClient { sent: sent.clone(), delay: Duration::from_millis(delay), closed: closed }), sent)
+    }
+
+    fn status() -> LiveStatus {
+        LiveStatus { interval: 100, logging: true, watermark: 3, wifi: WifiStatus::Connected, voltage: 5.0,
+                     current: 0.1, power: 0.5, battery: BatteryState::new(), battery_low: false }
+    }
+
+    fn record(clock: u32) -> CurrentLog {
+        let mut data = CurrentLog::default();
+        data.clock = clock;
+        data
+    }
+
+    #[test]
+    fn slow_client_does_not_hold_the_logger() {
+        let mut live = LiveBroadcast::new();
+        let (slow, slow_sent) = client(400, false);
+        live.add_client(slow);
+        live.start();
+        live.status(&status());
+        // The message is on its way to the slow client now.
+        thread::sleep(Duration::from_millis(SEND_MS + 150));
+        let start = Instant::now();
+        live.record(&record(7));
+        live.status(&status());
+        assert!(start.elapsed() < Duration::from_millis(100), "{:?}", start.elapsed());
+        let (added, added_sent) = client(0, false);
+        live.add_client(added);
+        thread::sleep(Duration::from_millis(1000));
+        // Both get what came during the send, the added client is kept with the slow one.
+        let slow_sent = slow_sent.lock().unwrap();
+        assert_eq!(slow_sent.len(), 2);
+        assert!(slow_sent[0].contains("\"records\": [] }"));
+        assert!(slow_sent[1].contains("\"clock\": 7,"));
+        assert_eq!(*added_sent.lock().unwrap(), vec![slow_sent[1].clone()]);
+    }
+
+    #[test]
+    fn closed_client_is_dropped() {
+        let mut live = LiveBroadcast::new();
+        let (closed, closed_sent) = client(0, true);
+        let (open, open_sent) = client(0, false);
+        live.add_client(closed);
+        live.add_client(open);
+        live.start();
+        for clock in 0..3 {
+            live.record(&record(clock));
+            thread::sleep(Duration::from_millis(SEND_MS * 2));
+        }
+        assert_eq!(closed_sent.lock().unwrap().len(), 1);
+        assert_eq!(open_sent.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn oldest_clients_go_first() {
+        let live = LiveBroadcast::new();
+        let sent: Vec<_> = (0..MAX_CLIENTS + 1).map(|_| {
+            let (c, sent) = client(0, false);
+            live.add_client(c);
+            sent
+        }).collect();
+        let mut feed = live.clone();
+        live.start();
+        feed.record(&record(1));
+        thread::sleep(Duration::from_millis(SEND_MS * 3));
+        assert!(sent[0].lock().unwrap().is_empty());
+        assert!(sent[1..].iter().all(|s| s.lock().unwrap().len() == 1));
+    }
+}
+
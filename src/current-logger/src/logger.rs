@@ -0,0 +1,475 @@
+// Measurement core: button handling, sampling and buffering of the logs.
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use log::*;
+use anyhow::{bail, Result};
+
+use crate::hal::{Sensor, Clock, DisplaySink, Buttons, BatteryAdc, Uplink, StatusLeds, PowerControl, Network,
+                KeyValueStore, RemoteCommand, LiveFeed};
+use crate::currentlogs::{CurrentRecord, CurrentLog, SessionStats};
+use crate::displayctl::{LoggingStatus, WifiStatus};
+use crate::pushswitch::{PushEvent, ButtonRole};
+use crate::bignumber::Quantity;
+use crate::battery::{FuelGauge, BatteryConfig, BatteryState};
+use crate::settings::{KEY_INTERVAL, KEY_READOUT};
+use crate::livefeed::LiveStatus;
+use crate::dutycycle::MIN_DUTY_INTERVAL;
+
+pub const MAX_RECORDS: usize = 4095;
+const FLUSH_TIMEOUT_MS: u64 = 15_000;     // Sending the buffer before a low battery shutdown
+pub const BATTERY_CHECK_MS: u64 = 60_000; // Deep sleep between battery checks after a shutdown
+
+/// Sends the records and then `event` if given, until everything is sent or `timeout` ms passed.
+/// Returns true when everything was sent.
+pub fn flush_records(uplink: &mut dyn Uplink, clock: &dyn Clock, clogs: &mut CurrentRecord,
+                     event: Option<(&str, &BatteryState)>, timeout: u64) -> bool
+{
+    let deadline = clock.now_ms() + timeout;
+    let mut event_sent = event.is_none();
+    while clock.now_ms() < deadline {
+        clogs.remove_data(uplink.take_acknowledged());
+        if clogs.get_size() > 0 {
+            uplink.set_transfer_data(clogs.get_all_data());
+        }
+        else if event_sent == false {
+            let (name, battery) = event.unwrap();
+            event_sent = uplink.send_event(name, battery);
+        }
+        else if uplink.is_idle() {
+            return true;
+        }
+        clock.sleep_ms(100);
+    }
+    false
+}
+
+pub struct Hardware {
+    pub sensor: Box<dyn Sensor>,
+    pub clock: Box<dyn Clock>,
+    pub display: Box<dyn DisplaySink>,
+    pub buttons: Box<dyn Buttons>,
+    pub battery: Box<dyn BatteryAdc>,
+    pub uplink: Box<dyn Uplink>,
+    pub leds: Box<dyn StatusLeds>,
+    pub power: Box<dyn PowerControl>,
+    pub network: Box<dyn Network>,
+    pub store: Box<dyn KeyValueStore>,     // Keeps the interval and readout selected with the buttons
+}
+
+pub struct Logger {
+    hw: Hardware,
+    clogs: CurrentRecord,
+    wifi_status: WifiStatus,
+    logging_start: bool,
+    measuring_interval: u32,
+    measurement_count: u32,
+    start_logging_time: u64,
+    next_time: u64,
+    measurement_light: bool,
+    readout: Quantity,
+    gauge: FuelGauge,
+    shutdown: bool,
+    duty_interval: u32,     // ms, selectable after 1 sec when deep sleep logging is enabled
+    duty_request: bool,
+    provision_request: bool,
+    trigger: f32,           // A, 0 disables it
+    trigger_armed: bool,    // Set when the current is below the trigger, so that a stop is not undone at once
+    in_flight: usize,       // Records at the front of clogs the uplink is sending
+    wall_clock: bool,       // Set once the clock has the time of day
+    stats: SessionStats,
+    sensor_error: bool,     // Alerts are sent when these become true
+    battery_low: bool,
+    live: Option<Box<dyn LiveFeed>>,
+}
+
+impl Logger {
+    pub fn new(mut hw: Hardware) -> Logger {
+        let measuring_interval = 4;    // 4ms interval time when it starts
+        hw.display.set_interval(measuring_interval+1);
+        let start_logging_time = hw.clock.now_ms();
+        Logger { hw: hw,
+                 clogs: CurrentRecord::new(),
+                 wifi_status: WifiStatus::Disconnected,
+                 logging_start: false,
+                 measuring_interval: measuring_interval,
+                 measurement_count: 0,
+                 start_logging_time: start_logging_time,
+                 next_time: start_logging_time + measuring_interval as u64,
+                 measurement_light: false,
+                 readout: Quantity::Voltage,
+                 gauge: FuelGauge::new(BatteryConfig::new()),
+                 shutdown: false,
+                 duty_interval: 0,
+                 duty_request: false,
+                 provision_request: false,
+                 trigger: 0.0,
+                 trigger_armed: false,
+                 in_flight: 0,
+                 wall_clock: false,
+                 stats: SessionStats::new(start_logging_time),
+                 sensor_error: false,
+                 battery_low: false,
+                 live: None }
+    }
+
+    pub fn set_readout(&mut self, readout: Quantity)
+    {
+        self.readout = readout;
+        self.hw.display.set_readout(readout);
+    }
+
+    /// Selects the interval in ms, it must be one of the INT button intervals. Returns false when it is not.
+    pub fn set_interval(&mut self, interval: u32) -> bool
+    {
+        let mut selectable = vec![5, 10, 50, 100, 500, 1000];
+        if self.duty_interval > 0 {
+            selectable.push(self.duty_interval);
+        }
+        if selectable.contains(&interval) == false {
+            info!("Interval {}ms is not selectable", interval);
+            return false;
+        }
+        self.measuring_interval = interval - 1;
+        self.hw.display.set_interval(interval);
+        self.next_time = self.start_logging_time + self.measuring_interval as u64;
+        true
+    }
+
+    /// Starts logging when the current reaches `trigger` A.
+    pub fn set_trigger(&mut self, trigger: f32)
+    {
+        self.trigger = trigger;
+        self.trigger_armed = false;
+    }
+
+    /// Sends every record and the display state to `live` as well.
+    pub fn set_live_feed(&mut self, live: Box<dyn LiveFeed>)
+    {
+        self.live = Some(live);
+    }
+
+    pub fn set_battery_config(&mut self, config: BatteryConfig)
+    {
+        self.gauge = FuelGauge::new(config);
+    }
+
+    pub fn is_logging(&self) -> bool {
+        self.logging_start
+    }
+
+    pub fn get_records(&self) -> &CurrentRecord {
+        &self.clogs
+    }
+
+    /// Adds `interval` ms to the intervals of the INT button, logging with it sleeps between samples.
+    /// It must be a minute or longer, 0 disables it.
+    pub fn set_duty_interval(&mut self, interval: u32) -> Result<()>
+    {
+        if interval > 0 && interval < MIN_DUTY_INTERVAL {
+            bail!("Deep sleep interval {}ms is shorter than {}ms", interval, MIN_DUTY_INTERVAL);
+        }
+        self.duty_interval = interval;
+        Ok(())
+    }
+
+    /// True when logging was started with the deep sleep interval, `run` returns then.
+    pub fn is_duty_requested(&self) -> bool {
+        self.duty_request
+    }
+
+    /// True when the provisioning portal was asked for, `run` returns then.
+    pub fn is_provision_requested(&self) -> bool {
+        self.provision_request
+    }
+
+    pub fn get_interval(&self) -> u32 {
+        self.measuring_interval + 1
+    }
+
+    /// True after a low battery shutdown, only seen on the host where deep sleep returns.
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown
+    }
+
+    pub fn run(&mut self) -> Result<()>
+    {
+        while self.shutdown == false && self.duty_request == false && self.provision_request == false {
+            self.hw.clock.sleep_ms(1);
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    // Stops logging, sends what is buffered and a shutdown event, then sleeps until charged.
+    fn battery_shutdown(&mut self) -> Result<()>
+    {
+        let battery = self.gauge.state();
+        info!("Battery critical {:.2}V {}%, shutting down.", battery.voltage, battery.percent);
+        self.hw.uplink.alert("battery_critical", &format!("{}%", battery.percent));
+        if self.logging_start {
+            self.stop_logging("battery");
+        }
+        self.hw.leds.set_logging(false)?;
+        self.hw.leds.set_measuring(false)?;
+        self.hw.display.set_current_status(LoggingStatus::Stop);
+        self.hw.display.set_err_message("Low Bat".to_string());
+        if self.hw.network.status() == WifiStatus::Connected {
+            flush_records(self.hw.uplink.as_mut(), self.hw.clock.as_ref(), &mut self.clogs,
+                          Some(("shutdown", &battery)), FLUSH_TIMEOUT_MS);
+        }
+        if self.clogs.get_size() > 0 {
+            info!("{} records could not be sent.", self.clogs.get_size());
+        }
+        self.shutdown = true;
+        self.hw.power.deep_sleep(BATTERY_CHECK_MS);
+        Ok(())
+    }
+
+    fn start_logging(&mut self, reason: &str)
+    {
+        self.logging_start = true;
+        self.trigger_armed = false;
+        self.measurement_count = 0;
+        info!("Logging and Sending Start..");
+        // Records being sent stay until the server acknowledges them.
+        self.clogs.truncate(self.in_flight);
+        self.start_logging_time = self.hw.clock.now_ms();
+        self.stats = SessionStats::new(self.start_logging_time);
+        self.hw.uplink.session(true, self.measuring_interval + 1, reason);
+    }
+
+    fn stop_logging(&mut self, reason: &str)
+    {
+        self.logging_start = false;
+        info!("Logging Stop ({})", reason);
+        self.stats.finish(self.hw.clock.now_ms());
+        self.hw.uplink.session(false, self.measuring_interval + 1, reason);
+        self.hw.uplink.statistics(&self.stats);
+    }
+
+    // Saves the interval and restarts the timing of the samples with it.
+    fn interval_changed(&mut self)
+    {
+        self.hw.display.set_interval(self.measuring_interval+1);
+        self.save_interval();
+        self.measurement_light = false;
+        self.measurement_count = 0;
+        self.start_logging_time = self.hw.clock.now_ms();
+        self.next_time = self.start_logging_time + self.measuring_interval as u64;
+    }
+
+    /// Stores the interval in use, to be set again after a restart.
+    pub fn save_interval(&mut self)
+    {
+        self.save_setting(KEY_INTERVAL, &(self.measuring_interval+1).to_string());
+    }
+
+    fn save_setting(&mut self, key: &str, value: &str)
+    {
+        if let Err(e) = self.hw.store.set(key, value) {
+            info!("{:?}", e);
+        }
+    }
+
+    pub fn step(&mut self) -> Result<()>
+    {
+        let mut interval_select_btn = false;
+        let mut start_stop_btn = false;
+        let mut event = self.hw.buttons.get_event();
+        if event.is_some() && self.hw.display.wake() {
+            // The press that wakes the screen up does nothing else.
+            info!("Display woken up by {:?}", event.unwrap());
+            event = None;
+        }
+        match event {
+            Some(PushEvent::ShortPress(ButtonRole::Interval)) => { interval_select_btn = true; },
+            Some(PushEvent::ShortPress(ButtonRole::StartStop)) => { start_stop_btn = true; },
+            Some(PushEvent::LongPress(ButtonRole::Interval)) => {
+                let readout = self.readout.next();
+                info!("Readout {:?}", readout);
+                self.set_readout(readout);
+                self.save_setting(KEY_READOUT, readout.name());
+            },
+            Some(PushEvent::LongPress(ButtonRole::StartStop)) => {
+                info!("Reset energy and charge");
+                if let Err(e) = self.hw.sensor.reset_accumulators() {
+                    info!("{:?}", e);
+                }
+            },
+            Some(PushEvent::Chord(ButtonRole::StartStop, ButtonRole::Interval)) |
+            Some(PushEvent::Chord(ButtonRole::Interval, ButtonRole::StartStop)) if self.logging_start == false => {
+                info!("Provisioning requested");
+                self.provision_request = true;
+                return Ok(());
+            },
+            Some(ev) => { info!("Button event {:?}", ev); },
+            None => {},
+        }
+        match self.hw.uplink.take_command() {
+            Some(RemoteCommand::Start) if self.logging_start == false => { self.start_logging("remote"); },
+            Some(RemoteCommand::Stop) if self.logging_start => { self.stop_logging("remote"); },
+            Some(RemoteCommand::Interval(interval)) if self.set_interval(interval) => {
+                info!("Interval {}ms by remote", interval);
+                self.interval_changed();
+            },
+            Some(command) => { info!("Ignored {:?}", command); },
+            None => {},
+        }
+        if start_stop_btn {
+            if self.logging_start {
+                // to Stop
+                self.stop_logging("button");
+            }
+            else if self.duty_interval > 0 && self.measuring_interval + 1 == self.duty_interval {
+                // to Start with deep sleep between samples
+                info!("Logging with deep sleep Start..");
+                self.duty_request = true;
+                self.hw.display.blank();
+                return Ok(());
+            }
+            else {
+                // to Start
+                self.start_logging("button");
+            }
+        }
+        if interval_select_btn {
+            self.measuring_interval = match self.measuring_interval {
+                4   => 9,
+                9   => 49,
+                49  => 99,
+                99  => 499,
+                499 => 999,
+                999 if self.duty_interval > 0 => self.duty_interval - 1,
+                _ => 4,
+            };
+            self.interval_changed();
+        }
+
+        let wifi_status = self.hw.network.status();
+        if wifi_status != self.wifi_status {
+            info!("WiFi {:?} ip={:?} rssi={:?}", wifi_status, self.hw.network.ip(), self.hw.network.rssi());
+            self.wifi_status = wifi_status;
+        }
+        if self.logging_start == false && self.hw.network.wants_provisioning() {
+            info!("No known AP, starting the provisioning portal");
+            self.provision_request = true;
+            return Ok(());
+        }
+        self.hw.display.set_wifi_status(wifi_status);
+
+        if self.logging_start {
+            self.hw.leds.set_logging(true)?;
+            self.hw.display.set_current_status(LoggingStatus::Start);
+        }
+        else {
+            self.hw.leds.set_logging(false)?;
+            self.hw.display.set_current_status(LoggingStatus::Stop);
+        }
+
+        let now = self.hw.clock.now_ms();
+        if now < self.next_time {
+            return Ok(());
+        }
+        self.measurement_count += 1;
+        self.next_time = self.start_logging_time + (self.measuring_interval * self.measurement_count) as u64;
+
+        self.measurement_light = !self.measurement_light;
+        self.hw.leds.set_measuring(self.measurement_light)?;
+
+        // Read Current/Voltage
+        let mut data = CurrentLog::default();
+        // Timestamp
+        data.clock = (now - self.start_logging_time) as u32;
+        match self.hw.clock.unix_ms() {
+            Some(unix_now) => {
+                if self.wall_clock == false {
+                    info!("Wall clock set, {} records moved onto it", self.clogs.get_size());
+                    self.clogs.set_wall_clock(now, unix_now);
+                    self.wall_clock = true;
+                }
+                data.time = unix_now;
+            },
+            None => { data.time = now; },
+        }
+        let sensor_ok = match self.hw.sensor.read() {
+            Ok(m) => {
+                data.voltage = m.voltage;   // V
+                data.current = m.current;   // A
+                data.power = m.power;       // W
+                self.hw.display.set_accumulated(m.energy, m.charge);
+                true
+            },
+            Err(e) => {
+                info!("{:?}", e);
+                self.hw.display.set_err_message(format!("{:?}", e));
+                if self.sensor_error == false {
+                    self.hw.uplink.alert("sensor_error", &format!("{}", e));
+                }
+                false
+            }
+        };
+        self.sensor_error = sensor_ok == false;
+        // battery voltage
+        data.battery = self.gauge.update(self.hw.battery.read_voltage()?, now);
+        self.hw.display.set_battery(data.battery);
+        self.hw.display.set_battery_low(self.gauge.is_low());
+        if self.gauge.is_low() && self.battery_low == false {
+            self.hw.uplink.alert("battery_low", &format!("{}%", data.battery.percent));
+        }
+        self.battery_low = self.gauge.is_low();
+        if self.gauge.is_critical() {
+            return self.battery_shutdown();
+        }
+        if sensor_ok {
+            // The error message stays on the display until the next good reading.
+            self.hw.display.set_voltage(data.voltage, data.current, data.power);
+        }
+        if self.logging_start {
+            self.clogs.record(data);
+            self.stats.add(&data);
+            if let Some(live) = self.live.as_mut() {
+                live.record(&data);
+            }
+        }
+        else if sensor_ok && self.trigger > 0.0 {
+            if data.current.abs() < self.trigger {
+                self.trigger_armed = true;
+            }
+            else if self.trigger_armed {
+                info!("Triggered at {:.5}A", data.current);
+                self.start_logging("trigger");
+            }
+        }
+        let acked = self.hw.uplink.take_acknowledged();
+        self.clogs.remove_data(acked);
+        self.in_flight = self.in_flight.saturating_sub(acked);
+        let current_record = self.clogs.get_size();
+        if current_record >= MAX_RECORDS && self.logging_start {
+            // Auto stop logging if buffer is full.
+            self.hw.uplink.alert("buffer_full", &format!("{}", current_record));
+            self.stop_logging("buffer_full");
+        }
+        let watermark = (current_record as u32) * 100 / MAX_RECORDS as u32;
+        self.hw.display.set_buffer_watermark(watermark);
+        if let Some(live) = self.live.as_mut() {
+            live.status(&LiveStatus { interval: self.measuring_interval + 1,
+                                      logging: self.logging_start,
+                                      watermark: watermark,
+                                      wifi: self.wifi_status,
+                                      voltage: data.voltage,
+                                      current: data.current,
+                                      power: data.power,
+                                      battery: data.battery,
+                                      battery_low: self.battery_low });
+        }
+
+        if self.wifi_status == WifiStatus::Connected && current_record > self.in_flight {
+            let logs = self.clogs.get_all_data();
+            let txcount = self.hw.uplink.set_transfer_data(logs);
+            if txcount > 0 {
+                self.in_flight = txcount;
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,27 +1,37 @@
-
-
-use std::{thread, time::Duration};
 use esp_idf_hal::{gpio::*, prelude::*, spi, i2c};
-use esp_idf_hal::delay::BLOCK;
 use esp_idf_hal::peripherals::Peripherals;
 use embedded_hal::spi::MODE_0;
 use log::*;
-use std::time::SystemTime;
+use std::{thread, time::Duration, time::SystemTime, time::UNIX_EPOCH};
 use esp_idf_hal::adc::config::Config as AdcConfig;
 use esp_idf_hal::adc::AdcChannelDriver;
 use esp_idf_hal::adc::AdcDriver;
 use esp_idf_hal::adc::Atten11dB;
 
-mod pushswitch;
-mod displayctl;
-mod currentlogs;
-mod wifi;
-mod transfer;
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sntp::EspSntp;
 
-use pushswitch::PushSwitch;
-use displayctl::{DisplayPanel, LoggingStatus, WifiStatus};
-use currentlogs::{CurrentRecord, CurrentLog};
-use transfer::Transfer;
+use currentlogger::{wifi, portal};
+use currentlogger::mdns::{MdnsAdvertiser, MdnsDiscovery};
+use currentlogger::webfeed::WebFeed;
+use currentlogger::livefeed::LiveBroadcast;
+use currentlogger::provision::{self, DEFAULT_NAME};
+use currentlogger::settings::Settings;
+use currentlogger::wifilist::WifiNetwork;
+use currentlogger::netconfig::NetConfig;
+use currentlogger::pushswitch::{self, PushSwitch, ButtonConfig, ButtonRole};
+use currentlogger::displayctl::{DisplayPanel, DisplaySettings};
+use currentlogger::transfer::{Transfer, split_scheme};
+use currentlogger::mqtt::{self, MqttUplink};
+use currentlogger::udpstream::UdpUplink;
+use currentlogger::tcpstream::TcpUplink;
+use currentlogger::ina228::{Ina228, INA228_ADDR};
+use currentlogger::hal::{SystemClock, StatusLeds, BatteryAdc, PowerControl, Uplink};
+use currentlogger::esphal::{EspI2c, EspBatteryAdc, EspLeds, EspPower, EspStore, EspTlsConnector};
+use currentlogger::logger::{self, Logger, Hardware, BATTERY_CHECK_MS};
+use currentlogger::dutycycle::DutyCycle;
+use currentlogger::bignumber::Quantity;
+use currentlogger::battery::{BatteryConfig, FuelGauge};
 
 #[toml_cfg::toml_config]
 pub struct Config {
@@ -30,7 +40,282 @@ pub struct Config {
     #[default("")]
     wifi_psk: &'static str,
     #[default("")]
+    wifi_networks: &'static str,
+    #[default("currentlogger")]
+    hostname: &'static str,
+    #[default("")]
+    static_ip: &'static str,
+    #[default("")]
+    gateway: &'static str,
+    #[default("")]
+    dns: &'static str,
+    #[default("")]
     http_server: &'static str,
+    #[default(true)]
+    mdns: bool,
+    #[default(true)]
+    live_view: bool,
+    #[default("/")]
+    http_path: &'static str,
+    #[default("")]
+    http_headers: &'static str,
+    #[default("")]
+    http_auth: &'static str,
+    #[default("")]
+    tls_ca: &'static str,
+    #[default("")]
+    tls_pin: &'static str,
+    #[default("http")]
+    uplink: &'static str,
+    #[default("")]
+    mqtt_server: &'static str,
+    #[default("")]
+    mqtt_user: &'static str,
+    #[default("")]
+    mqtt_password: &'static str,
+    #[default("")]
+    mqtt_topic: &'static str,
+    #[default("")]
+    mqtt_topics: &'static str,
+    #[default("")]
+    stream_server: &'static str,
+    #[default("json")]
+    upload_format: &'static str,
+    #[default("")]
+    influx_org: &'static str,
+    #[default("")]
+    influx_bucket: &'static str,
+    #[default("currentlogger")]
+    influx_measurement: &'static str,
+    #[default("tag")]
+    influx_tag: &'static str,
+    #[default("")]
+    influx_fields: &'static str,
+    #[default("21:startstop:low,20:interval:low")]
+    buttons: &'static str,
+    #[default(5)]
+    interval: u32,
+    #[default(0.010)]
+    shunt: f32,
+    #[default(0.0)]
+    trigger: f32,
+    #[default("voltage")]
+    readout: &'static str,
+    #[default(255)]
+    display_brightness: u8,
+    #[default(64)]
+    display_dim_brightness: u8,
+    #[default(60)]
+    display_dim_after: u64,
+    #[default(0)]
+    display_off_after: u64,
+    #[default(0)]
+    display_pixel_shift: u64,
+    #[default("")]
+    battery_curve: &'static str,
+    #[default(500)]
+    battery_capacity: u32,
+    #[default(80)]
+    battery_load: u32,
+    #[default(150)]
+    battery_resistance: u32,
+    #[default(15)]
+    battery_low: u8,
+    #[default(5)]
+    battery_critical: u8,
+    #[default(0)]
+    duty_interval: u32,
+    #[default(10)]
+    duty_batch: u32,
+}
+
+const DUTY_UPLOAD_MS: u64 = 30_000;
+const NVS_NAMESPACE: &str = "currentlogger";
+
+// Samples of the duty cycled logging, kept through deep sleep.
+#[link_section = ".rtc.data.duty"]
+static mut DUTY: DutyCycle = DutyCycle::new();
+
+// cfg.toml gives the settings until they are changed on the device.
+fn default_settings() -> Settings {
+    let mut settings = Settings::new();
+    settings.ssid = CONFIG.wifi_ssid.to_string();
+    settings.psk = CONFIG.wifi_psk.to_string();
+    settings.networks = CONFIG.wifi_networks.to_string();
+    settings.hostname = CONFIG.hostname.to_string();
+    settings.static_ip = CONFIG.static_ip.to_string();
+    settings.gateway = CONFIG.gateway.to_string();
+    settings.dns = CONFIG.dns.to_string();
+    settings.server = CONFIG.http_server.to_string();
+    settings.mdns = CONFIG.mdns;
+    settings.live_view = CONFIG.live_view;
+    settings.path = CONFIG.http_path.to_string();
+    settings.headers = CONFIG.http_headers.to_string();
+    settings.auth = CONFIG.http_auth.to_string();
+    settings.tls_ca = CONFIG.tls_ca.to_string();
+    settings.tls_pin = CONFIG.tls_pin.to_string();
+    settings.uplink = CONFIG.uplink.to_string();
+    settings.mqtt_server = CONFIG.mqtt_server.to_string();
+    settings.mqtt_user = CONFIG.mqtt_user.to_string();
+    settings.mqtt_password = CONFIG.mqtt_password.to_string();
+    settings.mqtt_topic = CONFIG.mqtt_topic.to_string();
+    settings.mqtt_topics = CONFIG.mqtt_topics.to_string();
+    settings.stream_server = CONFIG.stream_server.to_string();
+    settings.format = CONFIG.upload_format.to_string();
+    settings.org = CONFIG.influx_org.to_string();
+    settings.bucket = CONFIG.influx_bucket.to_string();
+    settings.measurement = CONFIG.influx_measurement.to_string();
+    settings.tag_key = CONFIG.influx_tag.to_string();
+    settings.fields = CONFIG.influx_fields.to_string();
+    settings.interval = CONFIG.interval;
+    settings.shunt = CONFIG.shunt;
+    settings.trigger = CONFIG.trigger;
+    match Quantity::from_name(CONFIG.readout) {
+        Some(readout) => { settings.readout = readout; },
+        None => { info!("Unknown readout: {}", CONFIG.readout); },
+    }
+    settings.display = DisplaySettings {
+        brightness: CONFIG.display_brightness,
+        dim_brightness: CONFIG.display_dim_brightness,
+        dim_after: CONFIG.display_dim_after,
+        off_after: CONFIG.display_off_after,
+        pixel_shift: CONFIG.display_pixel_shift,
+    };
+    settings
+}
+
+// The ssid comes first, then the networks list.
+fn wifi_networks(settings: &Settings) -> anyhow::Result<Vec<WifiNetwork>> {
+    let mut networks = Vec::new();
+    if settings.ssid != "" {
+        networks.push(WifiNetwork { ssid: settings.ssid.clone(), psk: settings.psk.clone(), priority: 0 });
+    }
+    networks.extend(WifiNetwork::parse_list(&settings.networks)?);
+    Ok(networks)
+}
+
+// The system time keeps running in deep sleep.
+fn wall_clock_ms() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+// An invalid address falls back to DHCP, so that the logger stays reachable.
+fn net_config(settings: &Settings) -> NetConfig {
+    match NetConfig::parse(&settings.hostname, &settings.static_ip, &settings.gateway, &settings.dns) {
+        Ok(net) => net,
+        Err(e) => { info!("{:?}", e); NetConfig::new() },
+    }
+}
+
+// Starts the selected uplink. With mDNS the hostname is advertised, and HTTP uses a collector found on the LAN
+// before the server. The advertiser must be kept.
+fn start_uplink(settings: &Settings) -> anyhow::Result<(Box<dyn Uplink>, Option<MdnsAdvertiser>)> {
+    let mdns = if settings.mdns {
+        let name = if settings.name != "" { settings.name.as_str() } else { DEFAULT_NAME };
+        match MdnsAdvertiser::start(&settings.hostname, name) {
+            Ok(mdns) => Some(mdns),
+            Err(e) => { info!("{:?}", e); None },
+        }
+    }
+    else {
+        None
+    };
+    let (format, point) = settings.upload_format()?;
+    match settings.uplink.as_str() {
+        "mqtt" => {
+            let mut mqtt = MqttUplink::new(settings.mqtt_server.clone());
+            if settings.name != "" {
+                mqtt.set_tag(&settings.name);
+            }
+            mqtt.set_measurement(&point.measurement);
+            mqtt.set_credentials(&settings.mqtt_user, &settings.mqtt_password);
+            mqtt.set_topics(settings.topics()?);
+            if mqtt::split_scheme(&settings.mqtt_server).0 {
+                mqtt.set_tls_connector(Box::new(EspTlsConnector::new(&settings.tls_ca, &settings.tls_pin)?));
+            }
+            mqtt.start()?;
+            Ok((Box::new(mqtt), mdns))
+        },
+        "udp" => {
+            let mut udp = UdpUplink::new(settings.stream_server.clone());
+            if settings.name != "" {
+                udp.set_tag(&settings.name);
+            }
+            udp.start()?;
+            Ok((Box::new(udp), mdns))
+        },
+        "tcp" => {
+            let mut tcp = TcpUplink::new(settings.stream_server.clone());
+            if settings.name != "" {
+                tcp.set_tag(&settings.name);
+            }
+            tcp.start()?;
+            Ok((Box::new(tcp), mdns))
+        },
+        "http" => {
+            let mut txd = Transfer::new(settings.server.clone());
+            if settings.name != "" {
+                txd.set_tag(&settings.name);
+            }
+            txd.set_endpoint(&settings.upload_path(), settings.upload_headers()?);
+            txd.set_format(format, point);
+            if split_scheme(&settings.server).0 {
+                txd.set_tls_connector(Box::new(EspTlsConnector::new(&settings.tls_ca, &settings.tls_pin)?));
+            }
+            if mdns.is_some() {
+                txd.set_discovery(Box::new(MdnsDiscovery));
+            }
+            txd.start()?;
+            Ok((Box::new(txd), mdns))
+        },
+        uplink => Err(anyhow::anyhow!("Unknown uplink: {}", uplink)),
+    }
+}
+
+// Holding START while the logger wakes up ends the duty cycled logging.
+fn start_stop_held() -> anyhow::Result<bool> {
+    for button in ButtonConfig::parse_list(CONFIG.buttons)? {
+        if button.role == ButtonRole::StartStop {
+            let mut input = pushswitch::gpio_button(&button)?;
+            return Ok(input.is_high() != button.active_low);
+        }
+    }
+    Ok(false)
+}
+
+// Takes one sample, uploads a batch when it is due, and sleeps until the next sample.
+fn duty_wake(duty: &mut DutyCycle, mut ina228: Ina228<EspI2c>, mut battery_adc: EspBatteryAdc,
+             battery: BatteryConfig, modem: esp_idf_hal::modem::Modem,
+             settings: &Settings) -> anyhow::Result<()>
+{
+    let mut gauge = FuelGauge::new(battery);
+    let state = gauge.update(battery_adc.read_voltage()?, 0);
+    if let Err(e) = duty.sample(&mut ina228, state, wall_clock_ms()) {
+        info!("{:?}", e);
+    }
+    let stop = start_stop_held()?;
+    if stop || gauge.is_critical() || duty.is_full() || duty.len() >= CONFIG.duty_batch as usize {
+        match wifi::wifi_connect(modem, &wifi_networks(settings)?, &net_config(settings)) {
+            Ok(_wifi) => {
+                let (mut uplink, _mdns) = start_uplink(settings)?;
+                let mut rec = duty.take();
+                let event = if gauge.is_critical() { Some(("shutdown", &state)) } else { None };
+                logger::flush_records(uplink.as_mut(), &SystemClock::new(), &mut rec, event, DUTY_UPLOAD_MS);
+                duty.restore(&rec);
+            },
+            Err(e) => { info!("{:?}", e); },
+        }
+    }
+    if gauge.is_critical() {
+        duty.stop();
+        EspPower.deep_sleep(BATTERY_CHECK_MS);
+    }
+    if stop {
+        duty.stop();
+        unsafe { esp_idf_sys::esp_restart(); }
+    }
+    EspPower.deep_sleep(duty.next_sleep(wall_clock_ms()));
+    Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
@@ -38,12 +323,58 @@ fn main() -> anyhow::Result<()> {
     esp_idf_svc::log::EspLogger::initialize_default();
 
     // Initialize nvs
-    unsafe {
-        esp_idf_sys::nvs_flash_init();
-    }
+    let nvs = EspDefaultNvsPartition::take()?;
+    let mut store = EspStore::new(nvs.clone(), NVS_NAMESPACE)?;
+    let settings = Settings::load(&mut store, default_settings())?;
     // Peripherals Initialize
     let peripherals = Peripherals::take().unwrap();
-    
+
+    // ADC GPIO0
+    let adc = AdcDriver::new(peripherals.adc1, &AdcConfig::new().calibration(true))?;
+    let adc_pin: AdcChannelDriver<'_, Gpio0, Atten11dB<_>> =
+        AdcChannelDriver::new(peripherals.pins.gpio0)?;
+    let mut battery_adc = EspBatteryAdc::new(adc, adc_pin);
+    let mut battery = BatteryConfig::new();
+    if CONFIG.battery_curve != "" {
+        battery.curve = BatteryConfig::parse_curve(CONFIG.battery_curve)?;
+    }
+    battery.capacity = CONFIG.battery_capacity;
+    battery.load = CONFIG.battery_load;
+    battery.resistance = CONFIG.battery_resistance;
+    battery.low = CONFIG.battery_low;
+    battery.critical = CONFIG.battery_critical;
+
+    // Current/Voltage
+    let i2c = peripherals.i2c0;
+    let scl = peripherals.pins.gpio4;
+    let sda = peripherals.pins.gpio3;
+    let config = i2c::I2cConfig::new().baudrate(400.kHz().into());
+    let i2cdrv = i2c::I2cDriver::new(i2c, sda, scl, &config)?;
+    let mut ina228 = Ina228::new(EspI2c::new(i2cdrv), INA228_ADDR);
+    ina228.init(settings.shunt).expect("INA228 I2C Write Error");
+
+    let timer_wakeup = unsafe { esp_idf_sys::esp_sleep_get_wakeup_cause() } == esp_idf_sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER;
+    let duty = unsafe { &mut DUTY };
+    if timer_wakeup && duty.is_active() {
+        return duty_wake(duty, ina228, battery_adc, battery, peripherals.modem, &settings);
+    }
+    // Back from a low battery shutdown, sleep again until it is charged.
+    if timer_wakeup {
+        let mut gauge = FuelGauge::new(battery.clone());
+        gauge.update(battery_adc.read_voltage()?, 0);
+        if gauge.can_start() == false {
+            info!("Battery {}%, waiting for charge.", gauge.state().percent);
+            EspPower.deep_sleep(BATTERY_CHECK_MS);
+        }
+    }
+
+    let networks = wifi_networks(&settings)?;
+    // Without a known network the portal opens by itself, until it once timed out.
+    let auto_portal = provision::is_auto_portal_allowed(&store)?;
+    if provision::is_portal_requested(&store)? || (networks.is_empty() && auto_portal) {
+        return portal::run(peripherals.modem, store, settings);
+    }
+
     // Display SPI
     let spi = peripherals.spi2;
     let sclk = peripherals.pins.gpio8;
@@ -61,211 +392,93 @@ fn main() -> anyhow::Result<()> {
         sdi_not_used,
         spi::Dma::Disabled,
     ).unwrap();
-    
+
     let spi_device = spi::SpiDeviceDriver::new(spi_driver, cs_not_used, &spi_config)?;
     let mut dp = DisplayPanel::new();
+    dp.set_settings(settings.display);
     dp.start(spi_device, dc, rst);
 
-    // Current/Voltage
-    let i2c = peripherals.i2c0;
-    let scl = peripherals.pins.gpio4;
-    let sda = peripherals.pins.gpio3;
-    let config = i2c::I2cConfig::new().baudrate(400.kHz().into());
-    let mut i2cdrv = i2c::I2cDriver::new(i2c, sda, scl, &config)?;
-
-    // SHUNT_CAL
-    let current_lsb = 16.384 / 524_288.0;
-    let shunt_cal_val = 13107.2 * current_lsb * 1000_000.0 * 0.010;
-    let shunt_cal = shunt_cal_val as u32;
-    info!("current_lsb={:?} shunt_cal_val={:?}", current_lsb, shunt_cal_val);
-    i2cdrv.write(0x40, &[0x02u8; 1], BLOCK).expect("INA228 I2C Write Error");
-    let mut shunt_cal_buf = [0u8; 2];
-    shunt_cal_buf[0] = (shunt_cal >> 8) as u8;
-    shunt_cal_buf[1] = (shunt_cal & 0xFF) as u8;
-    i2cdrv.write(0x40, &shunt_cal_buf, BLOCK)?;
-
     // PushSW
-    let interval_select_pin   = peripherals.pins.gpio20;
-    let startstop_pin   = peripherals.pins.gpio21;
-    let interval_select_sig   = Box::new(PinDriver::input(interval_select_pin)?);
-    let startstop_sig   = Box::new(PinDriver::input(startstop_pin)?);
     let mut psw         = PushSwitch::new();
-    psw.start(interval_select_sig, startstop_sig);
-    let mut interval_led   = PinDriver::input_output(peripherals.pins.gpio6)?;
-    let mut startstop_led   = PinDriver::input_output(peripherals.pins.gpio7)?;
-    startstop_led.set_low()?;
-    interval_led.set_low()?;
-
-    // Temperature Logs
-    let mut clogs = CurrentRecord::new();
+    for button in ButtonConfig::parse_list(CONFIG.buttons)? {
+        info!("{:?}", button);
+        psw.add_button(pushswitch::gpio_button(&button)?, button);
+    }
+    psw.start();
+    let interval_led   = PinDriver::input_output(peripherals.pins.gpio6)?;
+    let startstop_led   = PinDriver::input_output(peripherals.pins.gpio7)?;
+    let mut leds = EspLeds::new(interval_led, startstop_led);
+    leds.set_logging(false)?;
+    leds.set_measuring(false)?;
 
     // WiFi
-    let wifi_enable : bool;
-    let wifi = wifi::wifi_connect(peripherals.modem, CONFIG.wifi_ssid, CONFIG.wifi_psk);
-    match wifi {
-        Ok(_) => { wifi_enable = true; },
-        Err(e) => { info!("{:?}", e); wifi_enable = false }
-    }
-    let mut txd =  Transfer::new(CONFIG.http_server.to_string());
-    txd.start()?;
-    
-    // ADC GPIO0
-    let mut adc = AdcDriver::new(peripherals.adc1, &AdcConfig::new().calibration(true))?;
-    let mut adc_pin: esp_idf_hal::adc::AdcChannelDriver<'_, Gpio0, Atten11dB<_>> =
-        AdcChannelDriver::new(peripherals.pins.gpio0)?;
+    let mut wifimgr = wifi::WifiManager::new();
+    wifimgr.set_auto_portal(auto_portal);
+    wifimgr.set_net_config(net_config(&settings));
+    wifimgr.start(peripherals.modem, networks)?;
+    // Wall clock for the timestamps, set once the network is up. It keeps running in deep sleep.
+    let _sntp = EspSntp::new_default()?;
+    let (uplink, mut mdns) = start_uplink(&settings)?;
 
     // loop
-    let mut measurement_count : u32 = 0;
-    let mut logging_start = false;
-    let mut measuring_interval : u32 = 4;    // 4ms interval time when it starts
-    dp.set_interval(measuring_interval+1);
-    let mut start_logging_time = SystemTime::now();
-    let mut next_time = start_logging_time.checked_add(Duration::from_millis(measuring_interval as u64)).expect("no next_time");
-    let mut measurement_light = false;
-    loop {
-        thread::sleep(Duration::from_millis(1));
-
-        let interval_select_btn = psw.get_gpio_state(20);
-        let start_stop_btn = psw.get_gpio_state(21);
-        if start_stop_btn == true {
-            if logging_start == true {
-                // to Stop
-                logging_start = false;
-                // clogs.dump();
-                // clogs.clear();
-            }
-            else {
-                // to Start
-                logging_start = true;
-                measurement_count = 0;
-                info!("Logging and Sending Start..");
-                clogs.clear();
-                start_logging_time = SystemTime::now();
-            }
-        }
-        if interval_select_btn == true {
-            measuring_interval = match measuring_interval {
-                4   => 9,
-                9   => 49,
-                49  => 99,
-                99  => 499,
-                499 => 999,
-                _ => 4,
-            };
-            dp.set_interval(measuring_interval+1);
-            measurement_light = false;
-            measurement_count = 0;
-            start_logging_time = SystemTime::now();
-            next_time = start_logging_time.checked_add(Duration::from_millis(measuring_interval as u64)).expect("no next_time");
-        }
-
-        if wifi_enable == false{
-            dp.set_wifi_status(WifiStatus::Disconnected);
-        }
-        else {
-            dp.set_wifi_status(WifiStatus::Connected);
-        }
-
-        if logging_start == true {
-            startstop_led.set_high()?;
-            dp.set_current_status(LoggingStatus::Start);
-        }
-        else {
-            startstop_led.set_low()?;
-            dp.set_current_status(LoggingStatus::Stop);
-        }
-
-
-        let intime = next_time.duration_since(SystemTime::now());
-        match intime {
-            Ok(_v) => { continue },
-            Err(_e) => { },
-        }
-        measurement_count += 1;
-        next_time = start_logging_time.checked_add(Duration::from_millis((measuring_interval * measurement_count) as u64)).expect("no next_time");
-//        let duration = SystemTime::now();
-
-        if measurement_light == false {
-            interval_led.set_high()?;
-            measurement_light = true;
-        }
-        else {
-            interval_led.set_low()?;
-            measurement_light = false;
-        }
-
-       // Read Current/Voltage
-        let mut vbus_buf  = [0u8; 3];
-        let mut data = CurrentLog::default();
-        // Timestamp
-        data.clock = start_logging_time.elapsed().unwrap().as_millis() as u32;
-
-        i2cdrv.write(0x40, &[0x05u8; 1], BLOCK)?;
-        match i2cdrv.read(0x40, &mut vbus_buf, BLOCK){
-            Ok(_v) => {
-                // info!("vbus={:?}", vbus_buf);
-                let vbus = ((((vbus_buf[0] as u32) << 16 | (vbus_buf[1] as u32) << 8 | (vbus_buf[2] as u32)) >> 4) as f32 * 193.3125) / 1000_000.0;
-                data.voltage = vbus; // V
-            },
-            Err(e) => {
+    let mut logger = Logger::new(Hardware {
+        sensor: Box::new(ina228),
+        clock: Box::new(SystemClock::new()),
+        display: Box::new(dp),
+        buttons: Box::new(psw),
+        battery: Box::new(battery_adc),
+        uplink: uplink,
+        leds: Box::new(leds),
+        power: Box::new(EspPower),
+        network: Box::new(wifimgr),
+        store: Box::new(EspStore::new(nvs, NVS_NAMESPACE)?),
+    });
+    logger.set_battery_config(battery);
+    match CONFIG.duty_interval.checked_mul(1000) {
+        Some(duty_interval) => {
+            if let Err(e) = logger.set_duty_interval(duty_interval) {
                 info!("{:?}", e);
-                dp.set_err_message(format!("{:?}", e));
             }
-        }
-        let mut curt_buf  = [0u8; 3];
-        i2cdrv.write(0x40, &[0x07u8; 1], BLOCK)?;
-        match i2cdrv.read(0x40, &mut curt_buf, BLOCK) {
-            Ok(_v) => {
-                let current_reg : f32;
-                if curt_buf[0] & 0x80 == 0x80 {
-                    current_reg = (0x100000 - (((curt_buf[0] as u32) << 16 | (curt_buf[1] as u32) << 8 | (curt_buf[2] as u32)) >> 4)) as f32 * -1.0;
-                }
-                else {
-                    current_reg = (((curt_buf[0] as u32) << 16 | (curt_buf[1] as u32) << 8 | (curt_buf[2] as u32)) >> 4) as f32;
+        },
+        None => info!("Deep sleep interval {}s is too long", CONFIG.duty_interval),
+    }
+    if logger.set_interval(settings.interval) == false {
+        // An interval saved with a deep sleep interval that is no longer configured.
+        let interval = Settings::new().interval;
+        info!("Stored interval {}ms refused, back to {}ms", settings.interval, interval);
+        logger.set_interval(interval);
+        logger.save_interval();
+    }
+    logger.set_readout(settings.readout);
+    logger.set_trigger(settings.trigger);
+    let _web = if settings.live_view {
+        let live = LiveBroadcast::new();
+        match WebFeed::start(live.clone()) {
+            Ok(web) => {
+                live.start();
+                logger.set_live_feed(Box::new(live));
+                if let Some(mdns) = mdns.as_mut() {
+                    if let Err(e) = mdns.advertise_http() {
+                        info!("{:?}", e);
+                    }
                 }
-                let current = current_lsb * current_reg;
-                data.current = current;   // A
-                // info!("curt={:?} {:?}A", curt_buf, data.current);
-            },
-            Err(e) => {
-                info!("{:?}", e);
-                dp.set_err_message(format!("{:?}", e));
-            }
-        }
-        let mut power_buf = [0u8; 3];
-        i2cdrv.write(0x40, &[0x08u8; 1], BLOCK)?;
-        match i2cdrv.read(0x40, &mut power_buf, BLOCK) {
-            Ok(_v) => {
-                // info!("power={:?}", power_buf);
-                let power_reg = ((power_buf[0] as u32) << 16 | (power_buf[1] as u32) << 8 | (power_buf[2] as u32)) as f32;
-                let power = 3.2 * current_lsb * power_reg;        
-                data.power = power;   // W
+                Some(web)
             },
-            Err(e) => {
-                info!("{:?}", e);
-                dp.set_err_message(format!("{:?}", e));
-            }
+            Err(e) => { info!("{:?}", e); None },
         }
-        // battery voltage 
-        data.battery =  adc.read(&mut adc_pin).unwrap() as f32 * 2.0 / 1000.0;
-        dp.set_battery(data.battery);
-        dp.set_voltage(data.voltage, data.current, data.power);
-        if logging_start {
-            clogs.record(data);
-        }
-        let current_record = clogs.get_size();
-        if current_record >= 4095 {
-            logging_start = false;  // Auto stop logging if buffer is full.
-        }
-        dp.set_buffer_watermark((current_record as u32) * 100 / 4095);
-
-        if wifi_enable == true && current_record > 0 {
-            let logs = clogs.get_all_data();
-            let txcount = txd.set_transfer_data(logs);
-            if txcount > 0 {
-                clogs.remove_data(txcount);
-            }
-        }
-//        info!("duration = {:?}", duration.elapsed().unwrap().as_millis());
     }
+    else {
+        None
+    };
+    logger.run()?;
+    if logger.is_duty_requested() {
+        duty.start(wall_clock_ms(), logger.get_interval() as u64);
+        thread::sleep(Duration::from_millis(200));     // Let the display thread blank the screen
+        EspPower.deep_sleep(duty.next_sleep(wall_clock_ms()));
+    }
+    if logger.is_provision_requested() {
+        provision::request_portal(&mut store, true)?;
+        unsafe { esp_idf_sys::esp_restart(); }
+    }
+    Ok(())
 }
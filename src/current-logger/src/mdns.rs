@@ -0,0 +1,97 @@
+// mDNS: advertises the logger on the LAN and finds a collector advertising _currentlogger._tcp.
+#![allow(clippy::redundant_field_names)]
+
+use std::ffi::CString;
+use std::net::Ipv4Addr;
+use std::ptr;
+
+use esp_idf_svc::mdns::EspMdns;
+use esp_idf_sys::{esp, mdns_query_ptr, mdns_query_results_free, mdns_result_t};
+use anyhow::Result;
+use log::*;
+
+use crate::hal::Discovery;
+
+pub const COLLECTOR_SERVICE: &str = "_currentlogger";
+pub const DEVICE_SERVICE: &str = "_http";
+const PROTO: &str = "_tcp";
+const DEVICE_PORT: u16 = 80;
+const QUERY_TIMEOUT_MS: u32 = 2000;
+const QUERY_MAX_RESULTS: usize = 4;
+
+/// Advertises `hostname`.local while it is kept, and the instance currentlogger-<mac> of _http._tcp once
+/// there is a web server.
+pub struct MdnsAdvertiser {
+    mdns: EspMdns,
+    instance: String,
+    name: String,
+}
+
+impl MdnsAdvertiser {
+    pub fn start(hostname: &str, name: &str) -> Result<MdnsAdvertiser> {
+        let instance = format!("currentlogger-{}", mac_string());
+        let mut mdns = EspMdns::take()?;
+        mdns.set_hostname(hostname)?;
+        mdns.set_instance_name(&instance)?;
+        info!("mDNS {}.local", hostname);
+        Ok(MdnsAdvertiser { mdns: mdns, instance: instance, name: name.to_string() })
+    }
+
+    /// Adds the _http._tcp service, for when the live view is listening on port 80.
+    pub fn advertise_http(&mut self) -> Result<()> {
+        self.mdns.add_service(Some(&self.instance), DEVICE_SERVICE, PROTO, DEVICE_PORT, &[("name", self.name.as_str())])?;
+        info!("mDNS {}.{}.{}", self.instance, DEVICE_SERVICE, PROTO);
+        Ok(())
+    }
+}
+
+/// Asks the LAN for a collector each time the upload needs a server.
+pub struct MdnsDiscovery;
+
+impl Discovery for MdnsDiscovery {
+    fn find(&mut self) -> Option<String> {
+        match query_collector() {
+            Ok(Some(found)) => {
+                info!("Collector found by mDNS at {}", found);
+                Some(found)
+            },
+            Ok(None) => None,
+            Err(e) => { info!("{:?}", e); None },
+        }
+    }
+}
+
+// The first IPv4 collector in the answers, as address:port.
+fn query_collector() -> Result<Option<String>> {
+    let service = CString::new(COLLECTOR_SERVICE)?;
+    let proto = CString::new(PROTO)?;
+    let mut results: *mut mdns_result_t = ptr::null_mut();
+    esp!(unsafe { mdns_query_ptr(service.as_ptr(), proto.as_ptr(), QUERY_TIMEOUT_MS, QUERY_MAX_RESULTS as _, &mut results) })?;
+    let mut found = None;
+    let mut result = results;
+    while found.is_none() && !result.is_null() {
+        let r = unsafe { &*result };
+        let mut addr = r.addr;
+        while !addr.is_null() {
+            let a = unsafe { &*addr };
+            if a.addr.type_ == esp_idf_sys::ESP_IPADDR_TYPE_V4 as u8 {
+                // lwIP keeps the address in network order.
+                let ip = Ipv4Addr::from(unsafe { a.addr.u_addr.ip4.addr }.to_le_bytes());
+                found = Some(format!("{}:{}", ip, r.port));
+                break;
+            }
+            addr = a.next;
+        }
+        result = r.next;
+    }
+    if !results.is_null() {
+        unsafe { mdns_query_results_free(results) };
+    }
+    Ok(found)
+}
+
+fn mac_string() -> String {
+    let mut mac = [0u8; 6];
+    unsafe { esp_idf_sys::esp_read_mac(mac.as_mut_ptr(), esp_idf_sys::esp_mac_type_t_ESP_MAC_WIFI_STA) };
+    mac.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,346 @@
+// MQTT uplink: the records, sessions, statistics and alerts are published with QoS 1 to a broker,
+// commands come back on a subscribed topic.
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use log::*;
+use std::collections::VecDeque;
+use std::{thread, time::Duration, sync::Arc, sync::Mutex};
+use anyhow::{anyhow, Result};
+
+use crate::currentlogs::{CurrentLog, SessionStats};
+use crate::battery::BatteryState;
+use crate::hal::{Uplink, Connector, RemoteCommand};
+use crate::httpclient::TcpConnector;
+use crate::mqttclient::{MqttClient, MqttOptions, Publish, Incoming};
+use crate::lineprotocol::DEFAULT_MEASUREMENT;
+use crate::provision::DEFAULT_NAME;
+use crate::transfer::{Transfer, json_escape};
+
+const CONNECT_TIMEOUT_MS: u64 = 10_000;
+const RETRY_MS: u64 = 1000;
+const KEEP_ALIVE_S: u16 = 30;
+const MAX_QUEUED: usize = 32;       // Messages besides the records, older ones are dropped when offline
+
+/// Topics of the messages. `status` is retained "online", and "offline" by the last will.
+#[derive(Debug, Clone)]
+pub struct MqttTopics {
+    pub samples: String,
+    pub session: String,
+    pub stats: String,
+    pub alert: String,
+    pub event: String,
+    pub status: String,
+    pub command: String,
+}
+
+impl MqttTopics {
+    /// All topics below `prefix`, e.g. "currentlogger/currentch1/samples".
+    pub fn new(prefix: &str) -> MqttTopics {
+        let prefix = prefix.trim_end_matches('/');
+        MqttTopics { samples: format!("{}/samples", prefix),
+                     session: format!("{}/session", prefix),
+                     stats: format!("{}/stats", prefix),
+                     alert: format!("{}/alert", prefix),
+                     event: format!("{}/event", prefix),
+                     status: format!("{}/status", prefix),
+                     command: format!("{}/cmd", prefix) }
+    }
+
+    /// Topics below `prefix`, with some moved by "samples=lab/power;alert=lab/alerts;..".
+    pub fn parse(prefix: &str, topics: &str) -> Result<MqttTopics> {
+        let mut t = MqttTopics::new(prefix);
+        for entry in topics.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let (name, topic) = entry.split_once('=').ok_or(anyhow!("Invalid topic: {}", entry))?;
+            let topic = topic.trim().to_string();
+            if topic.is_empty() || topic.contains(['+', '#']) {
+                return Err(anyhow!("Invalid topic: {}", entry));
+            }
+            match name.trim() {
+                "samples" => { t.samples = topic; },
+                "session" => { t.session = topic; },
+                "stats"   => { t.stats = topic; },
+                "alert"   => { t.alert = topic; },
+                "event"   => { t.event = topic; },
+                "status"  => { t.status = topic; },
+                "cmd"     => { t.command = topic; },
+                _ => return Err(anyhow!("Unknown topic: {}", name)),
+            }
+        }
+        Ok(t)
+    }
+}
+
+struct Outgoing {
+    message: Publish,
+    records: usize,     // Records in a samples message
+    id: u16,            // Of the PUBACK
+    sent: bool,         // On the current connection
+    dup: bool,          // Sent on a connection that was lost
+}
+
+struct MqttData {
+    outbox: VecDeque<Outgoing>,
+    pending: usize,     // Records in the outbox
+    acked: usize,       // Records the broker accepted, not yet taken by the logger
+    commands: VecDeque<RemoteCommand>,
+}
+
+pub struct MqttUplink {
+    data: Arc<Mutex<MqttData>>,
+    server: String,
+    tag: String,
+    measurement: String,
+    username: String,
+    password: String,
+    topics: MqttTopics,
+    tls: Option<Box<dyn Connector>>,
+}
+
+impl MqttUplink {
+    /// `server` is host:port, mqtt://host[:port] or mqtts://host[:port].
+    pub fn new(server: String) -> Self {
+        MqttUplink { data: Arc::new(Mutex::new(
+            MqttData { outbox: VecDeque::new(), pending: 0, acked: 0, commands: VecDeque::new() })),
+            server: server,
+            tag: DEFAULT_NAME.to_string(),
+            measurement: DEFAULT_MEASUREMENT.to_string(),
+            username: "".to_string(),
+            password: "".to_string(),
+            topics: MqttTopics::new(&format!("currentlogger/{}", DEFAULT_NAME)),
+            tls: None }
+    }
+
+    /// Sets the device name, sent as the tag of every point and used as the client id.
+    pub fn set_tag(&mut self, tag: &str) {
+        self.tag = tag.to_string();
+    }
+
+    pub fn set_measurement(&mut self, measurement: &str) {
+        self.measurement = measurement.to_string();
+    }
+
+    pub fn set_credentials(&mut self, username: &str, password: &str) {
+        self.username = username.to_string();
+        self.password = password.to_string();
+    }
+
+    pub fn set_topics(&mut self, topics: MqttTopics) {
+        self.topics = topics;
+    }
+
+    /// Opens the connections to an mqtts:// broker.
+    pub fn set_tls_connector(&mut self, tls: Box<dyn Connector>) {
+        self.tls = Some(tls);
+    }
+
+    pub fn start(&mut self) -> Result<()>
+    {
+        let data = self.data.clone();
+        let (tls, server) = split_scheme(&self.server);
+        let topics = self.topics.clone();
+        let options = MqttOptions { client_id: format!("currentlogger-{}", self.tag),
+                                    keep_alive: KEEP_ALIVE_S,
+                                    username: self.username.clone(),
+                                    password: self.password.clone(),
+                                    will: Some(Publish::new(&topics.status, b"offline", 1, true)) };
+        let mut connector: Box<dyn Connector> = match (tls, self.tls.take()) {
+            (false, _) => Box::new(TcpConnector),
+            (true, Some(tls)) => tls,
+            (true, None) => return Err(anyhow!("No TLS support for {}", self.server)),
+        };
+        let _th = thread::spawn(move || {
+            info!("Start MQTT thread.");
+            let mut client: Option<MqttClient> = None;
+            loop {
+                if client.is_none() {
+                    client = Self::connect(connector.as_mut(), &server, &options, &topics);
+                    if client.is_none() {
+                        thread::sleep(Duration::from_millis(RETRY_MS));
+                        continue;
+                    }
+                }
+                if let Err(e) = Self::exchange(client.as_mut().unwrap(), &data, &topics) {
+                    info!("MQTT {}", e);
+                    client = None;
+                    if let Some(front) = data.lock().unwrap().outbox.front_mut() {
+                        front.dup = front.sent || front.dup;
+                        front.sent = false;
+                    }
+                    thread::sleep(Duration::from_millis(RETRY_MS));
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // Connects, tells that the logger is online and subscribes to the commands.
+    fn connect(connector: &mut dyn Connector, server: &str, options: &MqttOptions, topics: &MqttTopics) -> Option<MqttClient>
+    {
+        let addr = Transfer::resolve(server)?;
+        let name = server.rsplit_once(':').map(|(name, _)| name).unwrap_or(server);
+        let timeout = Duration::from_millis(CONNECT_TIMEOUT_MS);
+        let result = connector.connect(name, addr, timeout)
+            .and_then(|conn| MqttClient::connect(conn, options, timeout))
+            .and_then(|mut client| {
+                client.publish(&Publish::new(&topics.status, b"online", 1, true), false)?;
+                client.subscribe(&topics.command, 1)?;
+                Ok(client)
+            });
+        match result {
+            Ok(client) => {
+                info!("MQTT connected to {}", server);
+                Some(client)
+            },
+            Err(e) => { info!("MQTT {}: {}", server, e); None },
+        }
+    }
+
+    // Sends the front of the outbox, one at a time, and handles what the broker sent.
+    fn exchange(client: &mut MqttClient, data: &Arc<Mutex<MqttData>>, topics: &MqttTopics) -> Result<()>
+    {
+        let mut lck = data.lock().unwrap();
+        if let Some(front) = lck.outbox.front_mut() {
+            if front.sent == false {
+                front.id = client.publish(&front.message, front.dup)?;
+                front.sent = true;
+            }
+        }
+        drop(lck);
+        while let Some(incoming) = client.poll()? {
+            let mut lck = data.lock().unwrap();
+            match incoming {
+                Incoming::PubAck(id) if lck.outbox.front().map(|f| f.sent && f.id == id) == Some(true) => {
+                    let front = lck.outbox.pop_front().unwrap();
+                    if front.records > 0 {
+                        // Only now the records may leave the buffer.
+                        lck.acked += front.records;
+                        lck.pending = 0;
+                    }
+                },
+                Incoming::Publish(message) if message.topic == topics.command => {
+                    let text = String::from_utf8_lossy(&message.payload).to_string();
+                    match RemoteCommand::parse(&text) {
+                        Some(command) => {
+                            info!("MQTT command {:?}", command);
+                            lck.commands.push_back(command);
+                        },
+                        None => { info!("Unknown command: {}", text); },
+                    }
+                },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn queue(&mut self, topic: &str, payload: String, records: usize)
+    {
+        let mut lck = self.data.lock().unwrap();
+        if records == 0 && lck.outbox.len() >= MAX_QUEUED {
+            // The front may be in flight and the records must stay, the oldest other message goes.
+            if let Some(index) = lck.outbox.iter().skip(1).position(|o| o.records == 0) {
+                info!("MQTT queue full, dropped a message");
+                lck.outbox.remove(index + 1);
+            }
+        }
+        lck.outbox.push_back(Outgoing { message: Publish::new(topic, payload.as_bytes(), 1, false),
+                                        records: records, id: 0, sent: false, dup: false });
+    }
+
+    fn stamp(&self) -> String {
+        format!("\"measurement\": \"{}\", \"tag\": \"{}\"", json_escape(&self.measurement), json_escape(&self.tag))
+    }
+}
+
+impl Uplink for MqttUplink {
+    fn set_transfer_data(&mut self, data: &[CurrentLog]) -> usize
+    {
+        if data.is_empty() {
+            return 0;
+        }
+        let lck = self.data.lock().unwrap();
+        if lck.pending > 0 || lck.acked > 0 {
+            return 0;
+        }
+        drop(lck);
+        let (body, count) = Transfer::build_body(data, &self.measurement, &self.tag);
+        let topic = self.topics.samples.clone();
+        self.queue(&topic, body, count);
+        self.data.lock().unwrap().pending = count;
+        count
+    }
+
+    fn take_acknowledged(&mut self) -> usize
+    {
+        let mut lck = self.data.lock().unwrap();
+        let acked = lck.acked;
+        lck.acked = 0;
+        acked
+    }
+
+    fn send_event(&mut self, event: &str, battery: &BatteryState) -> bool
+    {
+        let body = Transfer::build_event(event, battery, &self.measurement, &self.tag);
+        let topic = self.topics.event.clone();
+        self.queue(&topic, body, 0);
+        true
+    }
+
+    fn is_idle(&self) -> bool
+    {
+        self.data.lock().unwrap().outbox.is_empty()
+    }
+
+    fn session(&mut self, logging: bool, interval: u32, reason: &str)
+    {
+        let body = format!("{{ {}, \"logging\": {}, \"interval\": {}, \"reason\": \"{}\" }}",
+                           self.stamp(), logging, interval, json_escape(reason));
+        let topic = self.topics.session.clone();
+        self.queue(&topic, body, 0);
+    }
+
+    fn statistics(&mut self, stats: &SessionStats)
+    {
+        let body = format!("{{ {}, \"samples\": {}, \"duration\": {}, \"current_min\": {:.5}, \"current_max\": {:.5}, \
+                            \"current_avg\": {:.5}, \"voltage_min\": {:.5}, \"voltage_max\": {:.5}, \"voltage_avg\": {:.5}, \
+                            \"power_max\": {:.5}, \"power_avg\": {:.5}, \"energy\": {:.6} }}",
+                           self.stamp(), stats.samples, stats.duration, stats.current_min, stats.current_max,
+                           stats.current_avg(), stats.voltage_min, stats.voltage_max, stats.voltage_avg(),
+                           stats.power_max, stats.power_avg(), stats.energy());
+        let topic = self.topics.stats.clone();
+        self.queue(&topic, body, 0);
+    }
+
+    fn alert(&mut self, alert: &str, detail: &str)
+    {
+        let body = format!("{{ {}, \"alert\": \"{}\", \"detail\": \"{}\" }}", self.stamp(), json_escape(alert), json_escape(detail));
+        let topic = self.topics.alert.clone();
+        self.queue(&topic, body, 0);
+    }
+
+    fn take_command(&mut self) -> Option<RemoteCommand>
+    {
+        self.data.lock().unwrap().commands.pop_front()
+    }
+}
+
+/// Splits "mqtt://host[:port]" or "mqtts://host[:port]" into TLS and host:port with the default port.
+/// Without a scheme it is plain host:port.
+pub fn split_scheme(server: &str) -> (bool, String) {
+    let (tls, rest) = if let Some(rest) = server.strip_prefix("mqtts://") {
+        (true, rest)
+    }
+    else if let Some(rest) = server.strip_prefix("mqtt://") {
+        (false, rest)
+    }
+    else {
+        return (false, server.to_string());
+    };
+    let host = rest.trim_end_matches('/');
+    if host.contains(':') {
+        (tls, host.to_string())
+    }
+    else {
+        (tls, format!("{}:{}", host, if tls { 8883 } else { 1883 }))
+    }
+}
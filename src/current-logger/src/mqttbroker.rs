@@ -0,0 +1,204 @@
+// A small MQTT broker standing in for Mosquitto, to try the MQTT uplink on a host and in the tests.
+//
+// QoS 1 is acknowledged and delivered as QoS 0, retained messages and the last will are kept,
+// and a client silent for 1.5 keep-alive periods is dropped.
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use log::*;
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::thread;
+use anyhow::{anyhow, bail, Result};
+
+use crate::mqttclient::{Packet, Publish, MqttOptions, get_bytes, topic_matches, puback_packet,
+                        CONNECT, CONNACK, PUBLISH, SUBSCRIBE, SUBACK, PINGREQ, PINGRESP, DISCONNECT};
+
+type Observer = Box<dyn FnMut(&Publish) + Send>;
+
+struct Client {
+    client_id: String,
+    stream: TcpStream,
+    filters: Vec<String>,
+}
+
+#[derive(Default)]
+struct Broker {
+    clients: HashMap<u64, Client>,
+    retained: HashMap<String, Publish>,
+    observer: Option<Observer>,
+    hold_acks: bool,
+}
+
+impl Broker {
+    fn publish(&mut self, message: &Publish) {
+        if let Some(observer) = self.observer.as_mut() {
+            observer(message);
+        }
+        if message.retain {
+            if message.payload.is_empty() {
+                self.retained.remove(&message.topic);
+            }
+            else {
+                self.retained.insert(message.topic.clone(), message.clone());
+            }
+        }
+        let forward = Publish::new(&message.topic, &message.payload, 0, false).packet(false).encode();
+        for client in self.clients.values_mut() {
+            if client.filters.iter().any(|f| topic_matches(f, &message.topic)) {
+                let _ = client.stream.write_all(&forward);
+            }
+        }
+    }
+}
+
+pub struct MqttBroker {
+    listener: TcpListener,
+    broker: Arc<Mutex<Broker>>,
+}
+
+impl MqttBroker {
+    /// Listens on `addr`, port 0 takes a free one.
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<MqttBroker> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(MqttBroker { listener: listener, broker: Arc::new(Mutex::new(Broker::default())) })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Called with every message published, e.g. to print it.
+    pub fn set_observer(&mut self, observer: Observer) {
+        self.broker.lock().unwrap().observer = Some(observer);
+    }
+
+    /// Leaves the QoS 1 messages unacknowledged while set, like a broker that is slow to take them.
+    pub fn set_hold_acks(&self, hold: bool) {
+        self.broker.lock().unwrap().hold_acks = hold;
+    }
+
+    /// Publishes `message` to the subscribers as if a client had sent it.
+    pub fn publish(&self, message: &Publish) {
+        self.broker.lock().unwrap().publish(message);
+    }
+
+    /// Breaks the connection of `client_id` without a DISCONNECT, so that its last will is published.
+    pub fn drop_client(&self, client_id: &str) -> bool {
+        let broker = self.broker.lock().unwrap();
+        match broker.clients.values().find(|c| c.client_id == client_id) {
+            Some(client) => client.stream.shutdown(Shutdown::Both).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn start(&self) -> Result<()> {
+        let listener = self.listener.try_clone()?;
+        let broker = self.broker.clone();
+        let _th = thread::spawn(move || {
+            info!("Start MQTT broker thread.");
+            for (id, stream) in listener.incoming().enumerate() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => { info!("{}", e); continue; },
+                };
+                let broker = broker.clone();
+                thread::spawn(move || {
+                    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                    let will = match serve(id as u64, stream, &broker) {
+                        Ok(()) => None,
+                        Err((e, will)) => { info!("{} {}", peer, e); will },
+                    };
+                    let mut broker = broker.lock().unwrap();
+                    broker.clients.remove(&(id as u64));
+                    if let Some(will) = will {
+                        broker.publish(&will);
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+}
+
+// Serves one client until it disconnects. On a lost connection the will is returned to be published.
+fn serve(id: u64, mut stream: TcpStream, broker: &Arc<Mutex<Broker>>) -> std::result::Result<(), (anyhow::Error, Option<Publish>)> {
+    let mut input = Vec::new();
+    let connect = read_packet(&mut stream, &mut input).map_err(|e| (e, None))?;
+    if connect.kind != CONNECT {
+        return Err((anyhow!("Expected CONNECT"), None));
+    }
+    let MqttOptions { client_id, keep_alive, username, will, .. } = MqttOptions::parse(&connect).map_err(|e| (e, None))?;
+    info!("{} connected as {}{}", stream.peer_addr().map(|a| a.to_string()).unwrap_or_default(), client_id,
+          if username.is_empty() { "".to_string() } else { format!(" user {}", username) });
+    if keep_alive > 0 {
+        let _ = stream.set_read_timeout(Some(Duration::from_millis(keep_alive as u64 * 1500)));
+    }
+    let writer = stream.try_clone().map_err(|e| (e.into(), None))?;
+    broker.lock().unwrap().clients.insert(id, Client { client_id: client_id, stream: writer, filters: Vec::new() });
+    let result = (|| -> Result<()> {
+        reply(broker, &mut stream, Packet::new(CONNACK, 0, vec![0, 0]))?;
+        loop {
+            let packet = read_packet(&mut stream, &mut input)?;
+            match packet.kind {
+                PUBLISH => {
+                    let message = Publish::parse(&packet)?;
+                    if message.qos > 0 && broker.lock().unwrap().hold_acks == false {
+                        reply(broker, &mut stream, puback_packet(message.id))?;
+                    }
+                    broker.lock().unwrap().publish(&message);
+                },
+                SUBSCRIBE => {
+                    let packet_id = packet.id()?;
+                    let mut pos = 2;
+                    let mut granted = packet_id.to_be_bytes().to_vec();
+                    let mut filters = Vec::new();
+                    while pos < packet.body.len() {
+                        let (filter, next) = get_bytes(&packet.body, pos)?;
+                        filters.push(String::from_utf8(filter)?);
+                        pos = next + 1;
+                        granted.push(0);
+                    }
+                    let mut broker = broker.lock().unwrap();
+                    let retained: Vec<Publish> = broker.retained.values()
+                        .filter(|m| filters.iter().any(|f| topic_matches(f, &m.topic))).cloned().collect();
+                    let client = broker.clients.get_mut(&id).unwrap();
+                    client.stream.write_all(&Packet::new(SUBACK, 0, granted).encode())?;
+                    for m in retained {
+                        client.stream.write_all(&Publish::new(&m.topic, &m.payload, 0, true).packet(false).encode())?;
+                    }
+                    info!("Subscribed to {:?}", filters);
+                    client.filters.extend(filters);
+                },
+                PINGREQ => { reply(broker, &mut stream, Packet::new(PINGRESP, 0, Vec::new()))?; },
+                DISCONNECT => return Ok(()),
+                _ => {},
+            }
+        }
+    })();
+    result.map_err(|e| (e, will))
+}
+
+// Under the lock, so that it does not mix with a message forwarded by another client's thread.
+fn reply(broker: &Arc<Mutex<Broker>>, stream: &mut TcpStream, packet: Packet) -> Result<()> {
+    let _lck = broker.lock().unwrap();
+    stream.write_all(&packet.encode())?;
+    Ok(())
+}
+
+fn read_packet(stream: &mut TcpStream, input: &mut Vec<u8>) -> Result<Packet> {
+    loop {
+        if let Some((packet, len)) = Packet::decode(input)? {
+            input.drain(..len);
+            return Ok(packet);
+        }
+        let mut buf = [0u8; 1024];
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            bail!("Connection closed");
+        }
+        input.extend_from_slice(&buf[..n]);
+    }
+}
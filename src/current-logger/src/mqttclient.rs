@@ -0,0 +1,357 @@
+// Minimal MQTT 3.1.1: the packets the logger and the broker stand-in need, and a client over a Connection.
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+use anyhow::{anyhow, bail, Result};
+
+use crate::hal::Connection;
+
+pub const CONNECT: u8 = 1;
+pub const CONNACK: u8 = 2;
+pub const PUBLISH: u8 = 3;
+pub const PUBACK: u8 = 4;
+pub const SUBSCRIBE: u8 = 8;
+pub const SUBACK: u8 = 9;
+pub const PINGREQ: u8 = 12;
+pub const PINGRESP: u8 = 13;
+pub const DISCONNECT: u8 = 14;
+
+pub const MAX_PACKET: usize = 64 * 1024;
+const POLL_MS: u64 = 50;
+
+/// A packet split into its type, the flags of the fixed header and the rest.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub kind: u8,
+    pub flags: u8,
+    pub body: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(kind: u8, flags: u8, body: Vec<u8>) -> Packet {
+        Packet { kind: kind, flags: flags, body: body }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![self.kind << 4 | self.flags];
+        let mut len = self.body.len();
+        loop {
+            let byte = (len % 128) as u8;
+            len /= 128;
+            out.push(if len > 0 { byte | 0x80 } else { byte });
+            if len == 0 {
+                break;
+            }
+        }
+        out.extend_from_slice(&self.body);
+        out
+    }
+
+    /// Takes one packet from the front of `buf` with its length, None until it is complete.
+    pub fn decode(buf: &[u8]) -> Result<Option<(Packet, usize)>> {
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let mut len = 0usize;
+        let mut pos = 1;
+        loop {
+            let byte = match buf.get(pos) {
+                Some(byte) => *byte,
+                None => return Ok(None),
+            };
+            len |= ((byte & 0x7F) as usize) << (7 * (pos - 1));
+            pos += 1;
+            if byte & 0x80 == 0 {
+                break;
+            }
+            if pos > 4 {
+                bail!("Invalid packet length");
+            }
+        }
+        if len > MAX_PACKET {
+            bail!("Packet of {} bytes too long", len);
+        }
+        if buf.len() < pos + len {
+            return Ok(None);
+        }
+        Ok(Some((Packet::new(buf[0] >> 4, buf[0] & 0x0F, buf[pos..pos + len].to_vec()), pos + len)))
+    }
+
+    /// The packet identifier at the start of the body, e.g. of PUBACK and SUBACK.
+    pub fn id(&self) -> Result<u16> {
+        match self.body.get(..2) {
+            Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+            None => Err(anyhow!("Packet {} without identifier", self.kind)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Publish {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub qos: u8,
+    pub retain: bool,
+    pub id: u16,            // Only with QoS 1
+}
+
+impl Publish {
+    pub fn new(topic: &str, payload: &[u8], qos: u8, retain: bool) -> Publish {
+        Publish { topic: topic.to_string(), payload: payload.to_vec(), qos: qos, retain: retain, id: 0 }
+    }
+
+    pub fn packet(&self, dup: bool) -> Packet {
+        let mut body = Vec::new();
+        put_bytes(&mut body, self.topic.as_bytes());
+        if self.qos > 0 {
+            body.extend_from_slice(&self.id.to_be_bytes());
+        }
+        body.extend_from_slice(&self.payload);
+        let flags = (dup as u8) << 3 | self.qos << 1 | self.retain as u8;
+        Packet::new(PUBLISH, flags, body)
+    }
+
+    pub fn parse(packet: &Packet) -> Result<Publish> {
+        let (topic, mut pos) = get_bytes(&packet.body, 0)?;
+        let qos = packet.flags >> 1 & 0x03;
+        let mut id = 0;
+        if qos > 0 {
+            let b = packet.body.get(pos..pos + 2).ok_or(anyhow!("PUBLISH without identifier"))?;
+            id = u16::from_be_bytes([b[0], b[1]]);
+            pos += 2;
+        }
+        Ok(Publish { topic: String::from_utf8(topic)?, payload: packet.body[pos..].to_vec(), qos: qos,
+                     retain: packet.flags & 0x01 != 0, id: id })
+    }
+}
+
+/// What the client sends in CONNECT. Empty user and password are left out.
+#[derive(Debug, Clone)]
+pub struct MqttOptions {
+    pub client_id: String,
+    pub keep_alive: u16,        // s
+    pub username: String,
+    pub password: String,
+    pub will: Option<Publish>,
+}
+
+impl MqttOptions {
+    pub fn packet(&self) -> Packet {
+        let mut body = Vec::new();
+        put_bytes(&mut body, b"MQTT");
+        body.push(4);       // 3.1.1
+        let mut flags = 0x02u8;     // Clean session
+        if let Some(will) = &self.will {
+            flags |= 0x04 | will.qos << 3 | (will.retain as u8) << 5;
+        }
+        if self.username.is_empty() == false {
+            flags |= 0x80;
+            if self.password.is_empty() == false {
+                flags |= 0x40;
+            }
+        }
+        body.push(flags);
+        body.extend_from_slice(&self.keep_alive.to_be_bytes());
+        put_bytes(&mut body, self.client_id.as_bytes());
+        if let Some(will) = &self.will {
+            put_bytes(&mut body, will.topic.as_bytes());
+            put_bytes(&mut body, &will.payload);
+        }
+        if self.username.is_empty() == false {
+            put_bytes(&mut body, self.username.as_bytes());
+            if self.password.is_empty() == false {
+                put_bytes(&mut body, self.password.as_bytes());
+            }
+        }
+        Packet::new(CONNECT, 0, body)
+    }
+
+    /// Reads a CONNECT back, for the broker stand-in.
+    pub fn parse(packet: &Packet) -> Result<MqttOptions> {
+        let (protocol, pos) = get_bytes(&packet.body, 0)?;
+        if protocol != b"MQTT" || packet.body.len() < pos + 4 {
+            bail!("Not an MQTT 3.1.1 CONNECT");
+        }
+        let flags = packet.body[pos + 1];
+        let keep_alive = u16::from_be_bytes([packet.body[pos + 2], packet.body[pos + 3]]);
+        let (client_id, mut pos) = get_bytes(&packet.body, pos + 4)?;
+        let mut will = None;
+        if flags & 0x04 != 0 {
+            let (topic, next) = get_bytes(&packet.body, pos)?;
+            let (payload, next) = get_bytes(&packet.body, next)?;
+            will = Some(Publish::new(&String::from_utf8(topic)?, &payload, flags >> 3 & 0x03, flags & 0x20 != 0));
+            pos = next;
+        }
+        let mut username = Vec::new();
+        let mut password = Vec::new();
+        if flags & 0x80 != 0 {
+            let (user, next) = get_bytes(&packet.body, pos)?;
+            username = user;
+            pos = next;
+        }
+        if flags & 0x40 != 0 {
+            password = get_bytes(&packet.body, pos)?.0;
+        }
+        Ok(MqttOptions { client_id: String::from_utf8(client_id)?, keep_alive: keep_alive,
+                         username: String::from_utf8(username)?, password: String::from_utf8(password)?, will: will })
+    }
+}
+
+pub fn subscribe_packet(id: u16, topic: &str, qos: u8) -> Packet {
+    let mut body = id.to_be_bytes().to_vec();
+    put_bytes(&mut body, topic.as_bytes());
+    body.push(qos);
+    Packet::new(SUBSCRIBE, 0x02, body)
+}
+
+pub fn puback_packet(id: u16) -> Packet {
+    Packet::new(PUBACK, 0, id.to_be_bytes().to_vec())
+}
+
+fn put_bytes(out: &mut Vec<u8>, s: &[u8]) {
+    out.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    out.extend_from_slice(s);
+}
+
+// A length prefixed string at `pos`, and the position after it.
+pub fn get_bytes(body: &[u8], pos: usize) -> Result<(Vec<u8>, usize)> {
+    let b = body.get(pos..pos + 2).ok_or(anyhow!("Packet too short"))?;
+    let len = u16::from_be_bytes([b[0], b[1]]) as usize;
+    let s = body.get(pos + 2..pos + 2 + len).ok_or(anyhow!("Packet too short"))?;
+    Ok((s.to_vec(), pos + 2 + len))
+}
+
+/// True when `topic` matches `filter` with its + and # wildcards.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    let mut levels = topic.split('/');
+    for f in filter.split('/') {
+        match (f, levels.next()) {
+            ("#", _) => return true,
+            ("+", Some(_)) => {},
+            (f, Some(level)) if f == level => {},
+            _ => return false,
+        }
+    }
+    levels.next().is_none()
+}
+
+/// Received from the broker.
+#[derive(Debug)]
+pub enum Incoming {
+    Publish(Publish),
+    PubAck(u16),
+    SubAck(u16),
+}
+
+/// One session with a broker. QoS 1 messages received are acknowledged, PINGREQ is sent by `poll`.
+pub struct MqttClient {
+    conn: Box<dyn Connection>,
+    input: Vec<u8>,
+    next_id: u16,
+    keep_alive: Duration,
+    last_sent: Instant,
+    ping_sent: Option<Instant>,
+}
+
+impl MqttClient {
+    /// Sends CONNECT and waits up to `timeout` for the CONNACK.
+    pub fn connect(conn: Box<dyn Connection>, options: &MqttOptions, timeout: Duration) -> Result<MqttClient> {
+        let mut client = MqttClient { conn: conn, input: Vec::new(), next_id: 1,
+                                      keep_alive: Duration::from_secs(options.keep_alive as u64),
+                                      last_sent: Instant::now(), ping_sent: None };
+        client.send(&options.packet())?;
+        client.conn.set_read_timeout(timeout)?;
+        let packet = client.read_packet()?.ok_or(anyhow!("No CONNACK from the broker"))?;
+        if packet.kind != CONNACK || packet.body.len() < 2 {
+            bail!("Expected CONNACK, got packet {}", packet.kind);
+        }
+        if packet.body[1] != 0 {
+            bail!("Broker refused the connection: {}", packet.body[1]);
+        }
+        client.conn.set_read_timeout(Duration::from_millis(POLL_MS))?;
+        Ok(client)
+    }
+
+    /// Sends `message` and returns its identifier, by which the PUBACK of QoS 1 is matched.
+    /// `dup` marks a message sent before on a lost connection.
+    pub fn publish(&mut self, message: &Publish, dup: bool) -> Result<u16> {
+        let mut message = message.clone();
+        if message.qos > 0 {
+            message.id = self.take_id();
+        }
+        self.send(&message.packet(dup))?;
+        Ok(message.id)
+    }
+
+    pub fn subscribe(&mut self, topic: &str, qos: u8) -> Result<u16> {
+        let id = self.take_id();
+        self.send(&subscribe_packet(id, topic, qos))?;
+        Ok(id)
+    }
+
+    /// Waits shortly for a packet from the broker and keeps the connection alive.
+    pub fn poll(&mut self) -> Result<Option<Incoming>> {
+        if self.keep_alive > Duration::ZERO {
+            if let Some(sent) = self.ping_sent {
+                if sent.elapsed() > self.keep_alive {
+                    bail!("No PINGRESP from the broker");
+                }
+            }
+            else if self.last_sent.elapsed() >= self.keep_alive / 2 {
+                self.send(&Packet::new(PINGREQ, 0, Vec::new()))?;
+                self.ping_sent = Some(Instant::now());
+            }
+        }
+        let packet = match self.read_packet()? {
+            Some(packet) => packet,
+            None => return Ok(None),
+        };
+        match packet.kind {
+            PUBLISH => {
+                let message = Publish::parse(&packet)?;
+                if message.qos > 0 {
+                    self.send(&puback_packet(message.id))?;
+                }
+                Ok(Some(Incoming::Publish(message)))
+            },
+            PUBACK => Ok(Some(Incoming::PubAck(packet.id()?))),
+            SUBACK => Ok(Some(Incoming::SubAck(packet.id()?))),
+            PINGRESP => { self.ping_sent = None; Ok(None) },
+            kind => bail!("Unexpected packet {}", kind),
+        }
+    }
+
+    pub fn disconnect(&mut self) -> Result<()> {
+        self.send(&Packet::new(DISCONNECT, 0, Vec::new()))
+    }
+
+    fn take_id(&mut self) -> u16 {
+        let id = self.next_id;
+        self.next_id = if self.next_id == u16::MAX { 1 } else { self.next_id + 1 };
+        id
+    }
+
+    fn send(&mut self, packet: &Packet) -> Result<()> {
+        self.conn.write_all(&packet.encode())?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    // A packet when one is complete within the read timeout.
+    fn read_packet(&mut self) -> Result<Option<Packet>> {
+        loop {
+            if let Some((packet, len)) = Packet::decode(&self.input)? {
+                self.input.drain(..len);
+                return Ok(Some(packet));
+            }
+            let mut buf = [0u8; 512];
+            match self.conn.read(&mut buf) {
+                Ok(0) => bail!("Connection closed by the broker"),
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
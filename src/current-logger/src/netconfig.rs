@@ -0,0 +1,127 @@
+// Address configuration of the station interface: DHCP or a static IP, and the hostname.
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use std::net::Ipv4Addr;
+use anyhow::{anyhow, Result};
+
+pub const DEFAULT_HOSTNAME: &str = "currentlogger";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StaticIp {
+    pub ip: Ipv4Addr,
+    pub prefix: u8,         // Subnet mask length
+    pub gateway: Ipv4Addr,
+    pub dns: Option<Ipv4Addr>,
+}
+
+impl StaticIp {
+    /// Parses "address/prefix", the gateway and the DNS server. An empty address or "dhcp" means DHCP.
+    /// Without a gateway the first address of the subnet is used, the DNS server defaults to the gateway.
+    pub fn parse(ip: &str, gateway: &str, dns: &str) -> Result<Option<StaticIp>> {
+        let ip = ip.trim();
+        if ip.is_empty() || ip == "dhcp" {
+            return Ok(None);
+        }
+        let (addr, prefix) = match ip.split_once('/') {
+            Some((addr, prefix)) => (addr, prefix.parse::<u8>().map_err(|_| anyhow!("Invalid prefix: {}", ip))?),
+            None => (ip, 24),
+        };
+        if prefix == 0 || prefix > 30 {
+            return Err(anyhow!("Invalid prefix: {}", ip));
+        }
+        let addr = addr.parse::<Ipv4Addr>().map_err(|_| anyhow!("Invalid IP address: {}", ip))?;
+        let gateway = match gateway.trim() {
+            "" => {
+                let mask = u32::MAX << (32 - prefix);
+                Ipv4Addr::from((u32::from(addr) & mask) + 1)
+            },
+            gw => gw.parse::<Ipv4Addr>().map_err(|_| anyhow!("Invalid gateway: {}", gw))?,
+        };
+        let dns = match dns.trim() {
+            "" => Some(gateway),
+            dns => Some(dns.parse::<Ipv4Addr>().map_err(|_| anyhow!("Invalid DNS server: {}", dns))?),
+        };
+        Ok(Some(StaticIp { ip: addr, prefix: prefix, gateway: gateway, dns: dns }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetConfig {
+    pub hostname: String,
+    pub static_ip: Option<StaticIp>,    // None uses DHCP
+}
+
+impl Default for NetConfig {
+    fn default() -> Self {
+        NetConfig::new()
+    }
+}
+
+impl NetConfig {
+    pub fn new() -> NetConfig {
+        NetConfig { hostname: DEFAULT_HOSTNAME.to_string(), static_ip: None }
+    }
+
+    pub fn parse(hostname: &str, ip: &str, gateway: &str, dns: &str) -> Result<NetConfig> {
+        if valid_hostname(hostname) == false {
+            return Err(anyhow!("Invalid hostname: {}", hostname));
+        }
+        Ok(NetConfig { hostname: hostname.to_string(), static_ip: StaticIp::parse(ip, gateway, dns)? })
+    }
+}
+
+/// Hostnames are letters, digits and '-' but not at either end, up to 30 characters as DHCP takes them.
+pub fn valid_hostname(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 30 && !name.starts_with('-') && !name.ends_with('-') &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn static_ip() {
+        let ip = StaticIp::parse("192.168.10.50/24", "192.168.10.254", "1.1.1.1").unwrap().unwrap();
+        assert_eq!(ip, StaticIp { ip: Ipv4Addr::new(192, 168, 10, 50), prefix: 24,
+                                  gateway: Ipv4Addr::new(192, 168, 10, 254), dns: Some(Ipv4Addr::new(1, 1, 1, 1)) });
+        assert_eq!(StaticIp::parse("", "", "").unwrap(), None);
+        assert_eq!(StaticIp::parse(" dhcp ", "10.0.0.1", "").unwrap(), None);
+    }
+
+    #[test]
+    fn missing_mask_and_gateway() {
+        // The mask defaults to /24, the gateway to the first address and the DNS server to the gateway.
+        let ip = StaticIp::parse("10.1.2.3", "", "").unwrap().unwrap();
+        assert_eq!(ip.prefix, 24);
+        assert_eq!(ip.gateway, Ipv4Addr::new(10, 1, 2, 1));
+        assert_eq!(ip.dns, Some(ip.gateway));
+        let ip = StaticIp::parse("172.16.5.9/20", " ", "").unwrap().unwrap();
+        assert_eq!(ip.gateway, Ipv4Addr::new(172, 16, 0, 1));
+    }
+
+    #[test]
+    fn invalid_static_ip() {
+        assert!(StaticIp::parse("192.168.1.256/24", "", "").is_err());
+        assert!(StaticIp::parse("192.168.1/24", "", "").is_err());
+        assert!(StaticIp::parse("192.168.1.5/", "", "").is_err());
+        assert!(StaticIp::parse("192.168.1.5/0", "", "").is_err());
+        assert!(StaticIp::parse("192.168.1.5/31", "", "").is_err());
+        assert!(StaticIp::parse("192.168.1.5/24", "192.168.1.300", "").is_err());
+        assert!(StaticIp::parse("192.168.1.5/24", "", "dns.example.com").is_err());
+    }
+
+    #[test]
+    fn hostnames() {
+        assert!(valid_hostname("currentlogger-2"));
+        assert!(valid_hostname(&"a".repeat(30)));
+        assert!(valid_hostname(&"a".repeat(31)) == false);
+        assert!(valid_hostname("") == false);
+        assert!(valid_hostname("-logger") == false);
+        assert!(valid_hostname("logger-") == false);
+        assert!(valid_hostname("current_logger") == false);
+        assert!(valid_hostname("logger.local") == false);
+        assert!(NetConfig::parse("bad_name", "", "", "").is_err());
+        assert_eq!(NetConfig::parse("ch1", "", "", "").unwrap().hostname, "ch1");
+    }
+}
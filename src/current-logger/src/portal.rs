@@ -0,0 +1,114 @@
+// SoftAP captive portal for entering the WiFi and server settings.
+// It runs instead of the logger and restarts the device when it is done.
+
+use std::time::Duration;
+use std::thread;
+use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
+
+use esp_idf_hal::peripheral;
+use esp_idf_svc::{eventloop::EspSystemEventLoop, wifi::EspWifi};
+use esp_idf_svc::http::server::{EspHttpServer, Configuration as HttpConfiguration};
+use embedded_svc::wifi::{AccessPointConfiguration, AuthMethod, Wifi, Configuration};
+use embedded_svc::http::Method;
+use embedded_svc::io::{Read, Write};
+use anyhow::Result;
+use log::*;
+
+use crate::hal::KeyValueStore;
+use crate::provision::{self, captive_dns_reply};
+use crate::settings::Settings;
+
+pub const PORTAL_SSID: &str = "CurrentLogger-Setup";
+const PORTAL_TIMEOUT_MS: u64 = 300_000;
+const FORM_MAX: usize = 1024;
+
+/// Opens the portal with the current `settings` and does not return, the device restarts after saving or after a timeout.
+pub fn run<S: KeyValueStore + Send + 'static>(
+    modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
+    store: S,
+    settings: Settings,
+) -> Result<()>
+{
+    let store = Arc::new(Mutex::new(store));
+
+    let sys_event_loop = EspSystemEventLoop::take()?;
+    let mut wifi = EspWifi::new(modem, sys_event_loop, None)?;
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: PORTAL_SSID.into(),
+        auth_method: AuthMethod::None,
+        channel: 1,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    let ip = wifi.ap_netif().get_ip_info()?.ip;
+    info!("Provisioning portal {} at http://{}/", PORTAL_SSID, ip);
+
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+    let form = Arc::new(Mutex::new(settings));
+    let page = form.clone();
+    server.fn_handler("/", Method::Get, move |req| {
+        let body = provision::form_page(&page.lock().unwrap());
+        req.into_ok_response()?.write_all(body.as_bytes())?;
+        Ok(())
+    })?;
+    let saved = form.clone();
+    let save_store = store.clone();
+    server.fn_handler("/save", Method::Post, move |mut req| {
+        let mut buf = [0u8; FORM_MAX];
+        let mut len = 0;
+        while len < buf.len() {
+            let n = req.read(&mut buf[len..])?;
+            if n == 0 {
+                break;
+            }
+            len += n;
+        }
+        let mut settings = saved.lock().unwrap();
+        provision::update_from_form(&mut settings, &String::from_utf8_lossy(&buf[..len]));
+        info!("Provisioned ssid={} server={} name={}", settings.ssid, settings.server, settings.name);
+        let mut store = save_store.lock().unwrap();
+        provision::save_form(&settings, &mut *store)?;
+        provision::request_portal(&mut *store, false)?;
+        req.into_ok_response()?.write_all(b"<html><body><h2>Saved, restarting..</h2></body></html>")?;
+        thread::spawn(|| {
+            thread::sleep(Duration::from_secs(1));
+            unsafe { esp_idf_sys::esp_restart(); }
+        });
+        Ok(())
+    })?;
+    // Phones probe various URLs to detect a captive portal, all of them lead to the form.
+    let location = format!("http://{}/", ip);
+    server.fn_handler("/*", Method::Get, move |req| {
+        req.into_response(302, Some("Found"), &[("Location", location.as_str())])?;
+        Ok(())
+    })?;
+
+    let octets = ip.octets();
+    let _th = thread::spawn(move || {
+        info!("Start DNS thread.");
+        if let Err(e) = serve_dns(octets) {
+            info!("{:?}", e);
+        }
+    });
+
+    thread::sleep(Duration::from_millis(PORTAL_TIMEOUT_MS));
+    info!("Provisioning timed out.");
+    provision::portal_timed_out(&mut *store.lock().unwrap())?;
+    drop(server);
+    unsafe { esp_idf_sys::esp_restart(); }
+}
+
+fn serve_dns(ip: [u8; 4]) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:53")?;
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, peer) = socket.recv_from(&mut buf)?;
+        if let Some(reply) = captive_dns_reply(&buf[..len], ip) {
+            socket.send_to(&reply, peer)?;
+        }
+    }
+}
@@ -0,0 +1,240 @@
+// Settings entered through the SoftAP portal, and the pieces of the portal that do not need ESP-IDF.
+#![allow(clippy::bool_comparison)]
+
+use anyhow::Result;
+
+use crate::hal::KeyValueStore;
+use crate::settings::{Settings, SETTINGS_VERSION, KEY_VERSION, KEY_SSID, KEY_PSK, KEY_SERVER, KEY_NAME};
+
+pub const DEFAULT_NAME: &str = "currentch1";
+
+const KEY_PORTAL: &str = "portal";
+
+// Bytes, as the WiFi configuration of ESP-IDF holds them.
+const MAX_SSID: usize = 32;
+const MAX_PSK: usize = 64;
+const MAX_SERVER: usize = 128;
+const MAX_NAME: usize = 32;
+
+/// Takes the fields of an application/x-www-form-urlencoded body into `settings`.
+/// An empty password keeps the stored one, so the form never shows it. Missing fields and
+/// values too long to store keep the stored ones as well.
+pub fn update_from_form(settings: &mut Settings, body: &str) {
+    for pair in body.split('&') {
+        let mut kv = pair.splitn(2, '=');
+        let key = kv.next().unwrap_or("");
+        let value = url_decode(kv.next().unwrap_or("")).trim().to_string();
+        let (field, max) = match key {
+            "ssid"   => (&mut settings.ssid, MAX_SSID),
+            "psk" if value.is_empty() == false => (&mut settings.psk, MAX_PSK),
+            "server" => (&mut settings.server, MAX_SERVER),
+            "name"   => (&mut settings.name, MAX_NAME),
+            _ => continue,
+        };
+        if value.len() <= max {
+            *field = value;
+        }
+    }
+}
+
+/// Stores only what the form edits, the other settings keep following cfg.toml.
+pub fn save_form(settings: &Settings, store: &mut dyn KeyValueStore) -> Result<()> {
+    store.set(KEY_SSID, &settings.ssid)?;
+    store.set(KEY_PSK, &settings.psk)?;
+    store.set(KEY_SERVER, &settings.server)?;
+    store.set(KEY_NAME, &settings.name)?;
+    store.set(KEY_VERSION, &SETTINGS_VERSION.to_string())
+}
+
+pub fn form_page(settings: &Settings) -> String {
+    format!("<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+             <title>Current Logger</title></head><body><h2>Current Logger setup</h2>\
+             <form method=\"post\" action=\"/save\">\
+             <p>WiFi SSID<br><input name=\"ssid\" value=\"{}\"></p>\
+             <p>WiFi password<br><input name=\"psk\" type=\"password\" placeholder=\"unchanged\"></p>\
+             <p>Server address:port<br><input name=\"server\" value=\"{}\"></p>\
+             <p>Device name<br><input name=\"name\" value=\"{}\"></p>\
+             <p><input type=\"submit\" value=\"Save and restart\"></p></form></body></html>",
+            html_escape(&settings.ssid), html_escape(&settings.server), html_escape(&settings.name))
+}
+
+/// The portal is requested for the next boot by storing a flag.
+pub fn request_portal(store: &mut dyn KeyValueStore, on: bool) -> Result<()> {
+    store.set(KEY_PORTAL, if on { "1" } else { "" })
+}
+
+/// Records that the portal closed without settings, so the next boot does not open it again by itself.
+pub fn portal_timed_out(store: &mut dyn KeyValueStore) -> Result<()> {
+    store.set(KEY_PORTAL, "timeout")
+}
+
+pub fn is_portal_requested(store: &dyn KeyValueStore) -> Result<bool> {
+    Ok(store.get(KEY_PORTAL)?.unwrap_or_default() == "1")
+}
+
+/// False after a portal timed out, until settings are saved in the portal.
+pub fn is_auto_portal_allowed(store: &dyn KeyValueStore) -> Result<bool> {
+    Ok(store.get(KEY_PORTAL)?.unwrap_or_default() != "timeout")
+}
+
+fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok());
+                match hex {
+                    Some(b) => { out.push(b); i += 2; },
+                    None => out.push(b'%'),
+                }
+            },
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).to_string()
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('"', "&quot;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+/// Answers every DNS A query with `ip`, so that phones open the portal page.
+pub fn captive_dns_reply(query: &[u8], ip: [u8; 4]) -> Option<Vec<u8>> {
+    // Header is 12 bytes, only standard queries with one question are answered.
+    if query.len() < 12 || query[2] & 0x80 != 0 || query[2] & 0x78 != 0 || query[4..6] != [0, 1] {
+        return None;
+    }
+    let mut end = 12;
+    while end < query.len() && query[end] != 0 {
+        end += query[end] as usize + 1;
+    }
+    end += 5;   // Terminating zero, type and class
+    if end > query.len() {
+        return None;
+    }
+    // Other types, like AAAA, get an empty answer, so that the client does not wait for one.
+    let is_a = query[end - 4..end] == [0, 1, 0, 1];
+    let mut reply = Vec::with_capacity(end + 16);
+    reply.extend_from_slice(&query[0..2]);
+    reply.extend_from_slice(&[0x81, 0x80, 0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+    reply.extend_from_slice(&query[12..end]);
+    if is_a {
+        // Name pointer to the question, type A, class IN, TTL 60 s, 4 bytes of address
+        reply.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        reply.extend_from_slice(&ip);
+    }
+    Some(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Query for `name` with id 0x1234 and recursion desired.
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut q = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            q.push(label.len() as u8);
+            q.extend_from_slice(label.as_bytes());
+        }
+        q.push(0);
+        q.extend_from_slice(&qtype.to_be_bytes());
+        q.extend_from_slice(&[0, 1]);
+        q
+    }
+
+    #[test]
+    fn url_decoding() {
+        assert_eq!(url_decode("My+Net"), "My Net");
+        assert_eq!(url_decode("a%26b%3Dc%2b"), "a&b=c+");
+        assert_eq!(url_decode("%E3%81%82"), "\u{3042}");
+        assert_eq!(url_decode("100%"), "100%");
+        assert_eq!(url_decode("%4"), "%4");
+        assert_eq!(url_decode("%zz1"), "%zz1");
+    }
+
+    #[test]
+    fn form_fields() {
+        let mut settings = Settings::new();
+        settings.psk = "secret123".to_string();
+        update_from_form(&mut settings, "ssid=Lab+WiFi&psk=&server=10.0.0.2%3A3001&name=ch2");
+        assert_eq!(settings.ssid, "Lab WiFi");
+        assert_eq!(settings.psk, "secret123");
+        assert_eq!(settings.server, "10.0.0.2:3001");
+        assert_eq!(settings.name, "ch2");
+
+        // Missing and unknown fields change nothing, a field without a value is emptied.
+        update_from_form(&mut settings, "name&color=red&psk=newsecret");
+        assert_eq!(settings.ssid, "Lab WiFi");
+        assert_eq!(settings.server, "10.0.0.2:3001");
+        assert_eq!(settings.name, "");
+        assert_eq!(settings.psk, "newsecret");
+        update_from_form(&mut settings, "");
+        assert_eq!(settings.psk, "newsecret");
+    }
+
+    #[test]
+    fn over_long_fields() {
+        let mut settings = Settings::new();
+        update_from_form(&mut settings, "ssid=Lab&psk=secret123&name=ch1");
+        let body = format!("ssid={}&psk={}&server={}&name={}", "s".repeat(MAX_SSID + 1), "p".repeat(MAX_PSK + 1),
+                           "h".repeat(MAX_SERVER + 1), "n".repeat(MAX_NAME + 1));
+        update_from_form(&mut settings, &body);
+        assert_eq!(settings.ssid, "Lab");
+        assert_eq!(settings.psk, "secret123");
+        assert_eq!(settings.server, Settings::new().server);
+        assert_eq!(settings.name, "ch1");
+        // Counted in bytes, after decoding.
+        update_from_form(&mut settings, &format!("ssid={}", "%41".repeat(MAX_SSID)));
+        assert_eq!(settings.ssid, "A".repeat(MAX_SSID));
+        update_from_form(&mut settings, &format!("ssid={}", "\u{3042}".repeat(11)));
+        assert_eq!(settings.ssid, "A".repeat(MAX_SSID));
+    }
+
+    #[test]
+    fn dns_a_query() {
+        let q = query("connectivitycheck.gstatic.com", 1);
+        let reply = captive_dns_reply(&q, [192, 168, 71, 1]).unwrap();
+        assert_eq!(reply[0..12], [0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(reply[12..q.len()], q[12..]);
+        assert_eq!(reply[q.len()..], [0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 168, 71, 1]);
+    }
+
+    #[test]
+    fn dns_other_queries() {
+        // AAAA gets the question back without an answer.
+        let q = query("captive.apple.com", 28);
+        let reply = captive_dns_reply(&q, [192, 168, 71, 1]).unwrap();
+        assert_eq!(reply[0..12], [0x12, 0x34, 0x81, 0x80, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(reply[12..], q[12..]);
+
+        // A response, and an inverse query, are not answered.
+        let mut q = query("example.com", 1);
+        q[2] |= 0x80;
+        assert!(captive_dns_reply(&q, [10, 0, 0, 1]).is_none());
+        let mut q = query("example.com", 1);
+        q[2] |= 0x08;
+        assert!(captive_dns_reply(&q, [10, 0, 0, 1]).is_none());
+        // Two questions.
+        let mut q = query("example.com", 1);
+        q[5] = 2;
+        assert!(captive_dns_reply(&q, [10, 0, 0, 1]).is_none());
+    }
+
+    #[test]
+    fn dns_truncated() {
+        let q = query("example.com", 1);
+        assert!(captive_dns_reply(&q[..11], [10, 0, 0, 1]).is_none());
+        for len in 12..q.len() {
+            assert!(captive_dns_reply(&q[..len], [10, 0, 0, 1]).is_none(), "{} bytes", len);
+        }
+        // A label longer than what is left.
+        let mut q = query("example.com", 1);
+        q[12] = 60;
+        assert!(captive_dns_reply(&q, [10, 0, 0, 1]).is_none());
+    }
+}
@@ -1,110 +1,555 @@
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
 use log::*;
 use std::{thread, time::Duration, sync::Arc, sync::Mutex};
-use esp_idf_hal::{gpio::*, task};
-use esp_idf_sys::{tskTaskControlBlock, TaskHandle_t};
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::SystemTime;
+use anyhow::{anyhow, bail, Result};
+
+use crate::hal::Buttons;
+
+const SCAN_INTERVAL: u64 = 5;               // ms, while a button is active
+const IDLE_WAIT: u64 = 1000;                // ms, between scans of idle buttons that wake the thread by an edge
+const DEFAULT_DEBOUNCE_TIME: u32 = 30;      // ms
+const DEFAULT_LONG_PRESS_TIME: u32 = 1000;  // ms
+const DEFAULT_DOUBLE_PRESS_TIME: u32 = 300; // ms
+const EVENT_QUEUE_SIZE: usize = 16;
+
+/// GPIOs of the ESP32-C3.
+pub const MAX_GPIO: i32 = 21;
+/// Pins the board uses otherwise: ADC 0, display 1/8/9/10, I2C 3/4, LEDs 6/7, flash 11-17, USB 18/19.
+pub const BOARD_PINS: [i32; 18] = [0, 1, 3, 4, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ButtonRole {
+    StartStop,
+    Interval,
+    Marker,
+}
+
+impl ButtonRole {
+    fn from_name(name: &str) -> Option<ButtonRole> {
+        match name {
+            "startstop" => Some(ButtonRole::StartStop),
+            "interval"  => Some(ButtonRole::Interval),
+            "marker"    => Some(ButtonRole::Marker),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PushEvent {
+    ShortPress(ButtonRole),
+    LongPress(ButtonRole),
+    DoublePress(ButtonRole),
+    Chord(ButtonRole, ButtonRole),  // Two buttons are pressed together.
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ButtonConfig {
+    pub pin: i32,
+    pub role: ButtonRole,
+    pub active_low: bool,
+    pub debounce: u32,      // ms
+}
+
+impl ButtonConfig {
+    /// Parses a list like "21:startstop:low,20:interval:low:50".
+    /// Each entry is pin:role[:low|high[:debounce ms]].
+    pub fn parse_list(list: &str) -> Result<Vec<ButtonConfig>> {
+        let mut buttons = Vec::new();
+        for entry in list.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let fields: Vec<&str> = entry.split(':').map(|f| f.trim()).collect();
+            if fields.len() < 2 {
+                return Err(anyhow!("Invalid button entry: {}", entry));
+            }
+            let pin = fields[0].parse::<i32>()
+                .map_err(|_| anyhow!("Invalid button pin: {}", entry))?;
+            check_pin(pin)?;
+            if buttons.iter().any(|b: &ButtonConfig| b.pin == pin) {
+                bail!("Button pin {} used twice", pin);
+            }
+            let role = ButtonRole::from_name(fields[1])
+                .ok_or(anyhow!("Unknown button role: {}", entry))?;
+            let active_low = match fields.get(2) {
+                None | Some(&"low") => true,
+                Some(&"high") => false,
+                _ => { return Err(anyhow!("Invalid button polarity: {}", entry)); },
+            };
+            let debounce = match fields.get(3) {
+                None => DEFAULT_DEBOUNCE_TIME,
+                Some(v) => v.parse::<u32>().map_err(|_| anyhow!("Invalid button debounce: {}", entry))?,
+            };
+            buttons.push(ButtonConfig { pin: pin, role: role, active_low: active_low, debounce: debounce });
+        }
+        Ok(buttons)
+    }
+}
+
+/// Fails for a pin the ESP32-C3 doesn't have or the board uses for something else.
+pub fn check_pin(pin: i32) -> Result<()> {
+    if (0..=MAX_GPIO).contains(&pin) == false {
+        bail!("No GPIO {}", pin);
+    }
+    if BOARD_PINS.contains(&pin) {
+        bail!("GPIO {} is used by the board", pin);
+    }
+    Ok(())
+}
+
+/// Wakes the scan thread on an edge of a button, so that it sleeps while no button is active.
+#[derive(Clone)]
+pub struct EdgeWake {
+    #[cfg(feature = "native")]
+    task: Arc<std::sync::atomic::AtomicPtr<esp_idf_sys::tskTaskControlBlock>>,
+    #[cfg(not(feature = "native"))]
+    flag: Arc<(Mutex<bool>, std::sync::Condvar)>,
+}
+
+impl EdgeWake {
+    fn new() -> Self {
+        #[cfg(feature = "native")]
+        return EdgeWake { task: Arc::new(std::sync::atomic::AtomicPtr::new(std::ptr::null_mut())) };
+        #[cfg(not(feature = "native"))]
+        return EdgeWake { flag: Arc::new((Mutex::new(false), std::sync::Condvar::new())) };
+    }
+
+    // Called by the thread that waits.
+    fn attach(&self) {
+        #[cfg(feature = "native")]
+        self.task.store(esp_idf_hal::task::current().unwrap(), Ordering::Relaxed);
+    }
+
+    /// Also from an interrupt.
+    pub fn notify(&self) {
+        #[cfg(feature = "native")]
+        {
+            let task = self.task.load(Ordering::Relaxed);
+            if task.is_null() == false {
+                #[allow(unused_unsafe)]
+                unsafe { esp_idf_hal::task::notify(task, 1); }
+            }
+        }
+        #[cfg(not(feature = "native"))]
+        {
+            *self.flag.0.lock().unwrap() = true;
+            self.flag.1.notify_one();
+        }
+    }
+
+    // Returns after a notify, at once when one came since the last wait, or after the timeout.
+    fn wait(&self, timeout: Duration) {
+        #[cfg(feature = "native")]
+        esp_idf_hal::task::wait_notification(Some(timeout));
+        #[cfg(not(feature = "native"))]
+        {
+            let lck = self.flag.0.lock().unwrap();
+            let (mut lck, _) = self.flag.1.wait_timeout_while(lck, timeout, |notified| *notified == false).unwrap();
+            *lck = false;
+        }
+    }
+}
+
+/// Level source of one button.
+pub trait ButtonInput: Send {
+    fn is_high(&mut self) -> bool;
+
+    /// Calls `wake` on every edge. Ok(false) when the input can't, it is polled then.
+    fn subscribe(&mut self, _wake: EdgeWake) -> Result<bool> {
+        Ok(false)
+    }
+}
 
-const PUSH_NOTIFICATION_GPIO20: u32 = 1;
-const PUSH_NOTIFICATION_GPIO21: u32 = 2;
+#[cfg(feature = "native")]
+impl ButtonInput for esp_idf_hal::gpio::PinDriver<'static, esp_idf_hal::gpio::AnyIOPin, esp_idf_hal::gpio::Input> {
+    fn is_high(&mut self) -> bool {
+        esp_idf_hal::gpio::PinDriver::is_high(self)
+    }
 
-type PINDRIVER20 = Box<PinDriver<'static, esp_idf_hal::gpio::Gpio20, esp_idf_hal::gpio::Input>>;
-type PINDRIVER21 = Box<PinDriver<'static, esp_idf_hal::gpio::Gpio21, esp_idf_hal::gpio::Input>>;
+    fn subscribe(&mut self, wake: EdgeWake) -> Result<bool> {
+        self.set_interrupt_type(esp_idf_hal::gpio::InterruptType::AnyEdge)?;
+        // The callback only notifies the task, which is safe in the interrupt.
+        unsafe { esp_idf_hal::gpio::PinDriver::subscribe(self, move || wake.notify())?; }
+        Ok(true)
+    }
+}
+
+/// Opens a GPIO by number as a button input with a pull toward the released level.
+/// Pins the board uses otherwise are refused, see BOARD_PINS.
+#[cfg(feature = "native")]
+pub fn gpio_button(config: &ButtonConfig) -> Result<Box<dyn ButtonInput>> {
+    use esp_idf_hal::gpio::{AnyIOPin, PinDriver, Pull};
+    check_pin(config.pin)?;
+    // Safe as no driver of the board owns the pin, checked above.
+    let pin = unsafe { AnyIOPin::new(config.pin) };
+    let mut drv = PinDriver::input(pin)?;
+    drv.set_pull(if config.active_low { Pull::Up } else { Pull::Down })?;
+    Ok(Box::new(drv))
+}
+
+/// Button input driven by software, for running on a host.
+#[derive(Clone)]
+pub struct SimulatedPin {
+    level: Arc<AtomicBool>,
+    wake: Arc<Mutex<Option<EdgeWake>>>,
+}
+
+#[allow(dead_code)]
+impl SimulatedPin {
+    pub fn new(high: bool) -> Self {
+        SimulatedPin { level: Arc::new(AtomicBool::new(high)), wake: Arc::new(Mutex::new(None)) }
+    }
+
+    pub fn set_high(&self, high: bool) {
+        if self.level.swap(high, Ordering::Relaxed) != high {
+            if let Some(wake) = self.wake.lock().unwrap().as_ref() {
+                wake.notify();
+            }
+        }
+    }
+}
+
+impl ButtonInput for SimulatedPin {
+    fn is_high(&mut self) -> bool {
+        self.level.load(Ordering::Relaxed)
+    }
+
+    fn subscribe(&mut self, wake: EdgeWake) -> Result<bool> {
+        *self.wake.lock().unwrap() = Some(wake);
+        Ok(true)
+    }
+}
 
 struct ButtonState {
-    startstop: bool,
-    send: bool,
+    events: VecDeque<PushEvent>,
+    long_press_time: u32,
+    double_press_time: u32,
+}
+
+impl ButtonState {
+    fn push(&mut self, ev: PushEvent)
+    {
+        if self.events.len() >= EVENT_QUEUE_SIZE {
+            self.events.pop_front();
+        }
+        self.events.push_back(ev);
+    }
+}
+
+// Tracks press and release edges of one button.
+struct KeyTracker {
+    input: Box<dyn ButtonInput>,
+    config: ButtonConfig,
+    level_pressed: bool,    // raw level
+    pressed: bool,          // debounced level
+    edge_time: u32,
+    press_time: u32,
+    release_time: u32,
+    click_pending: bool,
+    long_sent: bool,
+    chorded: bool,
+}
+
+impl KeyTracker {
+    fn new(input: Box<dyn ButtonInput>, config: ButtonConfig) -> Self {
+        KeyTracker { input: input, config: config, level_pressed: false, pressed: false, edge_time: 0,
+            press_time: 0, release_time: 0, click_pending: false, long_sent: false, chorded: false }
+    }
+
+    fn sample(&mut self, now: u32)
+    {
+        let level_pressed = self.input.is_high() != self.config.active_low;
+        if level_pressed != self.level_pressed {
+            self.level_pressed = level_pressed;
+            self.edge_time = now;
+        }
+    }
+
+    // Returns Some(true) on a debounced press, Some(false) on a debounced release.
+    fn debounce(&mut self, now: u32) -> Option<bool>
+    {
+        if self.level_pressed != self.pressed && now.wrapping_sub(self.edge_time) >= self.config.debounce {
+            self.pressed = self.level_pressed;
+            return Some(self.pressed);
+        }
+        None
+    }
+
+    fn released(&mut self, now: u32, state: &mut ButtonState)
+    {
+        self.release_time = now;
+        if self.chorded {
+            self.chorded = false;
+        }
+        else if self.long_sent {
+            self.long_sent = false;
+        }
+        else if self.click_pending {
+            self.click_pending = false;
+            state.push(PushEvent::DoublePress(self.config.role));
+        }
+        else {
+            self.click_pending = true;
+        }
+    }
+
+    // Nothing to time until the next edge.
+    fn is_idle(&self) -> bool
+    {
+        self.level_pressed == false && self.pressed == false && self.click_pending == false
+    }
+
+    fn tick(&mut self, now: u32, state: &mut ButtonState)
+    {
+        if self.pressed && !self.chorded && !self.long_sent
+            && now.wrapping_sub(self.press_time) >= state.long_press_time {
+            if self.click_pending {
+                self.click_pending = false;
+                state.push(PushEvent::ShortPress(self.config.role));
+            }
+            self.long_sent = true;
+            state.push(PushEvent::LongPress(self.config.role));
+        }
+        if self.click_pending && !self.pressed
+            && now.wrapping_sub(self.release_time) > state.double_press_time {
+            self.click_pending = false;
+            state.push(PushEvent::ShortPress(self.config.role));
+        }
+    }
+}
+
+fn scan_keys(keys: &mut [KeyTracker], now: u32, state: &mut ButtonState)
+{
+    for key in keys.iter_mut() {
+        key.sample(now);
+    }
+    for i in 0..keys.len() {
+        match keys[i].debounce(now) {
+            Some(true) => {
+                let other = (0..keys.len()).find(|&j| j != i
+                    && keys[j].pressed && !keys[j].chorded && !keys[j].long_sent);
+                if let Some(j) = other {
+                    keys[i].chorded = true;
+                    keys[j].chorded = true;
+                    keys[i].click_pending = false;
+                    keys[j].click_pending = false;
+                    state.push(PushEvent::Chord(keys[j].config.role, keys[i].config.role));
+                }
+                keys[i].press_time = now;
+            },
+            Some(false) => {
+                keys[i].released(now, state);
+            },
+            None => {},
+        }
+        keys[i].tick(now, state);
+    }
 }
 
 pub struct PushSwitch {
-    state: Arc<Mutex<ButtonState>>
+    state: Arc<Mutex<ButtonState>>,
+    keys: Vec<KeyTracker>,
+}
+
+impl Default for PushSwitch {
+    fn default() -> Self {
+        PushSwitch::new()
+    }
 }
 
 impl PushSwitch {
     pub fn new() -> PushSwitch {
         PushSwitch { state: Arc::new(Mutex::new(
-            ButtonState { startstop: false, send: false })) }
+            ButtonState { events: VecDeque::new(),
+                          long_press_time: DEFAULT_LONG_PRESS_TIME,
+                          double_press_time: DEFAULT_DOUBLE_PRESS_TIME })),
+            keys: Vec::new() }
     }
 
-    pub fn start(&mut self,
-            mut gpio20_sig : PINDRIVER20,
-            mut gpio21_sig : PINDRIVER21)
+    pub fn add_button(&mut self, input: Box<dyn ButtonInput>, config: ButtonConfig)
+    {
+        self.keys.push(KeyTracker::new(input, config));
+    }
+
+    pub fn start(&mut self)
     {
         let state = self.state.clone();
+        let mut keys = std::mem::take(&mut self.keys);
         let _th = thread::spawn(move || {
-            info!("Start Switch Read Thread.");
-            let task_handle_20: AtomicPtr<tskTaskControlBlock> = AtomicPtr::new(std::ptr::null_mut());
-            let ptr_20: TaskHandle_t = task::current().unwrap();
-            task_handle_20.store(ptr_20, Ordering::Relaxed);
-            let task_handle_21: AtomicPtr<tskTaskControlBlock> = AtomicPtr::new(std::ptr::null_mut());
-            let ptr_21: TaskHandle_t = task::current().unwrap();
-            task_handle_21.store(ptr_21, Ordering::Relaxed);
-            
-            gpio20_sig.set_pull(Pull::Up).unwrap();
-            gpio21_sig.set_pull(Pull::Up).unwrap();
-            gpio20_sig.set_interrupt_type(InterruptType::NegEdge).unwrap();
-            gpio21_sig.set_interrupt_type(InterruptType::NegEdge).unwrap();
-            unsafe {
-                gpio20_sig.subscribe(move || {
-                    task::notify(task_handle_20.load(Ordering::Relaxed), PUSH_NOTIFICATION_GPIO20);
-                }).unwrap();
-                gpio21_sig.subscribe(move || {
-                    task::notify(task_handle_21.load(Ordering::Relaxed), PUSH_NOTIFICATION_GPIO21);
-                }).unwrap();
-            }        
+            info!("Start Switch Read Thread. {} buttons", keys.len());
+            let wake = EdgeWake::new();
+            wake.attach();
+            let mut interrupts = true;
+            for key in keys.iter_mut() {
+                match key.input.subscribe(wake.clone()) {
+                    Ok(true) => {},
+                    Ok(false) => { interrupts = false; },
+                    Err(e) => { info!("Button {} without interrupt: {}", key.config.pin, e); interrupts = false; },
+                }
+            }
             let now = SystemTime::now();
-            let mut gpio20_trigger_time : u32 = 0;
-            let mut gpio21_trigger_time : u32 = 0;
             loop {
-                let res = task::wait_notification(Some(Duration::from_millis(10)));
-                let mut lck = state.lock().unwrap();
-                match res {
-                    Some(PUSH_NOTIFICATION_GPIO20) => {
-//                        info!("PUSH_NOTIFICATION_GPIO20");
-                        if gpio20_trigger_time == 0 {
-                            gpio20_trigger_time = now.elapsed().unwrap().as_millis() as u32;
-                        }
-                    },
-                    Some(PUSH_NOTIFICATION_GPIO21) => {
-//                       info!("PUSH_NOTIFICATION_GPIO21");
-                       if gpio21_trigger_time == 0 {
-                           gpio21_trigger_time = now.elapsed().unwrap().as_millis() as u32;
-                       }
-                    },
-                    _ => {
-                        if gpio20_trigger_time > 0 && gpio20_trigger_time + 300 < now.elapsed().unwrap().as_millis() as u32 {
-                            lck.startstop = true;
-                            gpio20_trigger_time = 0;
-                        }
-                        if gpio21_trigger_time > 0 && gpio21_trigger_time + 300 < now.elapsed().unwrap().as_millis() as u32 {
-                            lck.send = true;
-                            gpio21_trigger_time = 0;
-                        }
-                    },
+                // Polled only while a button is active, the first edge wakes the thread otherwise.
+                if interrupts && keys.iter().all(|k| k.is_idle()) {
+                    wake.wait(Duration::from_millis(IDLE_WAIT));
+                }
+                else {
+                    thread::sleep(Duration::from_millis(SCAN_INTERVAL));
                 }
-                drop(lck);                
+                let elapsed = now.elapsed().unwrap().as_millis() as u32;
+                let mut lck = state.lock().unwrap();
+                scan_keys(&mut keys, elapsed, &mut lck);
+                drop(lck);
             }
         });
     }
 
-    pub fn get_gpio_state(&mut self, gpio: u32) -> bool
+    #[allow(dead_code)]
+    pub fn set_long_press_time(&mut self, ms: u32)
     {
-        let mut lock= self.state.lock().unwrap();
-        match gpio {
-            20 => {
-                let ret = lock.startstop;
-                lock.startstop = false;
-                ret
-            },
-            21 => {
-                let ret = lock.send;
-                lock.send = false;
-                ret
-            },
-            _ => {
-                false
-            }
+        let mut lck = self.state.lock().unwrap();
+        lck.long_press_time = ms;
+    }
+
+    #[allow(dead_code)]
+    pub fn set_double_press_time(&mut self, ms: u32)
+    {
+        let mut lck = self.state.lock().unwrap();
+        lck.double_press_time = ms;
+    }
+
+}
+
+impl Buttons for PushSwitch {
+    fn get_event(&mut self) -> Option<PushEvent>
+    {
+        let mut lck = self.state.lock().unwrap();
+        lck.events.pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state() -> ButtonState {
+        ButtonState { events: VecDeque::new(), long_press_time: DEFAULT_LONG_PRESS_TIME,
+                      double_press_time: DEFAULT_DOUBLE_PRESS_TIME }
+    }
+
+    // One active low button on a simulated pin, released.
+    fn button(role: ButtonRole, pin: i32) -> (SimulatedPin, KeyTracker) {
+        let sim = SimulatedPin::new(true);
+        let config = ButtonConfig { pin: pin, role: role, active_low: true, debounce: DEFAULT_DEBOUNCE_TIME };
+        (sim.clone(), KeyTracker::new(Box::new(sim), config))
+    }
+
+    // Scans every 5 ms from `from` up to `to`.
+    fn run(keys: &mut [KeyTracker], from: u32, to: u32, state: &mut ButtonState) {
+        let mut now = from;
+        while now < to {
+            scan_keys(keys, now, state);
+            now += SCAN_INTERVAL as u32;
+        }
+    }
+
+    #[test]
+    fn bounce_is_ignored() {
+        let (sim, key) = button(ButtonRole::StartStop, 21);
+        let mut keys = vec![key];
+        let mut state = state();
+        run(&mut keys, 0, 100, &mut state);
+        // Shorter than the debounce time.
+        sim.set_high(false);
+        run(&mut keys, 100, 120, &mut state);
+        sim.set_high(true);
+        run(&mut keys, 120, 1000, &mut state);
+        assert!(state.events.is_empty());
+        assert!(keys[0].is_idle());
+    }
+
+    #[test]
+    fn short_press() {
+        let (sim, key) = button(ButtonRole::StartStop, 21);
+        let mut keys = vec![key];
+        let mut state = state();
+        sim.set_high(false);
+        run(&mut keys, 0, 100, &mut state);
+        sim.set_high(true);
+        // Waits whether a second press follows.
+        run(&mut keys, 100, 300, &mut state);
+        assert!(state.events.is_empty());
+        run(&mut keys, 300, 600, &mut state);
+        assert_eq!(state.events.pop_front(), Some(PushEvent::ShortPress(ButtonRole::StartStop)));
+        assert!(state.events.is_empty());
+    }
+
+    #[test]
+    fn long_press() {
+        let (sim, key) = button(ButtonRole::Interval, 20);
+        let mut keys = vec![key];
+        let mut state = state();
+        sim.set_high(false);
+        run(&mut keys, 0, 1000, &mut state);
+        assert!(state.events.is_empty());
+        run(&mut keys, 1000, 1100, &mut state);
+        assert_eq!(state.events.pop_front(), Some(PushEvent::LongPress(ButtonRole::Interval)));
+        // Held longer and released, nothing more.
+        run(&mut keys, 1100, 3000, &mut state);
+        sim.set_high(true);
+        run(&mut keys, 3000, 4000, &mut state);
+        assert!(state.events.is_empty());
+    }
+
+    #[test]
+    fn double_press_and_chord() {
+        let (sim, key) = button(ButtonRole::StartStop, 21);
+        let (other, key2) = button(ButtonRole::Interval, 20);
+        let mut keys = vec![key, key2];
+        let mut state = state();
+        for (from, level) in [(0, false), (100, true), (200, false), (300, true)] {
+            sim.set_high(level);
+            run(&mut keys, from, from + 100, &mut state);
         }
+        run(&mut keys, 400, 1000, &mut state);
+        assert_eq!(state.events.pop_front(), Some(PushEvent::DoublePress(ButtonRole::StartStop)));
+        assert!(state.events.is_empty());
+
+        sim.set_high(false);
+        run(&mut keys, 1000, 1100, &mut state);
+        other.set_high(false);
+        run(&mut keys, 1100, 3000, &mut state);
+        sim.set_high(true);
+        other.set_high(true);
+        run(&mut keys, 3000, 4000, &mut state);
+        assert_eq!(state.events.pop_front(), Some(PushEvent::Chord(ButtonRole::StartStop, ButtonRole::Interval)));
+        assert!(state.events.is_empty());
+    }
+
+    #[test]
+    fn edge_wakes_the_thread() {
+        let sim = SimulatedPin::new(true);
+        let mut psw = PushSwitch::new();
+        psw.add_button(Box::new(sim.clone()), ButtonConfig::parse_list("21:startstop").unwrap()[0]);
+        psw.start();
+        thread::sleep(Duration::from_millis(50));
+        sim.set_high(false);
+        thread::sleep(Duration::from_millis(100));
+        sim.set_high(true);
+        thread::sleep(Duration::from_millis(600));
+        assert_eq!(psw.get_event(), Some(PushEvent::ShortPress(ButtonRole::StartStop)));
     }
 
+    #[test]
+    fn board_pins_are_refused() {
+        assert!(ButtonConfig::parse_list("21:startstop:low,20:interval:high:50").is_ok());
+        assert!(ButtonConfig::parse_list("5:marker").is_ok());
+        assert!(ButtonConfig::parse_list("4:startstop:low").is_err());
+        assert!(ButtonConfig::parse_list("12:startstop").is_err());
+        assert!(ButtonConfig::parse_list("22:startstop").is_err());
+        assert!(ButtonConfig::parse_list("-1:startstop").is_err());
+        assert!(ButtonConfig::parse_list("21:startstop,21:interval").is_err());
+    }
 }
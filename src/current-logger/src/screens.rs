@@ -0,0 +1,88 @@
+// Screen states of the display, drawn into BMP files by currentlogger-render and compared
+// with tests/snapshots by the tests.
+
+use crate::hal::DisplaySink;
+use crate::displayctl::{DisplayPanel, DisplaySettings, Screen, LoggingStatus, WifiStatus};
+use crate::hosthal::Framebuffer;
+use crate::bignumber::Quantity;
+use crate::battery::{FuelGauge, BatteryConfig, BatteryState, PowerSource};
+
+// Frames drawn before the frame is taken, so that the blinking logging dot is on.
+const FRAMES: usize = 31;
+
+fn battery(voltage: f32) -> BatteryState {
+    FuelGauge::new(BatteryConfig::new()).update(voltage, 0)
+}
+
+fn base() -> DisplayPanel {
+    let mut dp = DisplayPanel::new();
+    dp.set_interval(100);
+    dp.set_voltage(5.12, 0.123, 0.630);
+    dp.set_accumulated(0.0456, 0.0089);
+    dp.set_battery(battery(4.0));
+    dp.set_buffer_watermark(30);
+    dp
+}
+
+/// Every state worth looking at, by name.
+pub fn states() -> Vec<(String, DisplayPanel)> {
+    let mut states: Vec<(String, DisplayPanel)> = Vec::new();
+    for (name, bat) in [("battery-0", 3.3), ("battery-20", 3.68), ("battery-40", 3.78), ("battery-60", 3.85),
+                        ("battery-80", 3.95), ("battery-100", 4.2), ("usb-power", 4.6)] {
+        let mut dp = base();
+        dp.set_battery(battery(bat));
+        states.push((name.to_string(), dp));
+    }
+    let mut dp = base();
+    dp.set_battery(BatteryState { source: PowerSource::Charging, ..battery(3.9) });
+    states.push(("charging".to_string(), dp));
+    let mut dp = base();
+    dp.set_wifi_status(WifiStatus::Connected);
+    states.push(("wifi".to_string(), dp));
+    let mut dp = base();
+    dp.set_wifi_status(WifiStatus::Lost);
+    states.push(("wifi-lost".to_string(), dp));
+    let mut dp = base();
+    dp.set_err_message("I2C Err".to_string());
+    states.push(("error".to_string(), dp));
+    let mut dp = base();
+    dp.set_current_status(LoggingStatus::Start);
+    states.push(("logging".to_string(), dp));
+    let mut dp = base();
+    dp.set_voltage(12.0, 40.0, 480.0);
+    states.push(("current-na".to_string(), dp));
+    let mut dp = base();
+    dp.set_voltage(-1.23, -0.050, 0.061);
+    states.push(("negative-voltage".to_string(), dp));
+    let mut dp = base();
+    dp.set_voltage(0.0123, 0.0, 0.0);
+    states.push(("millivolt".to_string(), dp));
+    let mut dp = base();
+    dp.set_voltage(1500.0, 0.0, 0.0);
+    states.push(("overflow".to_string(), dp));
+    let mut dp = base();
+    dp.set_voltage(f32::NAN, 0.0, 0.0);
+    states.push(("invalid".to_string(), dp));
+    for (name, readout) in [("readout-current", Quantity::Current), ("readout-power", Quantity::Power),
+                             ("readout-energy", Quantity::Energy), ("readout-charge", Quantity::Charge)] {
+        let mut dp = base();
+        dp.set_readout(readout);
+        states.push((name.to_string(), dp));
+    }
+    let mut settings = DisplaySettings::new();
+    settings.pixel_shift = 1;
+    let mut dp = base();
+    dp.set_settings(settings);
+    states.push(("pixel-shift".to_string(), dp));
+    states
+}
+
+/// The frame the display thread shows after a few seconds in this state.
+pub fn render(dp: &DisplayPanel) -> Framebuffer {
+    let mut screen = Screen::new();
+    let mut fb = Framebuffer::new();
+    for _ in 0..FRAMES {
+        dp.render(&mut screen, &mut fb).unwrap();
+    }
+    fb
+}
@@ -0,0 +1,347 @@
+// Settings kept in NVS, so that changes made at runtime survive a reboot.
+// Every value falls back to the default given by the firmware, which comes from cfg.toml.
+#![allow(clippy::bool_comparison)]
+
+use log::*;
+use anyhow::{anyhow, Result};
+
+use crate::hal::KeyValueStore;
+use crate::bignumber::Quantity;
+use crate::displayctl::DisplaySettings;
+use crate::netconfig::DEFAULT_HOSTNAME;
+use crate::httpclient::{parse_headers, parse_auth};
+use crate::lineprotocol::{PointFormat, write_path, DEFAULT_MEASUREMENT, DEFAULT_TAG_KEY};
+use crate::transfer::UploadFormat;
+use crate::mqtt::MqttTopics;
+use crate::provision::DEFAULT_NAME;
+
+/// Raised when a key changes its meaning; `migrate` converts the older stores.
+pub const SETTINGS_VERSION: u32 = 1;
+
+pub const KEY_VERSION: &str = "version";
+pub const KEY_SSID: &str = "ssid";
+pub const KEY_PSK: &str = "psk";
+pub const KEY_NETWORKS: &str = "networks";
+pub const KEY_HOSTNAME: &str = "hostname";
+pub const KEY_STATIC_IP: &str = "static_ip";
+pub const KEY_GATEWAY: &str = "gateway";
+pub const KEY_DNS: &str = "dns";
+pub const KEY_SERVER: &str = "server";
+pub const KEY_MDNS: &str = "mdns";
+pub const KEY_LIVE_VIEW: &str = "live_view";
+pub const KEY_PATH: &str = "path";
+pub const KEY_HEADERS: &str = "headers";
+pub const KEY_AUTH: &str = "auth";
+pub const KEY_TLS_CA: &str = "tls_ca";
+pub const KEY_TLS_PIN: &str = "tls_pin";
+pub const KEY_FORMAT: &str = "format";
+pub const KEY_ORG: &str = "org";
+pub const KEY_BUCKET: &str = "bucket";
+pub const KEY_MEASUREMENT: &str = "measurement";
+pub const KEY_TAG_KEY: &str = "tag_key";
+pub const KEY_FIELDS: &str = "fields";
+pub const KEY_UPLINK: &str = "uplink";
+pub const KEY_MQTT_SERVER: &str = "mqtt_server";
+pub const KEY_MQTT_USER: &str = "mqtt_user";
+pub const KEY_MQTT_PASSWORD: &str = "mqtt_pass";
+pub const KEY_MQTT_TOPIC: &str = "mqtt_topic";
+pub const KEY_MQTT_TOPICS: &str = "mqtt_topics";
+pub const KEY_STREAM_SERVER: &str = "stream_server";
+pub const KEY_NAME: &str = "name";
+pub const KEY_INTERVAL: &str = "interval";
+pub const KEY_SHUNT: &str = "shunt";
+pub const KEY_TRIGGER: &str = "trigger";
+pub const KEY_READOUT: &str = "readout";
+pub const KEY_BRIGHTNESS: &str = "disp_bright";
+pub const KEY_DIM_BRIGHTNESS: &str = "disp_dim";
+pub const KEY_DIM_AFTER: &str = "disp_dim_after";
+pub const KEY_OFF_AFTER: &str = "disp_off_after";
+pub const KEY_PIXEL_SHIFT: &str = "disp_shift";
+
+#[derive(Debug, Clone)]
+pub struct Settings {
+    // Network
+    pub ssid: String,
+    pub psk: String,
+    pub networks: String,       // "priority:ssid:password;.." as in wifilist
+    pub hostname: String,
+    pub static_ip: String,      // "address/prefix", empty or "dhcp" uses DHCP
+    pub gateway: String,
+    pub dns: String,
+    // Server
+    pub server: String,         // host:port, the host may be a name resolved by DNS
+    pub mdns: bool,             // Advertise the logger and look for a collector before `server`
+    pub live_view: bool,        // Serve the live view and its WebSocket on port 80
+    pub path: String,           // Path of the upload requests
+    pub headers: String,        // "Name: value;.." sent with the uploads
+    pub auth: String,           // "bearer:<token>" or "basic:<user>:<password>", empty for none
+    pub tls_ca: String,         // CA certificate in PEM for an https:// server, empty uses the bundle
+    pub tls_pin: String,        // SHA-256 of the server certificate in hex, empty for none
+    pub format: String,         // "json" for the agent or "influx" for the InfluxDB v2 write API
+    pub org: String,            // InfluxDB organization and bucket, they make the path of "influx"
+    pub bucket: String,
+    pub measurement: String,
+    pub tag_key: String,        // Tag holding the device name in "influx"
+    pub fields: String,         // "field=name;.." renaming the fields in "influx"
+    pub uplink: String,         // "http", "mqtt", "udp" or "tcp"
+    pub mqtt_server: String,    // host:port, mqtt:// or mqtts://
+    pub mqtt_user: String,
+    pub mqtt_password: String,
+    pub mqtt_topic: String,     // Prefix of the topics, empty is currentlogger/<name>
+    pub mqtt_topics: String,    // "samples=lab/power;.." moving single topics
+    pub stream_server: String,  // host:port of the collector for "udp" and "tcp"
+    pub name: String,           // Device name, sent as the tag of every point
+    // Sampling
+    pub interval: u32,          // ms
+    pub shunt: f32,             // Ohm
+    pub trigger: f32,           // A, logging starts when the current reaches it, 0 disables it
+    // Display
+    pub readout: Quantity,
+    pub display: DisplaySettings,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings::new()
+    }
+}
+
+impl Settings {
+    pub fn new() -> Settings {
+        Settings { ssid: "".to_string(),
+                   psk: "".to_string(),
+                   networks: "".to_string(),
+                   hostname: DEFAULT_HOSTNAME.to_string(),
+                   static_ip: "".to_string(),
+                   gateway: "".to_string(),
+                   dns: "".to_string(),
+                   server: "".to_string(),
+                   mdns: true,
+                   live_view: true,
+                   path: "/".to_string(),
+                   headers: "".to_string(),
+                   auth: "".to_string(),
+                   tls_ca: "".to_string(),
+                   tls_pin: "".to_string(),
+                   format: "json".to_string(),
+                   org: "".to_string(),
+                   bucket: "".to_string(),
+                   measurement: DEFAULT_MEASUREMENT.to_string(),
+                   tag_key: DEFAULT_TAG_KEY.to_string(),
+                   fields: "".to_string(),
+                   uplink: "http".to_string(),
+                   mqtt_server: "".to_string(),
+                   mqtt_user: "".to_string(),
+                   mqtt_password: "".to_string(),
+                   mqtt_topic: "".to_string(),
+                   mqtt_topics: "".to_string(),
+                   stream_server: "".to_string(),
+                   name: "".to_string(),
+                   interval: 5,
+                   shunt: 0.010,
+                   trigger: 0.0,
+                   readout: Quantity::Voltage,
+                   display: DisplaySettings::new() }
+    }
+
+    /// Reads the stored settings over `defaults`.
+    /// A store written by a newer firmware is not trusted and `defaults` are used as they are.
+    pub fn load(store: &mut dyn KeyValueStore, defaults: Settings) -> Result<Settings> {
+        let version = match store.get(KEY_VERSION)? {
+            Some(v) => v.parse::<u32>().unwrap_or(0),
+            None => 0,
+        };
+        if version > SETTINGS_VERSION {
+            info!("Settings version {} is newer than {}, using defaults.", version, SETTINGS_VERSION);
+            return Ok(defaults);
+        }
+        if version < SETTINGS_VERSION {
+            migrate(store, version)?;
+        }
+        let mut s = defaults;
+        load_string(store, KEY_SSID, &mut s.ssid)?;
+        load_string(store, KEY_PSK, &mut s.psk)?;
+        load_string(store, KEY_NETWORKS, &mut s.networks)?;
+        load_string(store, KEY_HOSTNAME, &mut s.hostname)?;
+        load_string(store, KEY_STATIC_IP, &mut s.static_ip)?;
+        load_string(store, KEY_GATEWAY, &mut s.gateway)?;
+        load_string(store, KEY_DNS, &mut s.dns)?;
+        load_string(store, KEY_SERVER, &mut s.server)?;
+        load_value(store, KEY_MDNS, &mut s.mdns)?;
+        load_value(store, KEY_LIVE_VIEW, &mut s.live_view)?;
+        load_string(store, KEY_PATH, &mut s.path)?;
+        load_string(store, KEY_HEADERS, &mut s.headers)?;
+        load_string(store, KEY_AUTH, &mut s.auth)?;
+        load_string(store, KEY_TLS_CA, &mut s.tls_ca)?;
+        load_string(store, KEY_TLS_PIN, &mut s.tls_pin)?;
+        load_string(store, KEY_FORMAT, &mut s.format)?;
+        load_string(store, KEY_ORG, &mut s.org)?;
+        load_string(store, KEY_BUCKET, &mut s.bucket)?;
+        load_string(store, KEY_MEASUREMENT, &mut s.measurement)?;
+        load_string(store, KEY_TAG_KEY, &mut s.tag_key)?;
+        load_string(store, KEY_FIELDS, &mut s.fields)?;
+        load_string(store, KEY_UPLINK, &mut s.uplink)?;
+        load_string(store, KEY_MQTT_SERVER, &mut s.mqtt_server)?;
+        load_string(store, KEY_MQTT_USER, &mut s.mqtt_user)?;
+        load_string(store, KEY_MQTT_PASSWORD, &mut s.mqtt_password)?;
+        load_string(store, KEY_MQTT_TOPIC, &mut s.mqtt_topic)?;
+        load_string(store, KEY_MQTT_TOPICS, &mut s.mqtt_topics)?;
+        load_string(store, KEY_STREAM_SERVER, &mut s.stream_server)?;
+        load_string(store, KEY_NAME, &mut s.name)?;
+        load_value(store, KEY_INTERVAL, &mut s.interval)?;
+        load_value(store, KEY_SHUNT, &mut s.shunt)?;
+        load_value(store, KEY_TRIGGER, &mut s.trigger)?;
+        if let Some(name) = store.get(KEY_READOUT)? {
+            match Quantity::from_name(&name) {
+                Some(readout) => { s.readout = readout; },
+                None => { info!("Unknown readout in settings: {}", name); },
+            }
+        }
+        load_value(store, KEY_BRIGHTNESS, &mut s.display.brightness)?;
+        load_value(store, KEY_DIM_BRIGHTNESS, &mut s.display.dim_brightness)?;
+        load_value(store, KEY_DIM_AFTER, &mut s.display.dim_after)?;
+        load_value(store, KEY_OFF_AFTER, &mut s.display.off_after)?;
+        load_value(store, KEY_PIXEL_SHIFT, &mut s.display.pixel_shift)?;
+        Ok(s)
+    }
+
+    /// The extra headers of the uploads with the Authorization header.
+    pub fn upload_headers(&self) -> Result<Vec<(String, String)>> {
+        let mut headers = parse_headers(&self.headers)?;
+        headers.extend(parse_auth(&self.auth)?);
+        Ok(headers)
+    }
+
+    /// The body format and the names of the points.
+    pub fn upload_format(&self) -> Result<(UploadFormat, PointFormat)> {
+        let format = UploadFormat::from_name(&self.format).ok_or(anyhow!("Unknown upload format: {}", self.format))?;
+        Ok((format, PointFormat::parse(&self.measurement, &self.tag_key, &self.fields)?))
+    }
+
+    /// The path of the uploads, the write API of `org` and `bucket` for "influx" when they are set.
+    pub fn upload_path(&self) -> String {
+        if UploadFormat::from_name(&self.format) == Some(UploadFormat::LineProtocol) && self.bucket.is_empty() == false {
+            write_path(&self.org, &self.bucket)
+        }
+        else {
+            self.path.clone()
+        }
+    }
+
+    /// The MQTT topics, below currentlogger/<name> unless a prefix is set.
+    pub fn topics(&self) -> Result<MqttTopics> {
+        let prefix = if self.mqtt_topic.is_empty() == false {
+            self.mqtt_topic.clone()
+        }
+        else {
+            format!("currentlogger/{}", if self.name.is_empty() == false { self.name.as_str() } else { DEFAULT_NAME })
+        };
+        MqttTopics::parse(&prefix, &self.mqtt_topics)
+    }
+
+    pub fn save(&self, store: &mut dyn KeyValueStore) -> Result<()> {
+        store.set(KEY_SSID, &self.ssid)?;
+        store.set(KEY_PSK, &self.psk)?;
+        store.set(KEY_NETWORKS, &self.networks)?;
+        store.set(KEY_HOSTNAME, &self.hostname)?;
+        store.set(KEY_STATIC_IP, &self.static_ip)?;
+        store.set(KEY_GATEWAY, &self.gateway)?;
+        store.set(KEY_DNS, &self.dns)?;
+        store.set(KEY_SERVER, &self.server)?;
+        store.set(KEY_MDNS, &self.mdns.to_string())?;
+        store.set(KEY_LIVE_VIEW, &self.live_view.to_string())?;
+        store.set(KEY_PATH, &self.path)?;
+        store.set(KEY_HEADERS, &self.headers)?;
+        store.set(KEY_AUTH, &self.auth)?;
+        store.set(KEY_TLS_CA, &self.tls_ca)?;
+        store.set(KEY_TLS_PIN, &self.tls_pin)?;
+        store.set(KEY_FORMAT, &self.format)?;
+        store.set(KEY_ORG, &self.org)?;
+        store.set(KEY_BUCKET, &self.bucket)?;
+        store.set(KEY_MEASUREMENT, &self.measurement)?;
+        store.set(KEY_TAG_KEY, &self.tag_key)?;
+        store.set(KEY_FIELDS, &self.fields)?;
+        store.set(KEY_UPLINK, &self.uplink)?;
+        store.set(KEY_MQTT_SERVER, &self.mqtt_server)?;
+        store.set(KEY_MQTT_USER, &self.mqtt_user)?;
+        store.set(KEY_MQTT_PASSWORD, &self.mqtt_password)?;
+        store.set(KEY_MQTT_TOPIC, &self.mqtt_topic)?;
+        store.set(KEY_MQTT_TOPICS, &self.mqtt_topics)?;
+        store.set(KEY_STREAM_SERVER, &self.stream_server)?;
+        store.set(KEY_NAME, &self.name)?;
+        store.set(KEY_INTERVAL, &self.interval.to_string())?;
+        store.set(KEY_SHUNT, &self.shunt.to_string())?;
+        store.set(KEY_TRIGGER, &self.trigger.to_string())?;
+        store.set(KEY_READOUT, self.readout.name())?;
+        store.set(KEY_BRIGHTNESS, &self.display.brightness.to_string())?;
+        store.set(KEY_DIM_BRIGHTNESS, &self.display.dim_brightness.to_string())?;
+        store.set(KEY_DIM_AFTER, &self.display.dim_after.to_string())?;
+        store.set(KEY_OFF_AFTER, &self.display.off_after.to_string())?;
+        store.set(KEY_PIXEL_SHIFT, &self.display.pixel_shift.to_string())?;
+        store.set(KEY_VERSION, &SETTINGS_VERSION.to_string())
+    }
+}
+
+// Version 0 is the store written by the provisioning portal, which only had the network and server keys.
+// Their names and meaning are unchanged, so only the version is written.
+fn migrate(store: &mut dyn KeyValueStore, from: u32) -> Result<()> {
+    info!("Settings version {} migrated to {}", from, SETTINGS_VERSION);
+    store.set(KEY_VERSION, &SETTINGS_VERSION.to_string())
+}
+
+// A stored empty value clears the setting, only a missing key keeps the default.
+fn load_string(store: &dyn KeyValueStore, key: &str, value: &mut String) -> Result<()> {
+    if let Some(v) = store.get(key)? {
+        *value = v;
+    }
+    Ok(())
+}
+
+fn load_value<T: std::str::FromStr>(store: &dyn KeyValueStore, key: &str, value: &mut T) -> Result<()> {
+    if let Some(v) = store.get(key)? {
+        match v.parse::<T>() {
+            Ok(v) => { *value = v; },
+            Err(_) => { info!("Invalid setting {}={}", key, v); },
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::hosthal::MemoryStore;
+
+    fn defaults() -> Settings {
+        let mut s = Settings::new();
+        s.server = "collector.local".to_string();
+        s.mqtt_topic = "lab/bench".to_string();
+        s
+    }
+
+    #[test]
+    fn empty_value_clears_the_setting() {
+        let mut store = MemoryStore::default();
+        store.set(KEY_VERSION, &SETTINGS_VERSION.to_string()).unwrap();
+        store.set(KEY_MQTT_TOPIC, "").unwrap();
+        let s = Settings::load(&mut store, defaults()).unwrap();
+        assert_eq!(s.mqtt_topic, "");
+        // A key that was never stored keeps the default.
+        assert_eq!(s.server, "collector.local");
+    }
+
+    #[test]
+    fn saved_settings_load_back() {
+        let mut store = MemoryStore::default();
+        let mut s = defaults();
+        s.server = "".to_string();
+        s.name = "bench".to_string();
+        s.interval = 250;
+        s.save(&mut store).unwrap();
+        let loaded = Settings::load(&mut store, defaults()).unwrap();
+        assert_eq!(loaded.server, "");
+        assert_eq!(loaded.name, "bench");
+        assert_eq!(loaded.interval, 250);
+    }
+}
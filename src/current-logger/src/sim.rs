@@ -0,0 +1,304 @@
+// Synthetic INA228 on a mock I2C bus, for running the logger without hardware.
+#![allow(clippy::redundant_field_names)]
+
+use log::*;
+use std::fs;
+use anyhow::{anyhow, bail, Result};
+
+use crate::hal::{Clock, I2cBus};
+use crate::ina228::{INA228_ADDR, REG_CONFIG, REG_SHUNT_CAL, REG_VBUS, REG_CURRENT, REG_POWER,
+                    REG_ENERGY, REG_CHARGE, CONFIG_RSTACC, VBUS_LSB};
+
+const REG_MANUFACTURER_ID: u8 = 0x3E;
+const REG_DEVICE_ID: u8       = 0x3F;
+const MANUFACTURER_ID: u64    = 0x5449;   // "TI"
+const DEVICE_ID: u64          = 0x2281;
+
+#[derive(Debug, Clone)]
+pub enum Waveform {
+    Constant { voltage: f32, current: f32 },
+    // Current bursts of `high` for `burst` ms every `period` ms, otherwise `low`.
+    Square { voltage: f32, low: f32, high: f32, period: u64, burst: u64 },
+    // Samples of (time ms, voltage, current), replayed in a loop.
+    Replay { samples: Vec<(u64, f32, f32)> },
+}
+
+impl Waveform {
+    /// Parses "constant:V:A", "square:V:low A:high A:period ms:burst ms" or "csv:<file>".
+    pub fn parse(spec: &str) -> Result<Waveform> {
+        let fields: Vec<&str> = spec.split(':').collect();
+        let num = |i: usize| -> Result<f32> {
+            fields.get(i).ok_or(anyhow!("Missing waveform field: {}", spec))?
+                .parse::<f32>().map_err(|_| anyhow!("Invalid waveform field: {}", spec))
+        };
+        match fields[0] {
+            "constant" => Ok(Waveform::Constant { voltage: num(1)?, current: num(2)? }),
+            "square" => Ok(Waveform::Square { voltage: num(1)?, low: num(2)?, high: num(3)?,
+                                              period: num(4)? as u64, burst: num(5)? as u64 }),
+            "csv" => {
+                let (_, path) = spec.split_once(':').ok_or(anyhow!("Missing CSV file: {}", spec))?;
+                Self::load_csv(path)
+            },
+            _ => bail!("Unknown waveform: {}", spec),
+        }
+    }
+
+    // Lines are time(ms),voltage(V),current(A). Lines that do not parse, like a header, are skipped.
+    fn load_csv(path: &str) -> Result<Waveform> {
+        let text = fs::read_to_string(path)?;
+        let mut samples = Vec::new();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+            if fields.len() < 3 {
+                continue;
+            }
+            if let (Ok(t), Ok(v), Ok(c)) = (fields[0].parse::<u64>(), fields[1].parse::<f32>(), fields[2].parse::<f32>()) {
+                samples.push((t, v, c));
+            }
+        }
+        if samples.is_empty() {
+            bail!("No samples in {}", path);
+        }
+        info!("{} samples loaded from {}", samples.len(), path);
+        Ok(Waveform::Replay { samples: samples })
+    }
+
+    /// Returns (voltage, current) at `t` ms.
+    pub fn sample(&self, t: u64) -> (f32, f32) {
+        match self {
+            Waveform::Constant { voltage, current } => (*voltage, *current),
+            Waveform::Square { voltage, low, high, period, burst } => {
+                if *period > 0 && t % period < *burst {
+                    (*voltage, *high)
+                }
+                else {
+                    (*voltage, *low)
+                }
+            },
+            Waveform::Replay { samples } => {
+                let length = samples[samples.len() - 1].0 + 1;
+                let t = t % length;
+                let idx = samples.iter().rposition(|s| s.0 <= t).unwrap_or(0);
+                (samples[idx].1, samples[idx].2)
+            },
+        }
+    }
+}
+
+/// INA228 register map answering on the INA228 address.
+pub struct SimIna228 {
+    clock: Box<dyn Clock + Send>,
+    waveform: Waveform,
+    shunt_resistor: f32,
+    shunt_cal: u32,
+    pointer: u8,
+    energy: f64,        // J
+    charge: f64,        // C
+    last_time: u64,     // ms
+}
+
+impl SimIna228 {
+    pub fn new(clock: Box<dyn Clock + Send>, waveform: Waveform, shunt_resistor: f32) -> Self {
+        let last_time = clock.now_ms();
+        SimIna228 { clock: clock, waveform: waveform, shunt_resistor: shunt_resistor,
+                    shunt_cal: 0, pointer: 0, energy: 0.0, charge: 0.0, last_time: last_time }
+    }
+
+    // Integrates power and current since the last access, like the continuous mode of the device.
+    fn accumulate(&mut self) {
+        let now = self.clock.now_ms();
+        let (voltage, current) = self.waveform.sample(now);
+        let dt = now.saturating_sub(self.last_time) as f64 / 1000.0;
+        self.energy += (voltage * current).abs() as f64 * dt;
+        self.charge += current as f64 * dt;
+        self.last_time = now;
+    }
+
+    // Current LSB follows from SHUNT_CAL as on the real device.
+    fn current_lsb(&self) -> f32 {
+        self.shunt_cal as f32 / (13107.2 * 1_000_000.0 * self.shunt_resistor)
+    }
+
+    fn register(&self, reg: u8) -> (u64, usize) {
+        let (voltage, current) = self.waveform.sample(self.clock.now_ms());
+        let current_lsb = self.current_lsb();
+        match reg {
+            REG_SHUNT_CAL => (self.shunt_cal as u64, 2),
+            REG_VBUS => ((((voltage.max(0.0) / VBUS_LSB) as u32 & 0xFFFFF) << 4) as u64, 3),
+            REG_CURRENT => {
+                if current_lsb == 0.0 {
+                    return (0, 3);
+                }
+                let current_reg = (current / current_lsb) as i32;
+                ((((current_reg as u32) & 0xFFFFF) << 4) as u64, 3)
+            },
+            REG_POWER => {
+                if current_lsb == 0.0 {
+                    return (0, 3);
+                }
+                ((((voltage * current).abs() / (3.2 * current_lsb)) as u64) & 0xFFFFFF, 3)
+            },
+            REG_ENERGY => {
+                if current_lsb == 0.0 {
+                    return (0, 5);
+                }
+                ((self.energy / (16.0 * 3.2 * current_lsb as f64)) as u64 & 0xFF_FFFF_FFFF, 5)
+            },
+            REG_CHARGE => {
+                if current_lsb == 0.0 {
+                    return (0, 5);
+                }
+                ((self.charge / current_lsb as f64) as i64 as u64 & 0xFF_FFFF_FFFF, 5)
+            },
+            REG_MANUFACTURER_ID => (MANUFACTURER_ID, 2),
+            REG_DEVICE_ID => (DEVICE_ID, 2),
+            _ => (0, 2),
+        }
+    }
+}
+
+impl I2cBus for SimIna228 {
+    fn write(&mut self, addr: u8, bytes: &[u8]) -> Result<()> {
+        if addr != INA228_ADDR || bytes.is_empty() {
+            bail!("I2C NACK from 0x{:02X}", addr);
+        }
+        self.pointer = bytes[0];
+        if bytes.len() >= 3 {
+            let value = (bytes[1] as u16) << 8 | bytes[2] as u16;
+            match self.pointer {
+                REG_SHUNT_CAL => { self.shunt_cal = (value & 0x7FFF) as u32; },
+                REG_CONFIG if value & CONFIG_RSTACC != 0 => {
+                    self.energy = 0.0;
+                    self.charge = 0.0;
+                },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, addr: u8, buf: &mut [u8]) -> Result<()> {
+        if addr != INA228_ADDR {
+            bail!("I2C NACK from 0x{:02X}", addr);
+        }
+        self.accumulate();
+        let (value, size) = self.register(self.pointer);
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = if i < size { (value >> (8 * (size - 1 - i))) as u8 } else { 0 };
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hosthal::ManualClock;
+    use crate::ina228::{Ina228, MAX_CURRENT};
+
+    const SHUNT: f32 = 0.015;
+
+    fn sensor(waveform: &str) -> (Ina228<SimIna228>, ManualClock) {
+        let clock = ManualClock::new();
+        let sim = SimIna228::new(Box::new(clock.clone()), Waveform::parse(waveform).unwrap(), SHUNT);
+        let mut ina = Ina228::new(sim, INA228_ADDR);
+        ina.init(SHUNT).unwrap();
+        (ina, clock)
+    }
+
+    fn close(a: f32, b: f32, tolerance: f32) -> bool {
+        (a - b).abs() <= tolerance
+    }
+
+    #[test]
+    fn constant_and_square() {
+        let constant = Waveform::parse("constant:5:0.1").unwrap();
+        assert_eq!(constant.sample(0), (5.0, 0.1));
+        assert_eq!(constant.sample(123_456), (5.0, 0.1));
+        let square = Waveform::parse("square:3.3:0.001:0.1:1000:50").unwrap();
+        assert_eq!(square.sample(10), (3.3, 0.1));
+        assert_eq!(square.sample(50), (3.3, 0.001));
+        assert_eq!(square.sample(1049), (3.3, 0.1));
+    }
+
+    #[test]
+    fn csv_is_replayed() {
+        let path = std::env::temp_dir().join(format!("currentlogger-sim-{}.csv", std::process::id()));
+        fs::write(&path, "time,voltage,current\n0,5.0,0.010\n100, 4.9, 0.020\nbad line\n200,4.8,0.030\n").unwrap();
+        let waveform = Waveform::parse(&format!("csv:{}", path.display())).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(waveform.sample(0), (5.0, 0.010));
+        assert_eq!(waveform.sample(150), (4.9, 0.020));
+        assert_eq!(waveform.sample(200), (4.8, 0.030));
+        // It loops after the last sample.
+        assert_eq!(waveform.sample(201 + 99), (5.0, 0.010));
+    }
+
+    #[test]
+    fn bad_waveforms() {
+        for spec in ["", "sine:5:1", "constant:5", "constant:five:1", "square:3.3:0:1:1000", "csv:",
+                     "csv:/nonexistent/waveform.csv"] {
+            assert!(Waveform::parse(spec).is_err(), "{}", spec);
+        }
+        let path = std::env::temp_dir().join(format!("currentlogger-sim-empty-{}.csv", std::process::id()));
+        fs::write(&path, "time,voltage,current\n").unwrap();
+        let result = Waveform::parse(&format!("csv:{}", path.display()));
+        fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn registers_read_back() {
+        let (mut ina, _) = sensor("constant:5:0.5");
+        let lsb = MAX_CURRENT / 524_288.0;
+        assert!(close(ina.read_voltage().unwrap(), 5.0, VBUS_LSB));
+        assert!(close(ina.read_current().unwrap(), 0.5, lsb));
+        assert!(close(ina.read_power().unwrap(), 2.5, 3.2 * lsb));
+    }
+
+    #[test]
+    fn negative_current() {
+        let (mut ina, _) = sensor("constant:5:-0.25");
+        let current = ina.read_current().unwrap();
+        assert!(close(current, -0.25, MAX_CURRENT / 524_288.0), "{}", current);
+        // Power is the magnitude.
+        assert!(ina.read_power().unwrap() > 0.0);
+    }
+
+    #[test]
+    fn raw_encoding() {
+        let clock = ManualClock::new();
+        let mut sim = SimIna228::new(Box::new(clock), Waveform::parse("constant:5:-0.25").unwrap(), SHUNT);
+        sim.write(INA228_ADDR, &[REG_VBUS]).unwrap();
+        let mut buf = [0u8; 3];
+        sim.read(INA228_ADDR, &mut buf).unwrap();
+        let vbus = ((buf[0] as u32) << 16 | (buf[1] as u32) << 8 | buf[2] as u32) >> 4;
+        assert_eq!(vbus, (5.0 / VBUS_LSB) as u32);
+        // Without SHUNT_CAL there is no current.
+        sim.write(INA228_ADDR, &[REG_CURRENT]).unwrap();
+        sim.read(INA228_ADDR, &mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 0]);
+        // Negative current is 20-bit two's complement in the upper bits.
+        sim.write(INA228_ADDR, &[REG_SHUNT_CAL, 0x0F, 0x5C]).unwrap();
+        sim.write(INA228_ADDR, &[REG_CURRENT]).unwrap();
+        sim.read(INA228_ADDR, &mut buf).unwrap();
+        assert_eq!(buf[0] & 0x80, 0x80);
+        assert_eq!(buf[2] & 0x0F, 0);
+        assert!(sim.write(0x41, &[REG_VBUS]).is_err());
+        assert!(sim.read(0x41, &mut buf).is_err());
+    }
+
+    #[test]
+    fn accumulators() {
+        let (mut ina, clock) = sensor("constant:5:0.5");
+        ina.read_energy().unwrap();
+        clock.advance(3600 * 1000);
+        let energy = ina.read_energy().unwrap();
+        let charge = ina.read_charge().unwrap();
+        assert!(close(energy, 2.5, 0.01), "{}", energy);
+        assert!(close(charge, 0.5, 0.001), "{}", charge);
+        ina.reset_accumulators().unwrap();
+        assert_eq!(ina.read_energy().unwrap(), 0.0);
+    }
+}
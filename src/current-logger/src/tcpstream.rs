@@ -0,0 +1,394 @@
+// TCP streaming: one connection to the collector stays open, the records go in binary frames and
+// leave the buffer when the collector acknowledged them.
+//
+// Frame, big endian:
+//   0  length               u32, of the rest
+//   4  version              u8 (1), a frame of another version closes the connection
+//   5  kind                 u8
+//   6  body
+//
+// Bodies, the records and the battery as in udpstream.rs:
+//   hello      1  logger: u32 stream, tag. Collector: empty, before anything else is sent
+//   session    2  u32 sequence, u8 logging, u32 interval ms, reason
+//   batch      3  u32 sequence, battery, records
+//   ack        4  u32 sequence of the frame taken, by the collector
+//   heartbeat  5  empty, answered by the collector
+//   event      6  u32 sequence, battery, name
+//
+// After a reconnect the unacknowledged frame is sent again with its sequence, the collector
+// acknowledges a sequence of the stream it already has without taking it twice.
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use log::*;
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::{thread, time::Duration, time::Instant, sync::Arc, sync::Mutex};
+use anyhow::{anyhow, bail, Result};
+
+use crate::currentlogs::CurrentLog;
+use crate::battery::BatteryState;
+use crate::hal::{Uplink, Connection, Connector};
+use crate::httpclient::TcpConnector;
+use crate::provision::DEFAULT_NAME;
+use crate::transfer::Transfer;
+use crate::udpstream::{stream_id, put_str, put_battery, put_records};
+
+pub const DEFAULT_PORT: u16 = 3003;
+pub const VERSION: u8 = 1;
+pub const HELLO: u8 = 1;
+pub const SESSION: u8 = 2;
+pub const BATCH: u8 = 3;
+pub const ACK: u8 = 4;
+pub const HEARTBEAT: u8 = 5;
+pub const EVENT: u8 = 6;
+
+pub const MAX_FRAME: usize = 16 * 1024;
+const BATCH_RECORDS: usize = 64;
+const CONNECT_TIMEOUT_MS: u64 = 10_000;
+const RETRY_MS: u64 = 1000;
+const POLL_MS: u64 = 20;
+const HEARTBEAT_MS: u64 = 5000;     // When nothing else was sent, the collector is lost after 3 without an answer
+const MAX_QUEUED: usize = 32;       // Frames besides the records, older ones are dropped when offline
+
+/// A frame split into its kind and body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub kind: u8,
+    pub body: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(kind: u8, body: Vec<u8>) -> Frame {
+        Frame { kind: kind, body: body }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(6 + self.body.len());
+        out.extend_from_slice(&(2 + self.body.len() as u32).to_be_bytes());
+        out.push(VERSION);
+        out.push(self.kind);
+        out.extend_from_slice(&self.body);
+        out
+    }
+
+    /// A frame from the front of `input` and the bytes it took, None until it is complete.
+    pub fn decode(input: &[u8]) -> Result<Option<(Frame, usize)>> {
+        if input.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([input[0], input[1], input[2], input[3]]) as usize;
+        if (2..=MAX_FRAME).contains(&len) == false {
+            bail!("Invalid frame length {}", len);
+        }
+        if input.len() < 4 + len {
+            return Ok(None);
+        }
+        if input[4] != VERSION {
+            bail!("Unsupported version {}", input[4]);
+        }
+        Ok(Some((Frame::new(input[5], input[6..4 + len].to_vec()), 4 + len)))
+    }
+
+    /// The sequence at the start of the body.
+    pub fn sequence(&self) -> Result<u32> {
+        let bytes = self.body.get(..4).ok_or(anyhow!("Frame {} without a sequence", self.kind))?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+}
+
+/// The logger's end of the connection.
+pub struct StreamClient {
+    conn: Box<dyn Connection>,
+    input: Vec<u8>,
+    last_sent: Instant,
+    last_received: Instant,
+}
+
+impl StreamClient {
+    /// Says hello on `conn` and waits for the collector's.
+    pub fn connect(mut conn: Box<dyn Connection>, stream: u32, tag: &str, timeout: Duration) -> Result<StreamClient> {
+        conn.set_read_timeout(timeout)?;
+        let mut client = StreamClient { conn: conn, input: Vec::new(), last_sent: Instant::now(), last_received: Instant::now() };
+        let mut hello = stream.to_be_bytes().to_vec();
+        put_str(&mut hello, tag);
+        client.send(&Frame::new(HELLO, hello))?;
+        match client.read_frame()? {
+            Some(frame) if frame.kind == HELLO => {},
+            Some(frame) => bail!("Expected hello, got frame {}", frame.kind),
+            None => bail!("No hello from the collector"),
+        }
+        client.conn.set_read_timeout(Duration::from_millis(POLL_MS))?;
+        Ok(client)
+    }
+
+    pub fn send(&mut self, frame: &Frame) -> Result<()> {
+        self.conn.write_all(&frame.encode())?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Waits shortly for a frame from the collector and keeps the connection alive.
+    pub fn poll(&mut self) -> Result<Option<Frame>> {
+        if self.last_received.elapsed() > Duration::from_millis(3 * HEARTBEAT_MS) {
+            bail!("No heartbeat from the collector");
+        }
+        if self.last_sent.elapsed() >= Duration::from_millis(HEARTBEAT_MS) {
+            self.send(&Frame::new(HEARTBEAT, Vec::new()))?;
+        }
+        match self.read_frame()? {
+            Some(frame) if frame.kind == HEARTBEAT => Ok(None),
+            frame => Ok(frame),
+        }
+    }
+
+    // A frame when one is complete within the read timeout.
+    fn read_frame(&mut self) -> Result<Option<Frame>> {
+        loop {
+            if let Some((frame, len)) = Frame::decode(&self.input)? {
+                self.input.drain(..len);
+                self.last_received = Instant::now();
+                return Ok(Some(frame));
+            }
+            let mut buf = [0u8; 512];
+            match self.conn.read(&mut buf) {
+                Ok(0) => bail!("Connection closed by the collector"),
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+struct Outgoing {
+    frame: Frame,
+    sequence: u32,
+    records: usize,     // Records in a batch
+    sent: bool,         // On the current connection
+}
+
+struct TcpData {
+    outbox: VecDeque<Outgoing>,
+    pending: usize,     // Records in the outbox
+    acked: usize,       // Records the collector accepted, not yet taken by the logger
+}
+
+pub struct TcpUplink {
+    data: Arc<Mutex<TcpData>>,
+    server: String,
+    tag: String,
+    stream: u32,
+    sequence: u32,
+}
+
+impl TcpUplink {
+    /// `server` is host:port of the collector, the port defaults to 3003.
+    pub fn new(server: String) -> Self {
+        let server = if server.contains(':') { server } else { format!("{}:{}", server, DEFAULT_PORT) };
+        TcpUplink { data: Arc::new(Mutex::new(
+            TcpData { outbox: VecDeque::new(), pending: 0, acked: 0 })),
+            server: server,
+            tag: DEFAULT_NAME.to_string(),
+            stream: stream_id(),
+            sequence: 0 }
+    }
+
+    /// Sets the device name sent in the hello.
+    pub fn set_tag(&mut self, tag: &str) {
+        self.tag = tag.to_string();
+    }
+
+    pub fn start(&mut self) -> Result<()>
+    {
+        let data = self.data.clone();
+        let server = self.server.clone();
+        let tag = self.tag.clone();
+        let stream = self.stream;
+        info!("TCP stream {:08x} to {}", stream, server);
+        let _th = thread::spawn(move || {
+            info!("Start TCP stream thread.");
+            let mut client: Option<StreamClient> = None;
+            loop {
+                if client.is_none() {
+                    client = Self::connect(&server, stream, &tag);
+                    if client.is_none() {
+                        thread::sleep(Duration::from_millis(RETRY_MS));
+                        continue;
+                    }
+                }
+                if let Err(e) = Self::exchange(client.as_mut().unwrap(), &data) {
+                    info!("TCP stream {}", e);
+                    client = None;
+                    if let Some(front) = data.lock().unwrap().outbox.front_mut() {
+                        front.sent = false;
+                    }
+                    thread::sleep(Duration::from_millis(RETRY_MS));
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn connect(server: &str, stream: u32, tag: &str) -> Option<StreamClient>
+    {
+        let addr = Transfer::resolve(server)?;
+        let timeout = Duration::from_millis(CONNECT_TIMEOUT_MS);
+        match TcpConnector.connect(server, addr, timeout).and_then(|conn| StreamClient::connect(conn, stream, tag, timeout)) {
+            Ok(client) => {
+                info!("TCP stream connected to {}", server);
+                Some(client)
+            },
+            Err(e) => { info!("TCP stream {}: {}", server, e); None },
+        }
+    }
+
+    // Sends the front of the outbox, one at a time, and takes the acknowledgements.
+    fn exchange(client: &mut StreamClient, data: &Arc<Mutex<TcpData>>) -> Result<()>
+    {
+        let mut lck = data.lock().unwrap();
+        if let Some(front) = lck.outbox.front_mut() {
+            if front.sent == false {
+                client.send(&front.frame)?;
+                front.sent = true;
+            }
+        }
+        drop(lck);
+        while let Some(frame) = client.poll()? {
+            if frame.kind != ACK {
+                bail!("Unexpected frame {}", frame.kind);
+            }
+            let sequence = frame.sequence()?;
+            let mut lck = data.lock().unwrap();
+            if lck.outbox.front().map(|f| f.sent && f.sequence == sequence) == Some(true) {
+                let front = lck.outbox.pop_front().unwrap();
+                if front.records > 0 {
+                    // Only now the records may leave the buffer.
+                    lck.acked += front.records;
+                    lck.pending = 0;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Numbers the frame with the next sequence and queues it.
+    fn queue(&mut self, kind: u8, body: Vec<u8>, records: usize)
+    {
+        let mut frame = self.sequence.to_be_bytes().to_vec();
+        frame.extend_from_slice(&body);
+        let mut lck = self.data.lock().unwrap();
+        if records == 0 && lck.outbox.len() >= MAX_QUEUED {
+            // The front may be in flight and the records must stay, the oldest other frame goes.
+            if let Some(index) = lck.outbox.iter().skip(1).position(|o| o.records == 0) {
+                info!("TCP stream queue full, dropped a frame");
+                lck.outbox.remove(index + 1);
+            }
+        }
+        lck.outbox.push_back(Outgoing { frame: Frame::new(kind, frame), sequence: self.sequence,
+                                        records: records, sent: false });
+        self.sequence = self.sequence.wrapping_add(1);
+    }
+}
+
+impl Uplink for TcpUplink {
+    fn set_transfer_data(&mut self, data: &[CurrentLog]) -> usize
+    {
+        if data.is_empty() {
+            return 0;
+        }
+        let lck = self.data.lock().unwrap();
+        if lck.pending > 0 || lck.acked > 0 {
+            return 0;
+        }
+        drop(lck);
+        let records = &data[..data.len().min(BATCH_RECORDS)];
+        let mut body = Vec::with_capacity(16 + records.len() * 24);
+        put_battery(&mut body, &records[records.len() - 1].battery);
+        put_records(&mut body, records);
+        self.queue(BATCH, body, records.len());
+        self.data.lock().unwrap().pending = records.len();
+        records.len()
+    }
+
+    fn take_acknowledged(&mut self) -> usize
+    {
+        let mut lck = self.data.lock().unwrap();
+        let acked = lck.acked;
+        lck.acked = 0;
+        acked
+    }
+
+    fn send_event(&mut self, event: &str, battery: &BatteryState) -> bool
+    {
+        let mut body = Vec::new();
+        put_battery(&mut body, battery);
+        put_str(&mut body, event);
+        self.queue(EVENT, body, 0);
+        true
+    }
+
+    fn is_idle(&self) -> bool
+    {
+        self.data.lock().unwrap().outbox.is_empty()
+    }
+
+    fn session(&mut self, logging: bool, interval: u32, reason: &str)
+    {
+        let mut body = vec![logging as u8];
+        body.extend_from_slice(&interval.to_be_bytes());
+        put_str(&mut body, reason);
+        self.queue(SESSION, body, 0);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_round_trip() {
+        let frame = Frame::new(BATCH, vec![0, 0, 0, 7, 1, 2, 3]);
+        let bytes = frame.encode();
+        assert_eq!(bytes[..6], [0, 0, 0, 9, VERSION, BATCH]);
+        assert_eq!(Frame::decode(&bytes).unwrap(), Some((frame.clone(), bytes.len())));
+        assert_eq!(frame.sequence().unwrap(), 7);
+        assert!(Frame::new(HEARTBEAT, Vec::new()).sequence().is_err());
+    }
+
+    #[test]
+    fn partial_input_waits() {
+        let mut bytes = Frame::new(EVENT, b"start".to_vec()).encode();
+        for end in 0..bytes.len() {
+            assert_eq!(Frame::decode(&bytes[..end]).unwrap(), None, "{} bytes", end);
+        }
+        // A second frame behind the first is left for the next decode.
+        let second = Frame::new(ACK, vec![0, 0, 0, 1]).encode();
+        bytes.extend_from_slice(&second);
+        let (frame, used) = Frame::decode(&bytes).unwrap().unwrap();
+        assert_eq!(frame.body, b"start");
+        assert_eq!(Frame::decode(&bytes[used..]).unwrap(), Some((Frame::new(ACK, vec![0, 0, 0, 1]), second.len())));
+    }
+
+    #[test]
+    fn bad_version() {
+        let mut bytes = Frame::new(HELLO, Vec::new()).encode();
+        bytes[4] = VERSION + 1;
+        assert_eq!(Frame::decode(&bytes).unwrap_err().to_string(), "Unsupported version 2");
+        // Only a complete frame is checked.
+        assert_eq!(Frame::decode(&bytes[..5]).unwrap(), None);
+    }
+
+    #[test]
+    fn bad_length() {
+        for len in [0u32, 1, MAX_FRAME as u32 + 1, u32::MAX] {
+            // Refused from the length alone, without waiting for the rest.
+            assert!(Frame::decode(&len.to_be_bytes()).is_err(), "length {}", len);
+        }
+        let mut bytes = (MAX_FRAME as u32).to_be_bytes().to_vec();
+        assert_eq!(Frame::decode(&bytes).unwrap(), None);
+        bytes.extend_from_slice(&[VERSION, BATCH]);
+        bytes.resize(4 + MAX_FRAME, 0);
+        assert_eq!(Frame::decode(&bytes).unwrap().unwrap().1, 4 + MAX_FRAME);
+    }
+}
@@ -0,0 +1,132 @@
+// Certificate pinning: the SHA-256 of the server certificate (DER) must match the configured one.
+#![allow(clippy::bool_comparison)]
+
+use anyhow::{anyhow, Result};
+
+/// Parses 64 hex digits, optionally separated by ':' as openssl prints them. Empty is no pin.
+pub fn parse_pin(pin: &str) -> Result<Option<[u8; 32]>> {
+    let hex: String = pin.chars().filter(|c| *c != ':' && !c.is_whitespace()).collect();
+    if hex.is_empty() {
+        return Ok(None);
+    }
+    if hex.len() != 64 || hex.chars().all(|c| c.is_ascii_hexdigit()) == false {
+        return Err(anyhow!("Certificate pin must be a SHA-256 in hex"));
+    }
+    let mut digest = [0u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| anyhow!("Invalid certificate pin"))?;
+    }
+    Ok(Some(digest))
+}
+
+/// A digest that cannot be computed never matches.
+pub fn matches_pin(der: &[u8], pin: &[u8; 32]) -> bool {
+    matches!(sha256(der), Ok(digest) if digest == *pin)
+}
+
+/// By mbedTLS, on the SHA accelerator of the ESP32-C3. In the mbedTLS 2.28 of ESP-IDF v4.4
+/// mbedtls_sha256() is the deprecated form of mbedtls_sha256_ret().
+#[cfg(feature = "native")]
+pub fn sha256(data: &[u8]) -> Result<[u8; 32]> {
+    let mut digest = [0u8; 32];
+    let ret = unsafe { esp_idf_sys::mbedtls_sha256_ret(data.as_ptr(), data.len() as _, digest.as_mut_ptr(), 0) };
+    if ret != 0 {
+        return Err(anyhow!("SHA-256 failed {}", ret));
+    }
+    Ok(digest)
+}
+
+#[cfg(not(feature = "native"))]
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+// The same in Rust for the host build.
+#[cfg(not(feature = "native"))]
+pub fn sha256(data: &[u8]) -> Result<[u8; 32]> {
+    let mut h: [u32; 8] = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a,
+                           0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    for block in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = u32::from_be_bytes([block[i * 4], block[i * 4 + 1], block[i * 4 + 2], block[i * 4 + 3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let mut v = h;
+        for i in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^ v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^ v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [t1.wrapping_add(t2), v[0], v[1], v[2], v[3].wrapping_add(t1), v[4], v[5], v[6]];
+        }
+        for i in 0..8 {
+            h[i] = h[i].wrapping_add(v[i]);
+        }
+    }
+    let mut digest = [0u8; 32];
+    for i in 0..8 {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&h[i].to_be_bytes());
+    }
+    Ok(digest)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(digest: &[u8; 32]) -> String {
+        digest.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn fips_180_vectors() {
+        assert_eq!(hex(&sha256(b"").unwrap()), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        assert_eq!(hex(&sha256(b"abc").unwrap()), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(&sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq").unwrap()),
+                   "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1");
+        // Padding that needs a second block, and several blocks.
+        assert_eq!(hex(&sha256(&[b'a'; 1000]).unwrap()), "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3");
+    }
+
+    #[test]
+    fn pin_formats() {
+        let digest = sha256(b"abc").unwrap();
+        let plain = hex(&digest);
+        let colons = digest.iter().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(":");
+        assert_eq!(parse_pin(&plain).unwrap(), Some(digest));
+        assert_eq!(parse_pin(&colons).unwrap(), Some(digest));
+        assert_eq!(parse_pin(&format!(" {} ", plain)).unwrap(), Some(digest));
+        assert_eq!(parse_pin("").unwrap(), None);
+        assert!(matches_pin(b"abc", &digest));
+        assert!(matches_pin(b"abd", &digest) == false);
+    }
+
+    #[test]
+    fn invalid_pins() {
+        assert!(parse_pin(&"ab".repeat(31)).is_err());
+        assert!(parse_pin(&"ab".repeat(33)).is_err());
+        assert!(parse_pin(&"zz".repeat(32)).is_err());
+        assert!(parse_pin(&format!("{}é", "a".repeat(62))).is_err());
+    }
+}
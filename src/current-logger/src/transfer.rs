@@ -1,110 +1,469 @@
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
 use log::*;
 use std::{thread, time::Duration, sync::Arc, sync::Mutex};
-use esp_idf_hal::task;
-use std::io::prelude::*;
 use std::io::Error;
-use std::net::{TcpStream, Shutdown};
-
-const MEASUREMENT : &str  = "currentlogger";
-const POINT_TAG : &str    = "currentch1";
+use std::net::{SocketAddr, ToSocketAddrs};
 
 use anyhow::Result;
 
-use crate::CurrentLog;
+use crate::currentlogs::CurrentLog;
+use crate::battery::BatteryState;
+use crate::hal::{Uplink, Discovery, Connector};
+use crate::provision::DEFAULT_NAME;
+use crate::httpclient::{HttpClient, TcpConnector};
+use crate::lineprotocol::{self, PointFormat};
 
-const HTTP_HEADER : &str = "Content-Type: application/json\r\nAcept: */*\r\nUser-Agent: temp-logger";
+const CONTENT_TYPE : &str = "application/json";
+const CHUNK_RECORDS : usize = 64;
+const UPLOAD_TIMEOUT_MS : u64 = 10_000;
+const RETRY_MS : u64 = 1000;
 
 struct TransferData {
     body: String,
     txreq: bool,
+    pending: usize,     // Records in the body
+    acked: usize,       // Records the server accepted, not yet taken by the logger
+}
+
+/// Body of the uploads.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UploadFormat {
+    Json,           // For the agent in src/server
+    LineProtocol,   // InfluxDB v2 /api/v2/write
+}
+
+impl UploadFormat {
+    pub fn from_name(name: &str) -> Option<UploadFormat> {
+        match name {
+            "json"   => Some(UploadFormat::Json),
+            "influx" => Some(UploadFormat::LineProtocol),
+            _ => None,
+        }
+    }
 }
 
 pub struct Transfer {
     data: Arc<Mutex<TransferData>>,
     server: String,
+    tag: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    discovery: Option<Box<dyn Discovery>>,
+    tls: Option<Box<dyn Connector>>,
+    format: UploadFormat,
+    point: PointFormat,
+    waiting_clock: bool,
 }
 
 impl Transfer {
     pub fn new(server: String) -> Self {
         Transfer { data: Arc::new(Mutex::new(
-            TransferData { body: "".to_string(), txreq: false })),
-            server: server}
+            TransferData { body: "".to_string(), txreq: false, pending: 0, acked: 0 })),
+            server: server,
+            tag: DEFAULT_NAME.to_string(),
+            path: "/".to_string(),
+            headers: Vec::new(),
+            discovery: None,
+            tls: None,
+            format: UploadFormat::Json,
+            point: PointFormat::new(),
+            waiting_clock: false }
+    }
+
+    /// Selects the body of the uploads, `point` names the measurement, the tag and the fields.
+    /// JSON only takes the measurement, the agent expects its own names.
+    pub fn set_format(&mut self, format: UploadFormat, point: PointFormat) {
+        self.format = format;
+        self.point = point;
+    }
+
+    /// Sets the path of the upload requests and the headers sent with them, e.g. authentication.
+    pub fn set_endpoint(&mut self, path: &str, headers: Vec<(String, String)>) {
+        self.path = if path.starts_with('/') { path.to_string() } else { format!("/{}", path) };
+        self.headers = headers;
+    }
+
+    /// A collector found by `discovery` is used before the configured server.
+    pub fn set_discovery(&mut self, discovery: Box<dyn Discovery>) {
+        self.discovery = Some(discovery);
+    }
+
+    /// Opens the connections to an https:// server.
+    pub fn set_tls_connector(&mut self, tls: Box<dyn Connector>) {
+        self.tls = Some(tls);
+    }
+
+    /// Sets the device name sent as the tag of every point.
+    pub fn set_tag(&mut self, tag: &str) {
+        self.tag = tag.to_string();
     }
 
     pub fn start(&mut self) -> Result<(), Error>
     {
         let data = self.data.clone();
-        let server = self.server.clone();
+        let (server_tls, server) = split_scheme(&self.server);
+        let path = self.path.clone();
+        let headers = self.headers.clone();
+        let mut discovery = self.discovery.take();
+        let mut tls = self.tls.take();
+        let content_type = match self.format {
+            UploadFormat::Json => CONTENT_TYPE,
+            UploadFormat::LineProtocol => lineprotocol::CONTENT_TYPE,
+        };
+        if server_tls && tls.is_none() {
+            info!("No TLS support for {}", self.server);
+        }
         let _th = thread::spawn(move || -> anyhow::Result<()> {
             info!("Start transfer thread.");
+            // Located again after a failure, so that a server moved to another address is found.
+            let mut client: Option<HttpClient> = None;
+            let mut use_discovery = true;   // Alternates with the server after a failure
+            let mut plain = TcpConnector;
             loop {
-                task::wait_notification(Some(Duration::from_millis(100)));
-                let mut lck = data.lock().unwrap();
+                thread::sleep(Duration::from_millis(100));
+                let lck = data.lock().unwrap();
                 if lck.txreq == false {
                     drop(lck);
                     continue;
                 }
-                let request = format!("POST / HTTP/1.1\r\nHost: {}\r\n{}\r\nContent-Length:{}\r\n\r\n{}\r\n",
-                    &server, HTTP_HEADER, lck.body.len(), lck.body);
-                drop(lck);                
-                let ret = Self::transfer(&server, request);
-                lck = data.lock().unwrap();
-                match ret {
-                    Ok(()) => { lck.txreq = false; },
-                    Err(e) => { info!("{}", e) },
-                }
+                let body = lck.body.clone();
                 drop(lck);
+                if client.is_none() {
+                    client = Self::locate(if use_discovery { discovery.as_mut() } else { None }, &server, server_tls)
+                        .map(|(host, addr, is_tls)| HttpClient::new(&host, addr, is_tls, Duration::from_millis(UPLOAD_TIMEOUT_MS)));
+                }
+                let http = match client.as_mut() {
+                    Some(http) => http,
+                    None => { thread::sleep(Duration::from_millis(RETRY_MS)); continue; },
+                };
+                let connector: &mut dyn Connector = match (http.is_tls(), tls.as_mut()) {
+                    (false, _) => &mut plain,
+                    (true, Some(tls)) => tls.as_mut(),
+                    (true, None) => { thread::sleep(Duration::from_millis(RETRY_MS)); continue; },
+                };
+                match http.post(connector, &path, &headers, content_type, body.as_bytes()) {
+                    Ok(response) if response.is_success() => {
+                        // Only now the records may leave the buffer.
+                        let mut lck = data.lock().unwrap();
+                        lck.acked += lck.pending;
+                        lck.pending = 0;
+                        lck.txreq = false;
+                    },
+                    Ok(response) => {
+                        info!("Server replied {}: {}", response.status, String::from_utf8_lossy(&response.body));
+                        thread::sleep(Duration::from_millis(RETRY_MS));
+                    },
+                    Err(e) => {
+                        info!("{}", e);
+                        use_discovery = http.host() == server;
+                        client = None;
+                    },
+                }
             }
         });
 
         Ok(())
     }
 
-    fn transfer(server: &String, request: String) -> Result<(), std::io::Error>
+    // A discovered collector comes first, it is plain HTTP on the LAN. Then the configured server.
+    fn locate(discovery: Option<&mut Box<dyn Discovery>>, server: &str, tls: bool) -> Option<(String, SocketAddr, bool)>
     {
-        let mut stream = TcpStream::connect(&server)?;
-        stream.write(request.as_bytes())?;
-        let mut rcvbuf = [0u8; 1024];
-        stream.read(&mut rcvbuf)?;        
-        stream.shutdown(Shutdown::Both).expect("shutdown call failed");
-        Ok(())
+        if let Some(found) = discovery.and_then(|d| d.find()) {
+            if let Some(addr) = Self::resolve(&found) {
+                return Some((found, addr, false));
+            }
+        }
+        Self::resolve(server).map(|addr| (server.to_string(), addr, tls))
     }
 
-
-    pub fn set_transfer_data(&mut self, data: &Vec<CurrentLog>) -> usize
+    /// Resolves host:port to an IPv4 address, the host may be a name.
+    pub fn resolve(server: &str) -> Option<SocketAddr>
     {
-        if data.len() == 0 {
-            return 0;
-        }
-        let mut lck = self.data.lock().unwrap();
-        if lck.txreq == true {
-            // There is sending data in buffer.
-            return 0;
+        match server.to_socket_addrs().map(|mut addrs| addrs.find(|a| a.is_ipv4())) {
+            Ok(Some(addr)) => {
+                info!("Server {} is {}", server, addr);
+                Some(addr)
+            },
+            Ok(None) => { info!("No IPv4 address for {}", server); None },
+            Err(e) => { info!("Could not resolve {}: {}", server, e); None },
         }
-        lck.body = format!("[ ");
+    }
+
+    /// Formats up to one chunk of records as the JSON body and returns it with the record count.
+    /// "time" is the wall clock in ms, it is left out while the clock is not set.
+    pub fn build_body(data: &[CurrentLog], measurement: &str, tag: &str) -> (String, usize)
+    {
+        let mut body = "[ ".to_string();
         let mut count = 0;
         for it in data {
-            lck.body.push_str(
-                &format!("{{ \"measurement\": \"{}\", \"tag\": \"{}\", \"timestamp\": {}, \"current\": {:.5}, \"voltage\": {:.5},  \"power\": {:.5}, \"bat\": {:.2} }}",
-                MEASUREMENT,
-                POINT_TAG,
+            let time = if it.has_time() { format!("\"time\": {}, ", it.time) } else { "".to_string() };
+            body.push_str(
+                &format!("{{ \"measurement\": \"{}\", \"tag\": \"{}\", \"timestamp\": {}, {}\"current\": {:.5}, \"voltage\": {:.5},  \"power\": {:.5}, \"bat\": {:.2}, \"bat_level\": {}, \"bat_runtime\": {}, \"bat_source\": \"{}\" }}",
+                json_escape(measurement),
+                json_escape(tag),
                 it.clock,
+                time,
                 it.current,
                 it.voltage,
                 it.power,
-                it.battery
+                it.battery.voltage,
+                it.battery.percent,
+                it.battery.runtime,
+                it.battery.source.name()
             ));
             count += 1;
-            if count == 64 {
+            if count == CHUNK_RECORDS {
                 info!("Chunk data");
                 break;
             }
             if data.len() != count {
-                lck.body.push_str(",");
+                body.push(',');
             }
         }
-        lck.body.push_str("]");
+        body.push(']');
+        (body, count)
+    }
+
+    /// Formats an event, the server stamps it with its own time.
+    pub fn build_event(event: &str, battery: &BatteryState, measurement: &str, tag: &str) -> String
+    {
+        format!("[ {{ \"measurement\": \"{}\", \"tag\": \"{}\", \"event\": \"{}\", \"bat\": {:.2}, \"bat_level\": {} }}]",
+            json_escape(measurement),
+            json_escape(tag),
+            json_escape(event),
+            battery.voltage,
+            battery.percent)
+    }
+}
+
+impl Uplink for Transfer {
+    fn set_transfer_data(&mut self, data: &[CurrentLog]) -> usize
+    {
+        if data.is_empty() {
+            return 0;
+        }
+        let mut lck = self.data.lock().unwrap();
+        if lck.txreq || lck.acked > 0 {
+            // There is sending data in buffer, or sent records are still in the logger's buffer.
+            return 0;
+        }
+        let (body, count) = match self.format {
+            UploadFormat::Json => Self::build_body(data, &self.point.measurement, &self.tag),
+            UploadFormat::LineProtocol => self.point.format_records(data, &self.tag, CHUNK_RECORDS),
+        };
+        if count == 0 {
+            // InfluxDB needs the time of every point, the logger moves the records onto the wall clock once it is set.
+            if self.waiting_clock == false {
+                info!("Waiting for the wall clock");
+                self.waiting_clock = true;
+            }
+            return 0;
+        }
+        self.waiting_clock = false;
+        lck.body = body;
+        lck.pending = count;
         lck.txreq = true;
-        count as usize
+        count
+    }
+
+    fn take_acknowledged(&mut self) -> usize
+    {
+        let mut lck = self.data.lock().unwrap();
+        let acked = lck.acked;
+        lck.acked = 0;
+        acked
+    }
+
+    fn send_event(&mut self, event: &str, battery: &BatteryState) -> bool
+    {
+        let mut lck = self.data.lock().unwrap();
+        if lck.txreq {
+            return false;
+        }
+        lck.body = match self.format {
+            UploadFormat::Json => Self::build_event(event, battery, &self.point.measurement, &self.tag),
+            UploadFormat::LineProtocol => self.point.format_event(event, battery, &self.tag),
+        };
+        lck.pending = 0;
+        lck.txreq = true;
+        true
+    }
+
+    fn is_idle(&self) -> bool
+    {
+        self.data.lock().unwrap().txreq == false
+    }
+}
+
+/// Splits "http://host[:port]" or "https://host[:port]" into TLS and host:port with the default port.
+/// Without a scheme it is plain host:port as before.
+pub fn split_scheme(server: &str) -> (bool, String) {
+    let (tls, rest) = if let Some(rest) = server.strip_prefix("https://") {
+        (true, rest)
+    }
+    else if let Some(rest) = server.strip_prefix("http://") {
+        (false, rest)
+    }
+    else {
+        return (false, server.to_string());
+    };
+    let host = rest.trim_end_matches('/');
+    if host.contains(':') {
+        (tls, host.to_string())
+    }
+    else {
+        (tls, format!("{}:{}", host, if tls { 443 } else { 80 }))
+    }
+}
+
+/// Escapes a string for the inside of JSON quotes, control characters included.
+pub fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '"' => out.push_str("\\\""),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{0}'..='\u{1f}' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    fn records(count: u32) -> Vec<CurrentLog> {
+        (0..count).map(|clock| {
+            let mut data = CurrentLog::default();
+            data.clock = clock;
+            data.voltage = 5.0;
+            data.current = 0.001;
+            data.power = 0.005;
+            data
+        }).collect()
+    }
+
+    #[test]
+    fn body_of_a_few_records() {
+        let (body, count) = Transfer::build_body(&records(2), "current", "ch1");
+        assert_eq!(count, 2);
+        assert_eq!(body, "[ { \"measurement\": \"current\", \"tag\": \"ch1\", \"timestamp\": 0, \"current\": 0.00100, \"voltage\": 5.00000,  \
+                          \"power\": 0.00500, \"bat\": 0.00, \"bat_level\": 0, \"bat_runtime\": 0, \"bat_source\": \"battery\" },\
+                          { \"measurement\": \"current\", \"tag\": \"ch1\", \"timestamp\": 1, \"current\": 0.00100, \"voltage\": 5.00000,  \
+                          \"power\": 0.00500, \"bat\": 0.00, \"bat_level\": 0, \"bat_runtime\": 0, \"bat_source\": \"battery\" }]");
+    }
+
+    #[test]
+    fn body_is_cut_at_the_chunk() {
+        for (records_in, expected) in [(63, 63), (64, 64), (65, 64), (200, 64)] {
+            let (body, count) = Transfer::build_body(&records(records_in), "current", "ch1");
+            assert_eq!(count, expected);
+            assert_eq!(body.matches("\"measurement\"").count(), expected);
+            // No comma after the last record, also when the chunk ends before the data.
+            assert!(body.ends_with("\" }]"), "{}", &body[body.len() - 20..]);
+            assert_eq!(body.matches("},{").count(), expected - 1);
+            assert!(body.contains(&format!("\"timestamp\": {},", expected - 1)));
+        }
+    }
+
+    #[test]
+    fn body_of_nothing() {
+        assert_eq!(Transfer::build_body(&Vec::new(), "current", "ch1"), ("[ ]".to_string(), 0));
+    }
+
+    #[test]
+    fn time_only_on_the_wall_clock() {
+        let mut data = records(2);
+        data[1].time = crate::hal::WALL_CLOCK_SET_MS + 5;
+        let (body, _) = Transfer::build_body(&data, "current", "ch1");
+        assert_eq!(body.matches("\"time\"").count(), 1);
+        assert!(body.contains(&format!("\"time\": {}, ", crate::hal::WALL_CLOCK_SET_MS + 5)));
+    }
+
+    #[test]
+    fn names_are_escaped() {
+        let (body, _) = Transfer::build_body(&records(1), "lab \"A\"", "bench\\1");
+        assert!(body.starts_with("[ { \"measurement\": \"lab \\\"A\\\"\", \"tag\": \"bench\\\\1\", "), "{}", body);
+        let event = Transfer::build_event("say \"hi\"", &BatteryState::new(), "current", "ch\"1");
+        assert_eq!(event, "[ { \"measurement\": \"current\", \"tag\": \"ch\\\"1\", \"event\": \"say \\\"hi\\\"\", \
+                           \"bat\": 0.00, \"bat_level\": 0 }]");
+    }
+
+    #[test]
+    fn control_characters_are_escaped() {
+        assert_eq!(json_escape("a\nb\r\tc"), "a\\nb\\r\\tc");
+        assert_eq!(json_escape("\u{0}\u{1b}[1m\u{1f}"), "\\u0000\\u001b[1m\\u001f");
+        assert_eq!(json_escape("\u{7f} \u{e9}"), "\u{7f} \u{e9}");
+        let event = Transfer::build_event("line 1\nline 2", &BatteryState::new(), "current", "ch1");
+        assert!(event.contains("\"event\": \"line 1\\nline 2\""), "{}", event);
+    }
+
+    #[test]
+    fn scheme() {
+        assert_eq!(split_scheme("10.0.0.2:3001"), (false, "10.0.0.2:3001".to_string()));
+        assert_eq!(split_scheme("https://example.com/"), (true, "example.com:443".to_string()));
+        assert_eq!(split_scheme("http://example.com"), (false, "example.com:80".to_string()));
+        assert_eq!(split_scheme("https://example.com:8443"), (true, "example.com:8443".to_string()));
+    }
+
+    // Answers each upload with the next of `statuses` on one kept-alive connection.
+    fn collector(statuses: Vec<u16>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            for status in statuses {
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                reader.read_exact(&mut vec![0u8; length]).unwrap();
+                write!(reader.get_mut(), "HTTP/1.1 {} Status\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+            }
+        });
+        server
+    }
+
+    fn wait_for(what: impl Fn() -> bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if what() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        false
+    }
+
+    #[test]
+    fn records_stay_until_success() {
+        let mut txd = Transfer::new(collector(vec![503, 204]));
+        txd.start().unwrap();
+        assert_eq!(txd.set_transfer_data(&records(3)), 3);
+        // The refused upload is sent again after RETRY_MS, the records are not released before.
+        thread::sleep(Duration::from_millis(RETRY_MS / 2));
+        assert_eq!(txd.take_acknowledged(), 0);
+        assert!(txd.is_idle() == false);
+        assert_eq!(txd.set_transfer_data(&records(3)), 0);
+        assert!(wait_for(|| txd.is_idle()));
+        assert_eq!(txd.take_acknowledged(), 3);
+        assert_eq!(txd.take_acknowledged(), 0);
     }
 }
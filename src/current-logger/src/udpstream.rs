@@ -0,0 +1,325 @@
+// UDP streaming: the records go out in binary datagrams as soon as they are logged, without a connection.
+//
+// Datagram, big endian:
+//   0  "CL"
+//   2  version              u8 (1)
+//   3  kind                 u8, 1 records, 2 event
+//   4  stream               u32, new at every start, the sequence restarts with it
+//   8  sequence             u32, +1 per datagram
+//  12  first record         u32, number of the first record in the stream
+//  16  tag                  u8 length, UTF-8
+//      battery              f32 V, u8 %, u8 source (0 battery, 1 charging, 2 usb), u16 runtime min
+//      records              u8 count, then per record u32 clock, u64 time, f32 current, f32 voltage, f32 power
+//      or event             u8 length, UTF-8
+//
+// A gap in the sequence is a lost datagram, the record numbers tell how many records it held.
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use log::*;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::{SocketAddr, UdpSocket};
+use std::{thread, time::Duration, sync::Arc, sync::Mutex};
+use anyhow::Result;
+
+use crate::currentlogs::CurrentLog;
+use crate::battery::{BatteryState, PowerSource};
+use crate::hal::Uplink;
+use crate::provision::DEFAULT_NAME;
+use crate::transfer::Transfer;
+
+pub const DEFAULT_PORT: u16 = 3002;
+pub const VERSION: u8 = 1;
+pub const KIND_RECORDS: u8 = 1;
+pub const KIND_EVENT: u8 = 2;
+const DATAGRAM_RECORDS: usize = 48;     // 1152 bytes of records, below the MTU with the header
+const SEND_POLL_MS: u64 = 10;
+const RETRY_MS: u64 = 1000;
+
+struct StreamData {
+    datagram: Vec<u8>,
+    txreq: bool,
+    pending: usize,     // Records in the datagram
+    acked: usize,       // Records sent, not yet taken by the logger
+}
+
+pub struct UdpUplink {
+    data: Arc<Mutex<StreamData>>,
+    server: String,
+    tag: String,
+    stream: u32,
+    sequence: u32,
+    record: u32,
+}
+
+impl UdpUplink {
+    /// `server` is host:port of the collector, the port defaults to 3002.
+    pub fn new(server: String) -> Self {
+        let server = if server.contains(':') { server } else { format!("{}:{}", server, DEFAULT_PORT) };
+        UdpUplink { data: Arc::new(Mutex::new(
+            StreamData { datagram: Vec::new(), txreq: false, pending: 0, acked: 0 })),
+            server: server,
+            tag: DEFAULT_NAME.to_string(),
+            stream: stream_id(),
+            sequence: 0,
+            record: 0 }
+    }
+
+    /// Sets the device name sent as the tag of every datagram.
+    pub fn set_tag(&mut self, tag: &str) {
+        self.tag = tag.to_string();
+    }
+
+    pub fn start(&mut self) -> Result<()>
+    {
+        let data = self.data.clone();
+        let server = self.server.clone();
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        info!("UDP stream {:08x} to {}", self.stream, server);
+        let _th = thread::spawn(move || {
+            info!("Start UDP stream thread.");
+            // Resolved again after a failure, so that a collector moved to another address is found.
+            let mut addr: Option<SocketAddr> = None;
+            loop {
+                thread::sleep(Duration::from_millis(SEND_POLL_MS));
+                let lck = data.lock().unwrap();
+                if lck.txreq == false {
+                    continue;
+                }
+                drop(lck);
+                if addr.is_none() {
+                    addr = Transfer::resolve(&server);
+                    if addr.is_none() {
+                        thread::sleep(Duration::from_millis(RETRY_MS));
+                        continue;
+                    }
+                }
+                let mut lck = data.lock().unwrap();
+                match socket.send_to(&lck.datagram, addr.unwrap()) {
+                    Ok(_) => {
+                        // Nothing comes back, the records are gone once they are sent.
+                        lck.acked += lck.pending;
+                        lck.pending = 0;
+                        lck.txreq = false;
+                    },
+                    Err(e) => {
+                        drop(lck);
+                        info!("UDP {}: {}", server, e);
+                        addr = None;
+                        thread::sleep(Duration::from_millis(RETRY_MS));
+                    },
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // The header up to the battery, and counts the datagram.
+    fn header(&mut self, kind: u8, battery: &BatteryState) -> Vec<u8>
+    {
+        let mut datagram = Vec::with_capacity(64 + DATAGRAM_RECORDS * 24);
+        datagram.extend_from_slice(b"CL");
+        datagram.push(VERSION);
+        datagram.push(kind);
+        datagram.extend_from_slice(&self.stream.to_be_bytes());
+        datagram.extend_from_slice(&self.sequence.to_be_bytes());
+        datagram.extend_from_slice(&self.record.to_be_bytes());
+        put_str(&mut datagram, &self.tag);
+        put_battery(&mut datagram, battery);
+        self.sequence = self.sequence.wrapping_add(1);
+        datagram
+    }
+}
+
+impl Uplink for UdpUplink {
+    fn set_transfer_data(&mut self, data: &[CurrentLog]) -> usize
+    {
+        if data.is_empty() {
+            return 0;
+        }
+        let lck = self.data.lock().unwrap();
+        if lck.txreq || lck.acked > 0 {
+            return 0;
+        }
+        drop(lck);
+        let records = &data[..data.len().min(DATAGRAM_RECORDS)];
+        let mut datagram = self.header(KIND_RECORDS, &records[records.len() - 1].battery);
+        put_records(&mut datagram, records);
+        self.record = self.record.wrapping_add(records.len() as u32);
+        let mut lck = self.data.lock().unwrap();
+        lck.datagram = datagram;
+        lck.pending = records.len();
+        lck.txreq = true;
+        records.len()
+    }
+
+    fn take_acknowledged(&mut self) -> usize
+    {
+        let mut lck = self.data.lock().unwrap();
+        let acked = lck.acked;
+        lck.acked = 0;
+        acked
+    }
+
+    fn send_event(&mut self, event: &str, battery: &BatteryState) -> bool
+    {
+        if self.data.lock().unwrap().txreq {
+            return false;
+        }
+        let mut datagram = self.header(KIND_EVENT, battery);
+        put_str(&mut datagram, event);
+        let mut lck = self.data.lock().unwrap();
+        lck.datagram = datagram;
+        lck.pending = 0;
+        lck.txreq = true;
+        true
+    }
+
+    fn is_idle(&self) -> bool
+    {
+        self.data.lock().unwrap().txreq == false
+    }
+}
+
+/// A random number telling the streams of the same device apart, e.g. after a reboot.
+pub fn stream_id() -> u32 {
+    RandomState::new().build_hasher().finish() as u32
+}
+
+/// A string of up to 255 bytes after its length, cut at a character boundary.
+pub fn put_str(out: &mut Vec<u8>, s: &str) {
+    let mut len = s.len().min(255);
+    while s.is_char_boundary(len) == false {
+        len -= 1;
+    }
+    out.push(len as u8);
+    out.extend_from_slice(&s.as_bytes()[..len]);
+}
+
+/// The battery in 8 bytes: f32 V, u8 %, u8 source, u16 runtime.
+pub fn put_battery(out: &mut Vec<u8>, battery: &BatteryState) {
+    out.extend_from_slice(&battery.voltage.to_be_bytes());
+    out.push(battery.percent);
+    out.push(match battery.source {
+        PowerSource::Battery => 0,
+        PowerSource::Charging => 1,
+        PowerSource::Usb => 2,
+    });
+    out.extend_from_slice(&battery.runtime.to_be_bytes());
+}
+
+/// The count of up to 255 records, then 24 bytes each.
+pub fn put_records(out: &mut Vec<u8>, records: &[CurrentLog]) {
+    out.push(records.len() as u8);
+    for it in records {
+        out.extend_from_slice(&it.clock.to_be_bytes());
+        out.extend_from_slice(&it.time.to_be_bytes());
+        out.extend_from_slice(&it.current.to_be_bytes());
+        out.extend_from_slice(&it.voltage.to_be_bytes());
+        out.extend_from_slice(&it.power.to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn battery() -> BatteryState {
+        BatteryState { voltage: 3.9, percent: 72, source: PowerSource::Charging, runtime: 300 }
+    }
+
+    fn records(count: u32) -> Vec<CurrentLog> {
+        (0..count).map(|n| CurrentLog { clock: 100 + n * 5, time: 1_700_000_000_000 + n as u64, current: 0.25,
+                                        voltage: 3.3, power: 0.825, battery: battery() }).collect()
+    }
+
+    // The datagram handed to the sender thread, as if it had gone out.
+    fn sent(uplink: &mut UdpUplink) -> Vec<u8> {
+        let mut lck = uplink.data.lock().unwrap();
+        assert!(lck.txreq);
+        lck.txreq = false;
+        lck.acked = 0;
+        std::mem::take(&mut lck.datagram)
+    }
+
+    fn u32_at(d: &[u8], pos: usize) -> u32 {
+        u32::from_be_bytes(d[pos..pos + 4].try_into().unwrap())
+    }
+
+    fn f32_at(d: &[u8], pos: usize) -> f32 {
+        f32::from_be_bytes(d[pos..pos + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn records_datagram() {
+        let mut uplink = UdpUplink::new("127.0.0.1".to_string());
+        assert_eq!(uplink.server, "127.0.0.1:3002");
+        uplink.set_tag("ch1");
+        assert_eq!(uplink.set_transfer_data(&records(2)), 2);
+        let d = sent(&mut uplink);
+        assert_eq!(d[0..4], [b'C', b'L', VERSION, KIND_RECORDS]);
+        assert_eq!(u32_at(&d, 4), uplink.stream);
+        assert_eq!(u32_at(&d, 8), 0);
+        assert_eq!(u32_at(&d, 12), 0);
+        assert_eq!(d[16..20], [3, b'c', b'h', b'1']);
+        assert_eq!(f32_at(&d, 20), 3.9);
+        assert_eq!(d[24..28], [72, 1, 0x01, 0x2C]);
+        assert_eq!(d[28], 2);
+        assert_eq!(d.len(), 29 + 2 * 24);
+        // The second record, as src/server/main.js reads it.
+        let r = 29 + 24;
+        assert_eq!(u32_at(&d, r), 105);
+        assert_eq!(u64::from_be_bytes(d[r + 4..r + 12].try_into().unwrap()), 1_700_000_000_001);
+        assert_eq!(f32_at(&d, r + 12), 0.25);
+        assert_eq!(f32_at(&d, r + 16), 3.3);
+        assert_eq!(f32_at(&d, r + 20), 0.825);
+
+        // The next datagram goes on with the sequence and the record number, at most 48 records.
+        assert_eq!(uplink.set_transfer_data(&records(60)), DATAGRAM_RECORDS);
+        let d = sent(&mut uplink);
+        assert_eq!(u32_at(&d, 8), 1);
+        assert_eq!(u32_at(&d, 12), 2);
+        assert_eq!(d[28] as usize, DATAGRAM_RECORDS);
+        assert_eq!(d.len(), 29 + DATAGRAM_RECORDS * 24);
+    }
+
+    #[test]
+    fn event_datagram() {
+        let mut uplink = UdpUplink::new("collector:4000".to_string());
+        assert_eq!(uplink.server, "collector:4000");
+        assert!(uplink.send_event("start", &battery()));
+        // Nothing else while it is waiting to go out.
+        assert!(uplink.send_event("stop", &battery()) == false);
+        assert_eq!(uplink.set_transfer_data(&records(1)), 0);
+        let d = sent(&mut uplink);
+        assert_eq!(d[0..4], [b'C', b'L', VERSION, KIND_EVENT]);
+        assert_eq!(u32_at(&d, 8), 0);
+        assert_eq!(u32_at(&d, 12), 0);
+        let tag = DEFAULT_NAME.len();
+        assert_eq!(d[16] as usize, tag);
+        assert_eq!(d[17 + tag + 8], 5);
+        assert_eq!(&d[17 + tag + 9..], b"start");
+        assert!(uplink.is_idle());
+    }
+
+    #[test]
+    fn strings_cut_at_a_char_boundary() {
+        let mut out = Vec::new();
+        put_str(&mut out, "");
+        assert_eq!(out, [0]);
+
+        // 254 bytes of ASCII, then a 3 byte character that would end at byte 257.
+        let s = format!("{}\u{3042}", "a".repeat(254));
+        let mut out = Vec::new();
+        put_str(&mut out, &s);
+        assert_eq!(out[0], 254);
+        assert_eq!(&out[1..], "a".repeat(254).as_bytes());
+
+        // 2 byte characters, byte 255 is in the middle of one.
+        let s = "\u{e9}".repeat(200);
+        let mut out = Vec::new();
+        put_str(&mut out, &s);
+        assert_eq!(out[0], 254);
+        assert_eq!(&out[1..], "\u{e9}".repeat(127).as_bytes());
+    }
+}
@@ -0,0 +1,43 @@
+// HTTP server of the logger: the live view at / and its WebSocket at /ws.
+#![allow(clippy::bool_comparison)]
+
+use esp_idf_svc::http::server::{EspHttpServer, Configuration as HttpConfiguration};
+use esp_idf_sys::EspError;
+use embedded_svc::http::Method;
+use embedded_svc::io::Write;
+use embedded_svc::ws::Receiver;
+use anyhow::Result;
+use log::*;
+
+use crate::livefeed::{LiveBroadcast, LIVE_PAGE};
+
+const WS_FRAME_MAX: usize = 64;     // The page sends nothing, anything else is read and ignored
+
+/// Serves the live view while it is kept.
+pub struct WebFeed {
+    _server: EspHttpServer,
+}
+
+impl WebFeed {
+    /// Opens port 80, the WebSocket clients are added to `live`.
+    pub fn start(live: LiveBroadcast) -> Result<WebFeed> {
+        let mut server = EspHttpServer::new(&HttpConfiguration::default())?;
+        server.fn_handler("/", Method::Get, |req| {
+            req.into_ok_response()?.write_all(LIVE_PAGE.as_bytes())?;
+            Ok(())
+        })?;
+        server.ws_handler("/ws", move |ws| {
+            if ws.is_new() {
+                info!("Live feed client {}", ws.session());
+                live.add_client(Box::new(ws.create_detached_sender()?));
+            }
+            else if ws.is_closed() == false {
+                let mut buf = [0u8; WS_FRAME_MAX];
+                ws.recv(&mut buf)?;
+            }
+            Ok::<(), EspError>(())
+        })?;
+        info!("Live view on port 80");
+        Ok(WebFeed { _server: server })
+    }
+}
@@ -1,48 +1,239 @@
+#![allow(clippy::bool_comparison)]
+
 use std::time::Duration;
 use std::thread;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
 
 use esp_idf_hal::peripheral;
-use esp_idf_svc::{eventloop::EspSystemEventLoop, wifi::EspWifi};
+use esp_idf_svc::{eventloop::EspSystemEventLoop, wifi::{EspWifi, WifiDriver}};
+use esp_idf_svc::netif::{EspNetif, NetifConfiguration, NetifStack};
 
-use embedded_svc::wifi::{ClientConfiguration, Wifi, Configuration, AccessPointInfo};
+use embedded_svc::wifi::{ClientConfiguration, Wifi, Configuration};
+use embedded_svc::ipv4;
 use anyhow::bail;
 use anyhow::Result;
 use log::*;
 
-pub fn wifi_connect<'d> (
+use crate::hal::Network;
+use crate::displayctl::WifiStatus;
+use crate::wifilist::{self, WifiNetwork};
+use crate::netconfig::NetConfig;
+
+const CONNECT_TIMEOUT: u32 = 30;       // s
+const BACKOFF_MIN_MS: u64 = 1000;
+const BACKOFF_MAX_MS: u64 = 60_000;
+const SCANS_BEFORE_PORTAL: u32 = 3;
+
+// The station interface gets the hostname for DHCP, or the static address.
+fn new_wifi(
     modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
-    ssid: &'d str,
-    pass: &'d str,
-) -> Result<Box<EspWifi<'d>>> {
-  
-    let sys_event_loop = EspSystemEventLoop::take().unwrap();
-    let mut wifi = Box::new(EspWifi::new(modem, sys_event_loop.clone(), None).unwrap());
+    net: &NetConfig,
+) -> Result<EspWifi<'static>> {
+    let sys_event_loop = EspSystemEventLoop::take()?;
+    let ip_configuration = match net.static_ip {
+        Some(s) => {
+            info!("Static IP {}/{} gateway {} dns {:?}", s.ip, s.prefix, s.gateway, s.dns);
+            ipv4::ClientConfiguration::Fixed(ipv4::ClientSettings {
+                ip: s.ip,
+                subnet: ipv4::Subnet { gateway: s.gateway, mask: ipv4::Mask(s.prefix) },
+                dns: s.dns,
+                secondary_dns: None,
+            })
+        },
+        None => ipv4::ClientConfiguration::DHCP(ipv4::DHCPClientSettings {
+            hostname: Some(net.hostname.as_str().into()),
+        }),
+    };
+    let sta_netif = EspNetif::new_with_conf(&NetifConfiguration {
+        ip_configuration: ipv4::Configuration::Client(ip_configuration),
+        ..NetifConfiguration::wifi_default_client()
+    })?;
+    let driver = WifiDriver::new(modem, sys_event_loop, None)?;
+    Ok(EspWifi::wrap_all(driver, sta_netif, EspNetif::new(NetifStack::Ap)?)?)
+}
 
-    let ap_list = wifi.scan().unwrap();
-    let find_ap = ap_list.into_iter().find(|ap| ap.ssid == ssid);
-    if find_ap == None {
+/// Connects once to the best known network, for short uploads.
+pub fn wifi_connect(
+    modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
+    networks: &[WifiNetwork],
+    net: &NetConfig,
+) -> Result<Box<EspWifi<'static>>> {
+  
+    let mut wifi = Box::new(new_wifi(modem, net)?);
+    wifi.start()?;
+    let candidates = scan_candidates(&mut wifi, networks);
+    if candidates.is_empty() {
         bail!("AP not found.");
     }
-    let ap_info : AccessPointInfo = find_ap.unwrap();
-    info!("{:?}", ap_info);
+    for network in candidates {
+        if connect(&mut wifi, &network) {
+            info!("Wifi connected");
+            return Ok(wifi);
+        }
+        let _ = wifi.disconnect();
+    }
+    bail!("Wifi could not be connected.");
+}
 
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
-        ssid: ssid.into(),
-        password: pass.into(),
-        ..Default::default()
-    })).unwrap();
+// Known networks in the scan, in the order to try them.
+fn scan_candidates(wifi: &mut EspWifi<'static>, networks: &[WifiNetwork]) -> Vec<WifiNetwork> {
+    let ap_list = match wifi.scan() {
+        Ok(ap_list) => ap_list,
+        Err(e) => { info!("{:?}", e); return Vec::new(); },
+    };
+    // signal_strength carries the RSSI in dBm as u8.
+    let scan: Vec<(&str, i8)> = ap_list.iter().map(|ap| (ap.ssid.as_str(), ap.signal_strength as i8)).collect();
+    wifilist::candidates(networks, &scan).into_iter().cloned().collect()
+}
 
-    wifi.start().unwrap();
-    wifi.connect().unwrap();
-    let mut timeout = 0;
-    while !wifi.is_connected().unwrap(){
+// Connects to `network` and waits for an address.
+fn connect(wifi: &mut EspWifi<'static>, network: &WifiNetwork) -> bool {
+    info!("Connecting to {}", network.ssid);
+    let config = Configuration::Client(ClientConfiguration {
+        ssid: network.ssid.as_str().into(),
+        password: network.psk.as_str().into(),
+        ..Default::default()
+    });
+    if let Err(e) = wifi.set_configuration(&config).and_then(|_| wifi.connect()) {
+        info!("{:?}", e);
+        return false;
+    }
+    for _ in 0..CONNECT_TIMEOUT {
         thread::sleep(Duration::from_secs(1));
-        timeout += 1;
-        if timeout > 30 {
-            bail!("Wifi could not be connected.");
+        let connected = wifi.is_connected().unwrap_or(false);
+        let has_ip = wifi.sta_netif().get_ip_info().map(|info| !info.ip.is_unspecified()).unwrap_or(false);
+        if connected && has_ip {
+            return true;
         }
     }
+    info!("Wifi could not be connected to {}.", network.ssid);
+    false
+}
+
+#[derive(Clone, Copy)]
+struct WifiState {
+    status: WifiStatus,
+    rssi: Option<i8>,
+    ip: Option<Ipv4Addr>,
+    missed_scans: u32,      // Scans without a known AP before the first connection
+    connected_once: bool,
+}
+
+/// Keeps the station connected in a thread, reconnecting with backoff.
+pub struct WifiManager {
+    state: Arc<Mutex<WifiState>>,
+    auto_portal: bool,
+    net: NetConfig,
+}
+
+impl WifiManager {
+    pub fn new() -> WifiManager {
+        WifiManager { state: Arc::new(Mutex::new(
+            WifiState { status: WifiStatus::Disconnected, rssi: None, ip: None,
+                        missed_scans: 0, connected_once: false })),
+            auto_portal: true,
+            net: NetConfig::new() }
+    }
+
+    pub fn set_net_config(&mut self, net: NetConfig) {
+        self.net = net;
+    }
+
+    /// Whether the provisioning portal is offered when no known AP is found.
+    pub fn set_auto_portal(&mut self, auto_portal: bool) {
+        self.auto_portal = auto_portal;
+    }
 
-    info!("Wifi connected");
-    Ok(wifi)
-}
\ No newline at end of file
+    pub fn start(&mut self,
+        modem: impl peripheral::Peripheral<P = esp_idf_hal::modem::Modem> + 'static,
+        networks: Vec<WifiNetwork>) -> Result<()>
+    {
+        let mut wifi = new_wifi(modem, &self.net)?;
+        wifi.start()?;
+        let state = self.state.clone();
+        let _th = thread::spawn(move || {
+            info!("Start WiFi thread.");
+            Self::supervise(wifi, networks, state);
+        });
+        Ok(())
+    }
+
+    fn set_status(state: &Arc<Mutex<WifiState>>, status: WifiStatus) {
+        let mut lck = state.lock().unwrap();
+        lck.status = status;
+        if status != WifiStatus::Connected {
+            lck.rssi = None;
+            lck.ip = None;
+        }
+    }
+
+    fn supervise(mut wifi: EspWifi<'static>, networks: Vec<WifiNetwork>, state: Arc<Mutex<WifiState>>) {
+        let mut backoff = BACKOFF_MIN_MS;
+        let mut lost = false;
+        loop {
+            Self::set_status(&state, WifiStatus::Scanning);
+            let candidates = scan_candidates(&mut wifi, &networks);
+            if candidates.is_empty() {
+                info!("No known AP found.");
+                state.lock().unwrap().missed_scans += 1;
+            }
+            // Falls back to the next network when one does not connect.
+            for network in candidates {
+                Self::set_status(&state, WifiStatus::Connecting);
+                if connect(&mut wifi, &network) {
+                    let ip = wifi.sta_netif().get_ip_info().ok().map(|info| info.ip);
+                    info!("Wifi connected to {} {:?}", network.ssid, ip);
+                    {
+                        let mut lck = state.lock().unwrap();
+                        lck.ip = ip;
+                        lck.connected_once = true;
+                    }
+                    Self::set_status(&state, WifiStatus::Connected);
+                    backoff = BACKOFF_MIN_MS;
+                    while wifi.is_connected().unwrap_or(false) {
+                        state.lock().unwrap().rssi = read_rssi();
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                    info!("Wifi lost");
+                    lost = true;
+                    let _ = wifi.disconnect();
+                    break;
+                }
+                let _ = wifi.disconnect();
+            }
+            Self::set_status(&state, if lost { WifiStatus::Lost } else { WifiStatus::Disconnected });
+            thread::sleep(Duration::from_millis(backoff));
+            backoff = (backoff * 2).min(BACKOFF_MAX_MS);
+        }
+    }
+}
+
+fn read_rssi() -> Option<i8> {
+    let mut info: esp_idf_sys::wifi_ap_record_t = unsafe { std::mem::zeroed() };
+    if unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut info) } == esp_idf_sys::ESP_OK as esp_idf_sys::esp_err_t {
+        Some(info.rssi)
+    }
+    else {
+        None
+    }
+}
+
+impl Network for WifiManager {
+    fn status(&self) -> WifiStatus {
+        self.state.lock().unwrap().status
+    }
+
+    fn rssi(&self) -> Option<i8> {
+        self.state.lock().unwrap().rssi
+    }
+
+    fn ip(&self) -> Option<Ipv4Addr> {
+        self.state.lock().unwrap().ip
+    }
+
+    fn wants_provisioning(&self) -> bool {
+        let lck = self.state.lock().unwrap();
+        self.auto_portal && lck.connected_once == false && lck.missed_scans >= SCANS_BEFORE_PORTAL
+    }
+}
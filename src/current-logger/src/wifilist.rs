@@ -0,0 +1,73 @@
+// Known WiFi networks and the choice between the APs found in a scan.
+#![allow(clippy::redundant_field_names)]
+
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    pub psk: String,
+    pub priority: u8,       // Lower is tried first
+}
+
+impl WifiNetwork {
+    /// Parses "priority:ssid:password;..". The password may contain ':', no field may contain ';'.
+    pub fn parse_list(list: &str) -> Result<Vec<WifiNetwork>> {
+        let mut networks = Vec::new();
+        for entry in list.split(';').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+            let fields: Vec<&str> = entry.splitn(3, ':').collect();
+            if fields.len() < 2 || fields[1].is_empty() {
+                return Err(anyhow!("Invalid WiFi network: {}", entry));
+            }
+            let priority = fields[0].parse::<u8>().map_err(|_| anyhow!("Invalid WiFi priority: {}", entry))?;
+            networks.push(WifiNetwork { ssid: fields[1].to_string(),
+                                        psk: fields.get(2).unwrap_or(&"").to_string(),
+                                        priority: priority });
+        }
+        Ok(networks)
+    }
+}
+
+/// Known networks seen in the scan of (ssid, rssi), by priority and then by signal strength.
+pub fn candidates<'a>(known: &'a [WifiNetwork], scan: &[(&str, i8)]) -> Vec<&'a WifiNetwork> {
+    let mut found: Vec<(&WifiNetwork, i8)> = known.iter()
+        .filter_map(|n| scan.iter().filter(|ap| ap.0 == n.ssid).map(|ap| ap.1).max().map(|rssi| (n, rssi)))
+        .collect();
+    found.sort_by(|a, b| a.0.priority.cmp(&b.0.priority).then(b.1.cmp(&a.1)));
+    found.into_iter().map(|(n, _)| n).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ssids(networks: Vec<&WifiNetwork>) -> Vec<&str> {
+        networks.iter().map(|n| n.ssid.as_str()).collect()
+    }
+
+    #[test]
+    fn priority_then_signal() {
+        let known = WifiNetwork::parse_list("2:Office:pw1;1:Lab:pw2;1:Bench:pw3;0:Home:pw4;3:Guest:").unwrap();
+        // Home has the best priority but is not in range, Office is seen by two APs.
+        let scan = [("Office", -80), ("Bench", -70), ("Cafe", -30), ("Lab", -55), ("Office", -40), ("Guest", -20)];
+        assert_eq!(ssids(candidates(&known, &scan)), vec!["Lab", "Bench", "Office", "Guest"]);
+
+        // The stronger AP of the same priority goes first.
+        let scan = [("Lab", -75), ("Bench", -45)];
+        assert_eq!(ssids(candidates(&known, &scan)), vec!["Bench", "Lab"]);
+
+        assert!(candidates(&known, &[("Cafe", -30)]).is_empty());
+        assert!(candidates(&[], &scan).is_empty());
+    }
+
+    #[test]
+    fn network_list() {
+        let known = WifiNetwork::parse_list(" 0:Home:pass:word ; 5:Open ;").unwrap();
+        assert_eq!(known.len(), 2);
+        assert_eq!((known[0].ssid.as_str(), known[0].psk.as_str(), known[0].priority), ("Home", "pass:word", 0));
+        assert_eq!((known[1].ssid.as_str(), known[1].psk.as_str(), known[1].priority), ("Open", "", 5));
+        assert!(WifiNetwork::parse_list("x:Home:pw").is_err());
+        assert!(WifiNetwork::parse_list("1::pw").is_err());
+        assert!(WifiNetwork::parse_list("Home").is_err());
+    }
+}
@@ -0,0 +1,186 @@
+// Logger::step on the host mocks, run with
+// cargo test --no-default-features --features std --target x86_64-unknown-linux-gnu
+#![cfg(feature = "std")]
+#![allow(clippy::bool_comparison, clippy::redundant_field_names)]
+
+use std::sync::atomic::Ordering;
+
+use currentlogger::hal::Measurement;
+use currentlogger::hosthal::{ManualClock, ConstantSensor, FixedBattery, ScriptedButtons, MemoryDisplay, MemoryUplink,
+                             HostPower, HostNetwork, MemoryStore, MemoryLeds};
+use currentlogger::logger::{Logger, Hardware, MAX_RECORDS};
+use currentlogger::displayctl::WifiStatus;
+use currentlogger::pushswitch::{PushEvent, ButtonRole};
+use currentlogger::currentlogs::CurrentLog;
+
+struct Rig {
+    logger: Logger,
+    clock: ManualClock,
+    buttons: ScriptedButtons,
+    uplink: MemoryUplink,
+    network: HostNetwork,
+    display: MemoryDisplay,
+}
+
+impl Rig {
+    fn new(status: WifiStatus) -> Rig {
+        let clock = ManualClock::new();
+        let buttons = ScriptedButtons::new();
+        let uplink = MemoryUplink::new(64);
+        let network = HostNetwork::new(status);
+        let display = MemoryDisplay::new();
+        let hw = Hardware {
+            sensor: Box::new(ConstantSensor { value: Measurement { voltage: 5.0, current: 0.1, power: 0.5, energy: 0.0, charge: 0.0 } }),
+            clock: Box::new(clock.clone()),
+            display: Box::new(display.clone()),
+            buttons: Box::new(buttons.clone()),
+            battery: Box::new(FixedBattery { voltage: 4.0 }),
+            uplink: Box::new(uplink.clone()),
+            leds: Box::<MemoryLeds>::default(),
+            power: Box::<HostPower>::default(),
+            network: Box::new(network.clone()),
+            store: Box::<MemoryStore>::default(),
+        };
+        Rig { logger: Logger::new(hw), clock: clock, buttons: buttons, uplink: uplink, network: network,
+              display: display }
+    }
+
+    // Steps every ms for `ms` ms, as Logger::run does.
+    fn run(&mut self, ms: u64) {
+        for _ in 0..ms {
+            self.clock.advance(1);
+            self.logger.step().unwrap();
+        }
+    }
+
+    fn press_start(&mut self) {
+        self.buttons.push(PushEvent::ShortPress(ButtonRole::StartStop));
+        self.run(1);
+    }
+
+    fn records(&self) -> Vec<CurrentLog> {
+        self.logger.get_records().get_all_data().clone()
+    }
+}
+
+#[test]
+fn samples_follow_the_interval() {
+    let mut rig = Rig::new(WifiStatus::Disconnected);
+    assert!(rig.logger.set_interval(7) == false);
+    assert!(rig.logger.set_interval(100));
+    assert_eq!(rig.logger.get_interval(), 100);
+    rig.run(10);
+    assert_eq!(rig.logger.get_records().get_size(), 0);
+    rig.press_start();
+    assert!(rig.logger.is_logging());
+    rig.run(1000);
+    let records = rig.records();
+    // The first sample is the one already due, from there they are one ms short of the interval apart,
+    // see set_interval.
+    assert_eq!(records.len(), 11);
+    assert!(records[0].clock < 99);
+    for pair in records[1..].windows(2) {
+        assert_eq!(pair[1].clock - pair[0].clock, 99);
+    }
+    assert_eq!(records[0].voltage, 5.0);
+    assert_eq!(records[0].current, 0.1);
+}
+
+#[test]
+fn interval_button_cycles() {
+    let mut rig = Rig::new(WifiStatus::Disconnected);
+    let mut intervals = Vec::new();
+    for _ in 0..6 {
+        rig.buttons.push(PushEvent::ShortPress(ButtonRole::Interval));
+        rig.run(1);
+        intervals.push(rig.logger.get_interval());
+    }
+    assert_eq!(intervals, vec![10, 50, 100, 500, 1000, 5]);
+}
+
+#[test]
+fn records_leave_the_buffer_when_acknowledged() {
+    let mut rig = Rig::new(WifiStatus::Connected);
+    rig.uplink.hold.store(true, Ordering::Relaxed);
+    rig.press_start();
+    rig.run(400);
+    // The first record is in flight, the others wait behind it.
+    let logged = rig.logger.get_records().get_size();
+    assert_eq!(logged, 101);
+    assert_eq!(rig.uplink.sent.lock().unwrap().len(), 1);
+
+    rig.press_start();
+    assert!(rig.logger.is_logging() == false);
+    let logged: Vec<u32> = rig.records().iter().map(|r| r.clock).collect();
+    rig.uplink.hold.store(false, Ordering::Relaxed);
+    rig.run(100);
+    assert_eq!(rig.logger.get_records().get_size(), 0);
+    // Every record sent once and in order.
+    let sent: Vec<u32> = rig.uplink.sent.lock().unwrap().iter().map(|r| r.clock).collect();
+    assert_eq!(sent, logged);
+}
+
+#[test]
+fn restart_keeps_the_records_in_flight() {
+    let mut rig = Rig::new(WifiStatus::Connected);
+    rig.uplink.hold.store(true, Ordering::Relaxed);
+    rig.press_start();
+    rig.run(100);
+    rig.press_start();
+    assert!(rig.logger.get_records().get_size() > 1);
+    // A new start drops the unsent records, but not the one the server may have taken.
+    let first = rig.records()[0].clock;
+    rig.press_start();
+    assert_eq!(rig.logger.get_records().get_size(), 1);
+    assert_eq!(rig.records()[0].clock, first);
+    rig.uplink.hold.store(false, Ordering::Relaxed);
+    rig.run(20);
+    assert_eq!(rig.uplink.sent.lock().unwrap()[0].clock, first);
+}
+
+#[test]
+fn full_buffer_stops_logging() {
+    let mut rig = Rig::new(WifiStatus::Disconnected);
+    rig.press_start();
+    rig.run(MAX_RECORDS as u64 * 4 + 100);
+    assert!(rig.logger.is_logging() == false);
+    assert_eq!(rig.logger.get_records().get_size(), MAX_RECORDS);
+
+    // Sent once the network is back.
+    rig.network.set_status(WifiStatus::Connected);
+    rig.run(MAX_RECORDS as u64 / 64 * 8 + 100);
+    assert_eq!(rig.logger.get_records().get_size(), 0);
+    assert_eq!(rig.uplink.sent.lock().unwrap().len(), MAX_RECORDS);
+}
+
+#[test]
+fn press_that_wakes_the_display_is_used_up() {
+    let mut rig = Rig::new(WifiStatus::Disconnected);
+    rig.display.state.lock().unwrap().asleep = true;
+    rig.press_start();
+    assert!(rig.logger.is_logging() == false);
+    rig.press_start();
+    assert!(rig.logger.is_logging());
+}
+
+#[test]
+fn duty_interval_is_a_minute_or_longer() {
+    let mut rig = Rig::new(WifiStatus::Disconnected);
+    assert!(rig.logger.set_duty_interval(1000).is_err());
+    assert!(rig.logger.set_duty_interval(59_999).is_err());
+    assert!(rig.logger.set_interval(1000));
+    // Not offered after 1 sec.
+    rig.buttons.push(PushEvent::ShortPress(ButtonRole::Interval));
+    rig.run(1);
+    assert_eq!(rig.logger.get_interval(), 5);
+
+    assert!(rig.logger.set_duty_interval(60_000).is_ok());
+    assert!(rig.logger.set_interval(1000));
+    rig.buttons.push(PushEvent::ShortPress(ButtonRole::Interval));
+    rig.run(1);
+    assert_eq!(rig.logger.get_interval(), 60_000);
+    // Starting with it asks for deep sleep logging.
+    rig.press_start();
+    assert!(rig.logger.is_duty_requested());
+    assert!(rig.logger.set_duty_interval(0).is_ok());
+}
@@ -0,0 +1,112 @@
+// The MQTT uplink against the stand-in broker on a free port.
+#![cfg(feature = "std")]
+#![allow(clippy::bool_comparison)]
+
+use std::net::SocketAddr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use currentlogger::currentlogs::CurrentLog;
+use currentlogger::hal::{Connector, RemoteCommand, Uplink};
+use currentlogger::httpclient::TcpConnector;
+use currentlogger::mqtt::{MqttTopics, MqttUplink};
+use currentlogger::mqttbroker::MqttBroker;
+use currentlogger::mqttclient::{Incoming, MqttClient, MqttOptions, Publish};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn records(count: u32) -> Vec<CurrentLog> {
+    (0..count).map(|clock| {
+        let mut data = CurrentLog::default();
+        data.clock = clock;
+        data.voltage = 3.3;
+        data.current = 0.01;
+        data
+    }).collect()
+}
+
+// A second client that sees what the uplink publishes.
+fn watch(addr: SocketAddr, filter: &str) -> MqttClient {
+    let options = MqttOptions { client_id: "watcher".to_string(), keep_alive: 0, username: "".to_string(),
+                                password: "".to_string(), will: None };
+    let conn = TcpConnector.connect("127.0.0.1", addr, TIMEOUT).unwrap();
+    let mut client = MqttClient::connect(conn, &options, TIMEOUT).unwrap();
+    client.subscribe(filter, 0).unwrap();
+    client
+}
+
+// The next message on `topic`.
+fn next_on(client: &mut MqttClient, topic: &str) -> Publish {
+    let start = Instant::now();
+    while start.elapsed() < TIMEOUT {
+        if let Some(Incoming::Publish(message)) = client.poll().unwrap() {
+            if message.topic == topic {
+                return message;
+            }
+        }
+    }
+    panic!("Nothing on {}", topic);
+}
+
+fn wait_for(mut what: impl FnMut() -> bool) -> bool {
+    let start = Instant::now();
+    while start.elapsed() < TIMEOUT {
+        if what() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
+
+#[test]
+fn uplink_through_the_broker() {
+    let broker = MqttBroker::bind("127.0.0.1:0").unwrap();
+    broker.start().unwrap();
+    let addr = broker.local_addr().unwrap();
+    let topics = MqttTopics::new("lab/ch1");
+    let mut first = watch(addr, "lab/ch1/status");
+
+    let mut uplink = MqttUplink::new(format!("mqtt://{}", addr));
+    uplink.set_tag("ch1");
+    uplink.set_topics(topics.clone());
+    uplink.start().unwrap();
+    assert_eq!(next_on(&mut first, &topics.status).payload, b"online");
+
+    // The status stays retained for the clients that come later.
+    let mut watcher = watch(addr, "lab/ch1/#");
+    let status = next_on(&mut watcher, &topics.status);
+    assert_eq!(status.payload, b"online");
+    assert!(status.retain);
+
+    // The records stay in flight until the broker acknowledges them.
+    broker.set_hold_acks(true);
+    assert_eq!(uplink.set_transfer_data(&records(3)), 3);
+    let samples = next_on(&mut watcher, &topics.samples);
+    assert_eq!(String::from_utf8_lossy(&samples.payload).matches("\"tag\": \"ch1\"").count(), 3);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(uplink.take_acknowledged(), 0);
+    assert!(uplink.is_idle() == false);
+    assert_eq!(uplink.set_transfer_data(&records(3)), 0);
+
+    // A lost connection publishes the will, the uplink comes back and sends the records again.
+    broker.set_hold_acks(false);
+    assert!(broker.drop_client("currentlogger-ch1"));
+    let will = next_on(&mut watcher, &topics.status);
+    assert_eq!(will.payload, b"offline");
+    assert_eq!(next_on(&mut watcher, &topics.status).payload, b"online");
+    assert_eq!(next_on(&mut watcher, &topics.samples).payload, samples.payload);
+    let mut acked = 0;
+    assert!(wait_for(|| { acked += uplink.take_acknowledged(); acked == 3 }));
+    assert!(uplink.is_idle());
+
+    // Commands come back on cmd.
+    broker.publish(&Publish::new(&topics.command, b"interval=250", 0, false));
+    broker.publish(&Publish::new(&topics.command, b"stop", 0, false));
+    let mut commands = Vec::new();
+    assert!(wait_for(|| {
+        commands.extend(uplink.take_command());
+        commands.len() == 2
+    }));
+    assert_eq!(commands, vec![RemoteCommand::Interval(250), RemoteCommand::Stop]);
+}
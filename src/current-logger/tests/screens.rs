@@ -0,0 +1,52 @@
+// Each screen state against its reference image in tests/snapshots.
+// UPDATE_SNAPSHOTS=1 writes the images of the current code instead, check them before committing.
+#![cfg(feature = "std")]
+#![allow(clippy::bool_comparison)]
+
+use std::{fs, path::PathBuf};
+
+use currentlogger::screens;
+
+fn snapshots() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("snapshots")
+}
+
+#[test]
+fn screens_match_the_snapshots() {
+    let update = std::env::var("UPDATE_SNAPSHOTS").is_ok();
+    let mut failed = Vec::new();
+    for (name, dp) in screens::states() {
+        let path = snapshots().join(format!("{}.bmp", name));
+        let frame = screens::render(&dp).to_bmp();
+        if update {
+            fs::create_dir_all(snapshots()).unwrap();
+            fs::write(&path, &frame).unwrap();
+            continue;
+        }
+        let expected = fs::read(&path).unwrap_or_default();
+        if expected != frame {
+            // The differing pixels, after the 54 byte header. The frame is kept next to the reference.
+            let pixels = if expected.len() == frame.len() {
+                frame[54..].chunks(3).zip(expected[54..].chunks(3)).filter(|(a, b)| a != b).count()
+            }
+            else {
+                frame.len() / 3
+            };
+            let actual = path.with_extension("actual.bmp");
+            fs::write(&actual, &frame).unwrap();
+            failed.push(format!("{}: {} pixels differ, see {}", name, pixels, actual.display()));
+        }
+    }
+    assert!(failed.is_empty(), "{}", failed.join("\n"));
+}
+
+#[test]
+fn every_snapshot_has_a_state() {
+    let names: Vec<String> = screens::states().into_iter().map(|(name, _)| format!("{}.bmp", name)).collect();
+    for entry in fs::read_dir(snapshots()).unwrap() {
+        let file = entry.unwrap().file_name().into_string().unwrap();
+        if file.ends_with(".actual.bmp") == false {
+            assert!(names.contains(&file), "{} is not drawn any more", file);
+        }
+    }
+}
@@ -39,6 +39,15 @@ const respondPost = (req, res) => {
             let json = JSON.parse(posted)
             // console.log(json)
             for (const it of json) {
+                if (it.event !== undefined) {
+                    console.log("event: ", it.event)
+                    writeClient.writePoint(new Point(it.measurement)
+                        .tag('tag', it.tag)
+                        .stringField('event', it.event)
+                        .floatField('bat', it.bat)
+                        .intField('bat_level', it.bat_level))
+                    continue
+                }
                 if (timestamp > it.timestamp) {
                     diff_start_time = it.timestamp
                     start_time = Date.now()
@@ -51,7 +60,17 @@ const respondPost = (req, res) => {
                     .floatField('voltage', it.voltage)
                     .floatField('power', it.power)
                     .floatField('bat', it.bat)
-                    .timestamp(new Date(start_time + it.timestamp - diff_start_time))
+                if (it.bat_level !== undefined) {
+                    point.intField('bat_level', it.bat_level)
+                        .intField('bat_runtime', it.bat_runtime)
+                        .stringField('bat_source', it.bat_source)
+                }
+                if (it.time !== undefined) {
+                    // Wall clock of the logger, set by SNTP
+                    point.timestamp(new Date(it.time))
+                } else {
+                    point.timestamp(new Date(start_time + it.timestamp - diff_start_time))
+                }
 
                 writeClient.writePoint(point)
                 i = i + 1
@@ -60,6 +79,8 @@ const respondPost = (req, res) => {
             writeClient.flush()
             res.end(JSON.stringify({ DATA: i }))
         } catch (err) {
+            // The logger keeps the records and sends them again.
+            res.statusCode = 500
             res.end(JSON.stringify({ message: 'server error' }))
         }
     })
@@ -80,3 +101,212 @@ const server = http.createServer((req, res) => {
 server.listen(port, () => {
     console.log('Server running')
 })
+
+// Datagrams of uplink = "udp", layout in src/current-logger/src/udpstream.rs
+const dgram = require('dgram')
+const udp_port = 3002
+const sources = {}     // Next sequence and record number by tag and stream
+const power_sources = ['battery', 'charging', 'usb']
+const measurement = 'currentlogger'
+
+const readString = (buf, pos) => {
+    let len = buf.readUInt8(pos)
+    return [buf.toString('utf8', pos + 1, pos + 1 + len), pos + 1 + len]
+}
+
+const readBattery = (buf, pos) => {
+    return [{ bat: buf.readFloatBE(pos),
+              bat_level: buf.readUInt8(pos + 4),
+              bat_source: power_sources[buf.readUInt8(pos + 5)],
+              bat_runtime: buf.readUInt16BE(pos + 6) }, pos + 8]
+}
+
+const writeEvent = (tag, battery, event) => {
+    console.log("event: ", event)
+    writeClient.writePoint(new Point(measurement)
+        .tag('tag', tag)
+        .stringField('event', event)
+        .floatField('bat', battery.bat)
+        .intField('bat_level', battery.bat_level))
+}
+
+// Writes the records at pos and returns their count
+const writeRecords = (tag, battery, buf, pos) => {
+    let count = buf.readUInt8(pos)
+    pos += 1
+    for (let n = 0; n < count && pos + 24 <= buf.length; n++, pos += 24) {
+        let clock = buf.readUInt32BE(pos)
+        let time = Number(buf.readBigUInt64BE(pos + 4))
+        if (timestamp > clock) {
+            diff_start_time = clock
+            start_time = Date.now()
+            console.log("logging start time: ", start_time)
+        }
+        timestamp = clock
+        writeClient.writePoint(new Point(measurement)
+            .tag('tag', tag)
+            .floatField('current', buf.readFloatBE(pos + 12))
+            .floatField('voltage', buf.readFloatBE(pos + 16))
+            .floatField('power', buf.readFloatBE(pos + 20))
+            .floatField('bat', battery.bat)
+            .intField('bat_level', battery.bat_level)
+            .intField('bat_runtime', battery.bat_runtime)
+            .stringField('bat_source', battery.bat_source)
+            .timestamp(new Date(time >= 1600000000000 ? time : start_time + clock - diff_start_time)))
+        i = i + 1
+    }
+    return count
+}
+
+const respondDatagram = (buf) => {
+    if (buf.length < 16 || buf.toString('latin1', 0, 2) != 'CL' || buf.readUInt8(2) != 1) {
+        console.log("unknown datagram")
+        return
+    }
+    let kind = buf.readUInt8(3)
+    let stream = buf.readUInt32BE(4)
+    let seq = buf.readUInt32BE(8)
+    let first = buf.readUInt32BE(12)
+    let [tag, pos] = readString(buf, 16)
+    let battery
+    [battery, pos] = readBattery(buf, pos)
+
+    let key = tag + '/' + stream
+    let source = sources[key]
+    if (source === undefined) {
+        console.log("stream start: ", tag, stream.toString(16))
+    } else if (seq != source.seq) {
+        // The first record tells how many were in the lost datagrams
+        let lost = (seq - source.seq) >>> 0
+        if (lost < 0x80000000) {
+            let lost_records = (first - source.record) >>> 0
+            console.log("gap: ", tag, lost, "datagrams", lost_records, "records")
+            writeClient.writePoint(new Point(measurement)
+                .tag('tag', tag)
+                .intField('lost_datagrams', lost)
+                .intField('lost_records', lost_records))
+        } else {
+            console.log("late datagram: ", tag, seq)
+        }
+    }
+    if (source === undefined || ((seq - source.seq) >>> 0) < 0x80000000) {
+        sources[key] = { seq: (seq + 1) >>> 0, record: first }
+    }
+
+    if (kind == 2) {
+        writeEvent(tag, battery, readString(buf, pos)[0])
+        writeClient.flush()
+        return
+    }
+    let count = writeRecords(tag, battery, buf, pos)
+    if (sources[key].seq == ((seq + 1) >>> 0)) {
+        sources[key].record = (first + count) >>> 0
+    }
+    writeClient.flush()
+}
+
+const udp = dgram.createSocket('udp4')
+udp.on('message', respondDatagram)
+udp.bind(udp_port, () => {
+    console.log('UDP stream on port', udp_port)
+})
+
+// Frames of uplink = "tcp", layout in src/current-logger/src/tcpstream.rs
+const net = require('net')
+const tcp_port = 3003
+const HELLO = 1, SESSION = 2, BATCH = 3, ACK = 4, HEARTBEAT = 5, EVENT = 6
+const streams = {}     // Last sequence taken by tag and stream, a frame sent again is only acknowledged
+
+const frame = (kind, body) => {
+    let header = Buffer.alloc(6)
+    header.writeUInt32BE(2 + body.length, 0)
+    header.writeUInt8(1, 4)
+    header.writeUInt8(kind, 5)
+    return Buffer.concat([header, body])
+}
+
+const respondFrame = (socket, conn, kind, body) => {
+    if (kind == HELLO) {
+        conn.stream = body.readUInt32BE(0)
+        conn.tag = readString(body, 4)[0]
+        conn.key = conn.tag + '/' + conn.stream
+        console.log("stream hello: ", conn.tag, conn.stream.toString(16))
+        socket.write(frame(HELLO, Buffer.alloc(0)))
+        return
+    }
+    if (kind == HEARTBEAT) {
+        socket.write(frame(HEARTBEAT, Buffer.alloc(0)))
+        return
+    }
+    if (conn.key === undefined) {
+        throw new Error('no hello')
+    }
+    let seq = body.readUInt32BE(0)
+    let ack = Buffer.alloc(4)
+    ack.writeUInt32BE(seq, 0)
+    if (streams[conn.key] !== undefined && seq == streams[conn.key]) {
+        console.log("sent again: ", conn.tag, seq)
+        socket.write(frame(ACK, ack))
+        return
+    }
+    let pos = 4
+    if (kind == SESSION) {
+        let logging = body.readUInt8(pos) != 0
+        let interval = body.readUInt32BE(pos + 1)
+        let reason = readString(body, pos + 5)[0]
+        console.log("session: ", conn.tag, logging ? "start" : "stop", interval, reason)
+        writeClient.writePoint(new Point(measurement)
+            .tag('tag', conn.tag)
+            .booleanField('logging', logging)
+            .intField('interval', interval)
+            .stringField('reason', reason))
+    } else if (kind == BATCH || kind == EVENT) {
+        let battery
+        [battery, pos] = readBattery(body, pos)
+        if (kind == EVENT) {
+            writeEvent(conn.tag, battery, readString(body, pos)[0])
+        } else {
+            writeRecords(conn.tag, battery, body, pos)
+        }
+    }
+    writeClient.flush()
+    streams[conn.key] = seq
+    socket.write(frame(ACK, ack))
+}
+
+const tcp = net.createServer((socket) => {
+    let conn = {}
+    let input = Buffer.alloc(0)
+    socket.setNoDelay(true)
+    socket.on('data', (chunk) => {
+        input = Buffer.concat([input, chunk])
+        try {
+            while (input.length >= 4) {
+                let len = input.readUInt32BE(0)
+                if (len < 2 || len > 16384) {
+                    throw new Error('invalid frame length ' + len)
+                }
+                if (input.length < 4 + len) {
+                    break
+                }
+                if (input.readUInt8(4) != 1) {
+                    throw new Error('unsupported version ' + input.readUInt8(4))
+                }
+                respondFrame(socket, conn, input.readUInt8(5), input.subarray(6, 4 + len))
+                input = input.subarray(4 + len)
+            }
+        } catch (err) {
+            // The logger connects again and sends the frame that was not acknowledged
+            console.log("stream error: ", err.message)
+            socket.destroy()
+        }
+    })
+    socket.on('error', () => {})
+    socket.on('close', () => {
+        console.log("stream closed: ", conn.tag)
+    })
+})
+
+tcp.listen(tcp_port, () => {
+    console.log('TCP stream on port', tcp_port)
+})